@@ -0,0 +1,54 @@
+//! Compares dispatching from predecoded instructions against decoding bytes every step.
+//!
+//! Run with `cargo bench --bench dispatch`.
+
+use std::time::Duration;
+
+use esper::vm::VM;
+
+const REPETITIONS: usize = 5;
+
+// Counts $0 up to 3_000_000, two instructions per iteration
+const COUNTING_LOOP: [u8; 22] = [
+    1, 1, 3, 232, // LOAD $1 #1000
+    1, 3, 11, 184, // LOAD $3 #3000
+    4, 1, 3, 1, // MULTIPLY $1 $3 $1
+    1, 2, 0, 16, // LOAD $2 #16
+    31, 0, // INCREMENT $0
+    43, 0, 1, 2, // JUMPIFLESS $0 $1 $2
+];
+
+/// Best wall-clock time over `REPETITIONS` runs, and the instructions each run executed.
+fn measure(predecode: bool) -> (Duration, u64) {
+    let mut best = Duration::MAX;
+    let mut instructions = 0;
+    for _ in 0..REPETITIONS {
+        let mut vm = VM::new();
+        vm.predecode = predecode;
+        vm.program = COUNTING_LOOP.to_vec();
+        vm.run().expect("benchmark program failed");
+        best = best.min(vm.run_time());
+        instructions = vm.instructions_executed();
+    }
+    (best, instructions)
+}
+
+fn main() {
+    let (bytes_time, instructions) = measure(false);
+    let (predecoded_time, _) = measure(true);
+    for (name, time) in [
+        ("byte decoding", bytes_time),
+        ("predecoded", predecoded_time),
+    ] {
+        println!(
+            "{:<14} {:>10.2?}  {:>6.1} M instructions/s",
+            name,
+            time,
+            instructions as f64 / time.as_secs_f64() / 1e6
+        );
+    }
+    println!(
+        "speedup        {:.2}x",
+        bytes_time.as_secs_f64() / predecoded_time.as_secs_f64()
+    );
+}
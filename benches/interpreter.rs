@@ -0,0 +1,217 @@
+//! Throughput of `VM::run` on representative programs and of `program()` on a large source.
+//!
+//! Run with `cargo bench --bench interpreter`. Pass `-- --save-baseline` to record the results
+//! in `target/esper-bench-baseline.txt`; later runs print how they compare with that file. Timings
+//! vary from run to run, so only with `-- --fail-on-regression` do they exit with an error if any
+//! workload got more than `REGRESSION_THRESHOLD` slower.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use esper::assembler::program_parsers::program;
+use esper::vm::VM;
+
+const REPETITIONS: usize = 10;
+const REGRESSION_THRESHOLD: f64 = 0.25;
+
+const ARITHMETIC: &str = "
+load $1 #1000
+load $3 #1000
+multiply $1 $3 $1
+load $2 #16
+addimmediate $4 $4 #3
+multiply $4 $4 $5
+subtract $5 $4 $6
+increment $0
+jumpifless $0 $1 $2
+";
+
+const FIBONACCI: &str = "
+load $1 #1000
+load $3 #1000
+multiply $1 $3 $1
+load $4 #0
+load $5 #1
+load $2 #24
+add $4 $5 $6
+move $5 $4
+move $6 $5
+increment $0
+jumpifless $0 $1 $2
+";
+
+// Marks composites below 60000 in the heap; $20-$23 hold the end, next, inner and outer labels
+const SIEVE: &str = "
+load $1 #60000
+load $20 #62
+load $21 #58
+load $22 #45
+load $23 #30
+allocate $1
+load $9 #1
+load $0 #2
+multiply $0 $0 $3
+jumpifgreater $3 $1 $20
+loadbyte $0 $4
+jumpifequal $4 $9 $21
+jumpifgreaterequal $3 $1 $21
+storebyte $9 $3
+add $3 $0 $3
+jump $22
+increment $0
+jump $23
+";
+
+// Classifies 0..1000000 by remainder mod 3 with flag-based relative branches
+const BRANCHING: &str = "
+load $1 #1000
+load $3 #1000
+multiply $1 $3 $1
+load $7 #3
+load $11 #4
+load $14 #2
+load $15 #1
+load $2 #32
+divide $0 $7 $8
+getremainder $8
+equal $8 $10
+jumpforwardifnot $11
+increment $12
+jumpforward $14
+increment $13
+greater $8 $15
+jumpforwardifnot $14
+increment $16
+increment $0
+jumpifless $0 $1 $2
+";
+
+struct Measurement {
+    name: &'static str,
+    unit: &'static str,
+    per_second: f64,
+}
+
+fn assemble(source: &str) -> Vec<u8> {
+    let (rest, program) = program(source).expect("benchmark program does not assemble");
+    assert!(rest.trim().is_empty(), "unparsed source: {:?}", rest);
+    program
+        .to_bytes()
+        .expect("benchmark program does not assemble")
+}
+
+/// Runs `source` `REPETITIONS` times, checks the final state with `check`, and reports the
+/// best instructions per second.
+fn run_workload(name: &'static str, source: &str, check: fn(&VM)) -> Measurement {
+    let bytecode = assemble(source);
+    let mut best = Duration::MAX;
+    let mut instructions = 0;
+    for _ in 0..REPETITIONS {
+        let mut vm = VM::new();
+        vm.program = bytecode.clone();
+        vm.run().expect("benchmark program failed");
+        check(&vm);
+        best = best.min(vm.run_time());
+        instructions = vm.instructions_executed();
+    }
+    Measurement {
+        name,
+        unit: "instructions",
+        per_second: instructions as f64 / best.as_secs_f64(),
+    }
+}
+
+fn assemble_workload() -> Measurement {
+    let lines: Vec<&str> = [ARITHMETIC, FIBONACCI, SIEVE, BRANCHING]
+        .iter()
+        .flat_map(|source| source.lines())
+        .filter(|line| !line.is_empty())
+        .collect();
+    let source = lines.repeat(500).join("\n");
+    let line_count = lines.len() * 500;
+    let mut best = Duration::MAX;
+    for _ in 0..REPETITIONS {
+        let started = Instant::now();
+        let (rest, _) = program(&source).expect("benchmark source does not assemble");
+        best = best.min(started.elapsed());
+        assert!(rest.is_empty());
+    }
+    Measurement {
+        name: "assembler",
+        unit: "lines",
+        per_second: line_count as f64 / best.as_secs_f64(),
+    }
+}
+
+fn baseline_path() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target/esper-bench-baseline.txt")
+}
+
+fn load_baseline() -> HashMap<String, f64> {
+    fs::read_to_string(baseline_path())
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            let (name, value) = line.split_once(' ')?;
+            Some((name.to_string(), value.parse().ok()?))
+        })
+        .collect()
+}
+
+fn main() {
+    let save_baseline = std::env::args().any(|argument| argument == "--save-baseline");
+    let fail_on_regression = std::env::args().any(|argument| argument == "--fail-on-regression");
+    let measurements = [
+        run_workload("arithmetic", ARITHMETIC, |vm| {
+            assert_eq!(vm.registers[0], 1_000_000)
+        }),
+        run_workload("fibonacci", FIBONACCI, |vm| {
+            assert_eq!(vm.registers[0], 1_000_000)
+        }),
+        run_workload("sieve", SIEVE, |vm| {
+            let primes = vm.heap[2..].iter().filter(|byte| **byte == 0).count();
+            assert_eq!(primes, 6_057);
+        }),
+        run_workload("branching", BRANCHING, |vm| {
+            assert_eq!(vm.registers[12], 333_334);
+            assert_eq!(vm.registers[13], 666_666);
+            assert_eq!(vm.registers[16], 333_333);
+        }),
+        assemble_workload(),
+    ];
+
+    let baseline = load_baseline();
+    let mut regressions = vec![];
+    for measurement in &measurements {
+        print!(
+            "{:<12} {:>8.2} M {}/s",
+            measurement.name,
+            measurement.per_second / 1e6,
+            measurement.unit
+        );
+        if let Some(previous) = baseline.get(measurement.name) {
+            let change = measurement.per_second / previous - 1.0;
+            print!("  ({:+.1}% vs baseline)", change * 100.0);
+            if change < -REGRESSION_THRESHOLD {
+                regressions.push(measurement.name);
+            }
+        }
+        println!();
+    }
+
+    if save_baseline {
+        let contents: String = measurements
+            .iter()
+            .map(|measurement| format!("{} {}\n", measurement.name, measurement.per_second))
+            .collect();
+        fs::write(baseline_path(), contents).expect("unable to save the baseline");
+        println!("Saved baseline to {}", baseline_path().display());
+    } else if !regressions.is_empty() {
+        eprintln!("Regressed: {}", regressions.join(", "));
+        if fail_on_regression {
+            std::process::exit(1);
+        }
+    }
+}
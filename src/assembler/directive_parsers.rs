@@ -0,0 +1,68 @@
+use nom::{
+    bytes::complete::tag,
+    character::complete::{multispace0, space0, space1},
+    sequence::tuple,
+    IResult,
+};
+
+use super::expression_parsers::{expression, Expression};
+use super::label_parsers::identifier;
+
+// .equ BUF_SIZE 64, .equ LENGTH @end - @start
+pub fn equ_directive(input: &str) -> IResult<&str, (String, Expression)> {
+    let (input, _) = space0(input)?;
+    let (input, (_, _, name, _, value)) =
+        tuple((tag(".equ"), space1, identifier, space1, expression))(input)?;
+    let (input, _) = multispace0(input)?;
+    Ok((input, (name.to_string(), value)))
+}
+
+fn symbol_directive<'a>(directive: &'static str, input: &'a str) -> IResult<&'a str, String> {
+    let (input, _) = space0(input)?;
+    let (input, (_, _, name)) = tuple((tag(directive), space1, identifier))(input)?;
+    let (input, _) = multispace0(input)?;
+    Ok((input, name.to_string()))
+}
+
+// .export main
+pub fn export_directive(input: &str) -> IResult<&str, String> {
+    symbol_directive(".export", input)
+}
+
+// .import print_number
+pub fn import_directive(input: &str) -> IResult<&str, String> {
+    symbol_directive(".import", input)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_equ_directive() {
+        let result = equ_directive(".equ BUF_SIZE 64\nload $1 #BUF_SIZE");
+        assert_eq!(
+            result,
+            Ok((
+                "load $1 #BUF_SIZE",
+                (String::from("BUF_SIZE"), Expression::Number(64))
+            ))
+        );
+        assert!(equ_directive(".equ BUF_SIZE").is_err());
+        assert!(equ_directive(".equal X 1").is_err());
+    }
+
+    #[test]
+    fn test_parse_symbol_directives() {
+        assert_eq!(
+            export_directive(".export main\nmain: halt"),
+            Ok(("main: halt", String::from("main")))
+        );
+        assert_eq!(
+            import_directive("  .import print "),
+            Ok(("", String::from("print")))
+        );
+        assert!(import_directive(".export print").is_err());
+        assert!(export_directive(".export").is_err());
+    }
+}
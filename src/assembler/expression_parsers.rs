@@ -0,0 +1,237 @@
+use nom::{
+    branch::alt,
+    bytes::complete::tag,
+    character::complete::{char, digit1, one_of, space0},
+    combinator::map,
+    multi::many0,
+    sequence::{delimited, pair, preceded, tuple},
+    IResult,
+};
+
+use super::label_parsers::identifier;
+use super::symbols::SymbolTable;
+use super::AssemblerError;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+}
+
+/// An integer computed by the assembler, such as `BUF_SIZE * 4 + 1` or `@end - @start`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Expression {
+    Number(i64),
+    /// Digits for a number too large to be an `i64`, reported when evaluated
+    TooLarge(String),
+    /// A name from `.equ`
+    Constant(String),
+    /// `@name`, the label's byte offset
+    Label(String),
+    Negate(Box<Expression>),
+    Binary {
+        operator: Operator,
+        left: Box<Expression>,
+        right: Box<Expression>,
+    },
+}
+
+impl Expression {
+    pub fn evaluate(&self, symbols: &SymbolTable) -> Result<i64, AssemblerError> {
+        self.evaluate_within(symbols, &mut vec![])
+    }
+
+    /// `resolving` holds the constants whose values are being worked out, to catch a constant
+    /// defined in terms of itself.
+    fn evaluate_within(
+        &self,
+        symbols: &SymbolTable,
+        resolving: &mut Vec<String>,
+    ) -> Result<i64, AssemblerError> {
+        match self {
+            Expression::Number(value) => Ok(*value),
+            Expression::TooLarge(digits) => Err(AssemblerError::NumberOutOfRange {
+                digits: digits.clone(),
+            }),
+            Expression::Label(name) => match symbols.value(name) {
+                Some(offset) => Ok(offset as i64),
+                // Its address is only known after linking
+                None if symbols.is_imported(name) => {
+                    Err(AssemblerError::ImportInExpression { name: name.clone() })
+                }
+                None => Err(AssemblerError::UndefinedLabel { name: name.clone() }),
+            },
+            Expression::Constant(name) => {
+                if resolving.contains(name) {
+                    return Err(AssemblerError::ConstantCycle { name: name.clone() });
+                }
+                let expression = symbols
+                    .constant(name)
+                    .ok_or_else(|| AssemblerError::UndefinedConstant { name: name.clone() })?;
+                resolving.push(name.clone());
+                let value = expression.evaluate_within(symbols, resolving)?;
+                resolving.pop();
+                Ok(value)
+            }
+            Expression::Negate(operand) => operand
+                .evaluate_within(symbols, resolving)?
+                .checked_neg()
+                .ok_or(AssemblerError::ExpressionOverflow),
+            Expression::Binary {
+                operator,
+                left,
+                right,
+            } => {
+                let left = left.evaluate_within(symbols, resolving)?;
+                let right = right.evaluate_within(symbols, resolving)?;
+                let result = match operator {
+                    Operator::Add => left.checked_add(right),
+                    Operator::Subtract => left.checked_sub(right),
+                    Operator::Multiply => left.checked_mul(right),
+                    Operator::Divide | Operator::Remainder if right == 0 => {
+                        return Err(AssemblerError::DivisionByZero)
+                    }
+                    Operator::Divide => left.checked_div(right),
+                    Operator::Remainder => left.checked_rem(right),
+                };
+                result.ok_or(AssemblerError::ExpressionOverflow)
+            }
+        }
+    }
+}
+
+fn operator(symbol: char) -> Operator {
+    match symbol {
+        '+' => Operator::Add,
+        '-' => Operator::Subtract,
+        '*' => Operator::Multiply,
+        '/' => Operator::Divide,
+        _ => Operator::Remainder,
+    }
+}
+
+/// Folds `first` and the `(operator, operand)` pairs after it to the left.
+fn fold(first: Expression, rest: Vec<(char, Expression)>) -> Expression {
+    rest.into_iter()
+        .fold(first, |left, (symbol, right)| Expression::Binary {
+            operator: operator(symbol),
+            left: Box::new(left),
+            right: Box::new(right),
+        })
+}
+
+// 12, NAME, @label, (expression)
+fn atom(input: &str) -> IResult<&str, Expression> {
+    let (input, _) = space0(input)?;
+    let (input, atom) = alt((
+        map(digit1, |digits: &str| match digits.parse() {
+            Ok(value) => Expression::Number(value),
+            Err(_) => Expression::TooLarge(digits.to_string()),
+        }),
+        map(preceded(tag("@"), identifier), |name| {
+            Expression::Label(name.to_string())
+        }),
+        map(identifier, |name| Expression::Constant(name.to_string())),
+        delimited(char('('), expression, tuple((space0, char(')')))),
+    ))(input)?;
+    let (input, _) = space0(input)?;
+    Ok((input, atom))
+}
+
+fn unary(input: &str) -> IResult<&str, Expression> {
+    let (input, _) = space0(input)?;
+    alt((
+        map(preceded(char('-'), unary), |operand| {
+            Expression::Negate(Box::new(operand))
+        }),
+        atom,
+    ))(input)
+}
+
+fn term(input: &str) -> IResult<&str, Expression> {
+    let (input, (first, rest)) = pair(unary, many0(pair(one_of("*/%"), unary)))(input)?;
+    Ok((input, fold(first, rest)))
+}
+
+// Integer arithmetic with the usual precedence: `*`, `/` and `%` bind tighter than `+` and `-`
+pub fn expression(input: &str) -> IResult<&str, Expression> {
+    let (input, (first, rest)) = pair(term, many0(pair(one_of("+-"), term)))(input)?;
+    Ok((input, fold(first, rest)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(input: &str) -> Result<i64, AssemblerError> {
+        let mut symbols = SymbolTable::new();
+        symbols.define("start", 4).unwrap();
+        symbols.define("end", 20).unwrap();
+        symbols
+            .define_constant("BUF_SIZE", Expression::Number(16))
+            .unwrap();
+        symbols
+            .define_constant("TWICE", expression("BUF_SIZE * 2").unwrap().1)
+            .unwrap();
+        symbols
+            .define_constant("LOOP", Expression::Constant(String::from("LOOP")))
+            .unwrap();
+        let (rest, parsed) = expression(input).unwrap();
+        assert_eq!(rest, "");
+        parsed.evaluate(&symbols)
+    }
+
+    #[test]
+    fn test_expression_precedence() {
+        assert_eq!(value("BUF_SIZE * 4 + 1"), Ok(65));
+        assert_eq!(value("1 + BUF_SIZE * 4"), Ok(65));
+        assert_eq!(value("(1 + BUF_SIZE) * 4"), Ok(68));
+        assert_eq!(value("20 - 5 - 3"), Ok(12));
+        assert_eq!(value("-7 % 4"), Ok(-3));
+        assert_eq!(value("@end - @start"), Ok(16));
+        assert_eq!(value("TWICE / 3"), Ok(10));
+    }
+
+    #[test]
+    fn test_expression_errors() {
+        assert_eq!(
+            value("NOPE + 1"),
+            Err(AssemblerError::UndefinedConstant {
+                name: String::from("NOPE")
+            })
+        );
+        assert_eq!(
+            value("@nope"),
+            Err(AssemblerError::UndefinedLabel {
+                name: String::from("nope")
+            })
+        );
+        assert_eq!(
+            value("LOOP"),
+            Err(AssemblerError::ConstantCycle {
+                name: String::from("LOOP")
+            })
+        );
+        assert_eq!(value("4 / (2 - 2)"), Err(AssemblerError::DivisionByZero));
+        assert_eq!(
+            value("4000000000 * 4000000000 * 4"),
+            Err(AssemblerError::ExpressionOverflow)
+        );
+        assert_eq!(
+            value("99999999999999999999 - 1"),
+            Err(AssemblerError::NumberOutOfRange {
+                digits: String::from("99999999999999999999")
+            })
+        );
+    }
+
+    #[test]
+    fn test_expression_stops_at_next_instruction() {
+        let result = expression("2 load $2 #1");
+        assert_eq!(result, Ok(("load $2 #1", Expression::Number(2))));
+        assert!(expression("(1 + 2").is_err());
+    }
+}
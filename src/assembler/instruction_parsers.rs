@@ -1,81 +1,238 @@
+use std::collections::HashMap;
+
 use nom::branch::alt;
-use nom::character::complete::space0;
+use nom::character::complete::multispace0;
+use nom::combinator::opt;
 use nom::sequence::tuple;
 use nom::IResult;
 
+use super::label_parsers::label_declaration;
 use super::opcode_parsers::opcode;
-use super::operand_parsers::integer_operand;
-use super::register_parsers::register;
-use super::Token;
+use super::operand_parsers::{expression_operand, float_operand};
+use super::register_parsers::{float_register, register, resolve};
+use super::symbols::SymbolTable;
+use super::{AssemblerError, Token};
+use crate::instruction::{Opcode, OperandKind};
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct AssemblerInstruction {
     pub opcode: Token,
+    pub label: Option<Token>,
     pub operand1: Option<Token>,
     pub operand2: Option<Token>,
     pub operand3: Option<Token>,
 }
 
 impl AssemblerInstruction {
-    fn extract_operand(t: &Token, results: &mut Vec<u8>) {
+    /// Integer operands are 16 bits, which the VM reads as unsigned, so they may not be
+    /// negative. A negative value is made by subtracting from a register that holds 0, as in
+    /// `load $1 #0` then `subtractimmediate $1 $1 #6`.
+    fn push_integer(value: i64, results: &mut Vec<u8>) -> Result<(), AssemblerError> {
+        if !(0..=u16::MAX as i64).contains(&value) {
+            return Err(AssemblerError::OperandOutOfRange { value });
+        }
+        results.extend((value as u16).to_be_bytes());
+        Ok(())
+    }
+
+    fn extract_operand(
+        t: &Token,
+        symbols: &SymbolTable,
+        results: &mut Vec<u8>,
+    ) -> Result<(), AssemblerError> {
         match t {
-            Token::Register { index } => {
+            Token::Register { index } | Token::FloatRegister { index } => {
                 results.push(*index);
             }
             Token::IntegerOperand { value } => {
-                let converted = *value as u16;
-                let byte1 = converted as u8;
-                let byte2 = (converted >> 8) as u8;
-                results.push(byte2 as u8); // Big-endian
-                results.push(byte1 as u8);
+                AssemblerInstruction::push_integer(*value as i64, results)?;
+            }
+            Token::Expression { expression } => {
+                AssemblerInstruction::push_integer(expression.evaluate(symbols)?, results)?;
             }
-            _ => {
-                println!("Opcode found in operand field!");
-                std::process::exit(1);
+            // Aliases are resolved by `Assembler`, so only errors are left here
+            Token::RegisterName { name } => {
+                resolve(name, &HashMap::new())?;
+            }
+            Token::FloatOperand { value } => {
+                results.extend(value.to_be_bytes());
+            }
+            // Filled in by the linker
+            Token::LabelUsage { name } if symbols.is_imported(name) => {
+                results.extend([0, 0]);
+            }
+            Token::LabelUsage { name } => {
+                let offset = symbols
+                    .value(name)
+                    .ok_or_else(|| AssemblerError::UndefinedLabel { name: name.clone() })?;
+                results.extend((offset as u16).to_be_bytes());
+            }
+            Token::Op { .. } | Token::LabelDeclaration { .. } => {
+                return Err(AssemblerError::UnexpectedOperand);
             }
         }
+        Ok(())
     }
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut results = vec![];
+
+    /// The opcode, if the opcode field really holds one.
+    pub fn code(&self) -> Option<Opcode> {
         match self.opcode {
-            Token::Op { code } => {
-                results.push(code as u8);
+            Token::Op { code } => Some(code),
+            _ => None,
+        }
+    }
+
+    fn operands(&self) -> impl Iterator<Item = &Token> {
+        [&self.operand1, &self.operand2, &self.operand3]
+            .into_iter()
+            .flatten()
+    }
+
+    fn operand_width(token: &Token) -> usize {
+        match token {
+            Token::Register { .. } | Token::FloatRegister { .. } | Token::RegisterName { .. } => 1,
+            Token::IntegerOperand { .. } | Token::LabelUsage { .. } | Token::Expression { .. } => 2,
+            Token::FloatOperand { .. } => 8,
+            Token::Op { .. } | Token::LabelDeclaration { .. } => 0,
+        }
+    }
+
+    /// How many bytes `to_bytes` will produce, which is known before labels are resolved.
+    pub fn width(&self) -> usize {
+        1 + self
+            .operands()
+            .map(AssemblerInstruction::operand_width)
+            .sum::<usize>()
+    }
+
+    /// Checks that the operands are the ones `code` is encoded with, since each is written
+    /// out at the width of its own kind.
+    fn check_operands(&self, code: Opcode) -> Result<(), AssemblerError> {
+        let kinds = code.operands();
+        let found = self.operands().count();
+        if found != kinds.len() {
+            return Err(AssemblerError::OperandCount {
+                opcode: code,
+                expected: kinds.len(),
+                found,
+            });
+        }
+        for (token, kind) in self.operands().zip(kinds) {
+            let fits = match token {
+                Token::Register { .. } => *kind == OperandKind::Register,
+                Token::FloatRegister { .. } => *kind == OperandKind::FloatRegister,
+                // Resolved by `Assembler`, or reported by `extract_operand`
+                Token::RegisterName { .. } => {
+                    matches!(kind, OperandKind::Register | OperandKind::FloatRegister)
+                }
+                Token::IntegerOperand { .. }
+                | Token::LabelUsage { .. }
+                | Token::Expression { .. } => *kind == OperandKind::Integer,
+                Token::FloatOperand { .. } => *kind == OperandKind::Float,
+                Token::Op { .. } | Token::LabelDeclaration { .. } => true,
+            };
+            if !fits {
+                return Err(AssemblerError::WrongOperand {
+                    opcode: code,
+                    expected: *kind,
+                });
             }
-            _ => {
-                println!("Non-opcode found in opcode field!");
-                std::process::exit(1);
+        }
+        Ok(())
+    }
+
+    /// Each operand and where its bytes start in the output of `to_bytes`.
+    pub fn operand_offsets(&self) -> Vec<(usize, &Token)> {
+        let mut offset = 1;
+        self.operands()
+            .map(|token| {
+                let start = offset;
+                offset += AssemblerInstruction::operand_width(token);
+                (start, token)
+            })
+            .collect()
+    }
+
+    /// The instruction's bytecode, when it is placed at `offset`.
+    pub fn to_bytes(
+        &self,
+        symbols: &SymbolTable,
+        offset: usize,
+    ) -> Result<Vec<u8>, AssemblerError> {
+        let mut results = vec![];
+        match self.code() {
+            Some(Opcode::ILLEGAL) => return Err(AssemblerError::UnknownOpcode),
+            Some(code) => {
+                self.check_operands(code)?;
+                results.push(code as u8);
             }
+            None => return Err(AssemblerError::UnexpectedOperand),
+        }
+        for token in self.operands() {
+            AssemblerInstruction::extract_operand(token, symbols, &mut results)?;
+        }
+        if self.code() == Some(Opcode::LOADRELATIVE) {
+            AssemblerInstruction::make_relative(&self.operand2, symbols, offset, &mut results)?;
         }
+        Ok(results)
+    }
 
-        for token in [&self.operand1, &self.operand2, &self.operand3]
-            .iter()
-            .copied()
-            .flatten()
-        {
-            AssemblerInstruction::extract_operand(token, &mut results);
+    /// Replaces the address at the end of a `LOADRELATIVE` by its distance from the end of the
+    /// instruction. An imported label is left for the linker.
+    fn make_relative(
+        operand: &Option<Token>,
+        symbols: &SymbolTable,
+        offset: usize,
+        results: &mut [u8],
+    ) -> Result<(), AssemblerError> {
+        if let Some(Token::LabelUsage { name }) = operand {
+            if symbols.is_imported(name) {
+                return Ok(());
+            }
         }
-        results
+        let end = results.len();
+        let address = u16::from_be_bytes([results[end - 2], results[end - 1]]);
+        let distance = address as i64 - (offset + end) as i64;
+        let distance = i16::try_from(distance)
+            .map_err(|_| AssemblerError::OperandOutOfRange { value: distance })?;
+        results[end - 2..].copy_from_slice(&distance.to_be_bytes());
+        Ok(())
     }
 }
 
 pub fn one_instruction(input: &str) -> IResult<&str, AssemblerInstruction> {
-    let (input, _) = space0(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, label) = opt(label_declaration)(input)?;
+    // Longest shapes first, since a bare opcode is a prefix of every other shape
     let (input, result) = alt((
+        instruction_type_six,
+        instruction_type_three,
+        instruction_type_four,
         instruction_type_one,
+        instruction_type_five,
+        instruction_type_seven,
         instruction_type_two,
-        instruction_type_three,
     ))(input)?;
-    let (input, _) = space0(input)?;
-    Ok((input, result))
+    let (input, _) = multispace0(input)?;
+    Ok((input, AssemblerInstruction { label, ..result }))
+}
+
+fn any_register(input: &str) -> IResult<&str, Token> {
+    alt((float_register, register))(input)
+}
+
+fn any_operand(input: &str) -> IResult<&str, Token> {
+    alt((float_operand, expression_operand))(input)
 }
 
-// <opcode> <register> <operand> (例えLOAD $12 #34)
+// <opcode> <register> <operand> (例えLOAD $12 #34, LOADF64 $f1 #2.5)
 fn instruction_type_one(input: &str) -> IResult<&str, AssemblerInstruction> {
-    let (input, (opcode, operand1, operand2)) = tuple((opcode, register, integer_operand))(input)?;
+    let (input, (opcode, operand1, operand2)) = tuple((opcode, any_register, any_operand))(input)?;
     Ok((
         input,
         AssemblerInstruction {
             opcode,
+            label: None,
             operand1: Some(operand1),
             operand2: Some(operand2),
             operand3: None,
@@ -90,6 +247,7 @@ fn instruction_type_two(input: &str) -> IResult<&str, AssemblerInstruction> {
         input,
         AssemblerInstruction {
             opcode,
+            label: None,
             operand1: None,
             operand2: None,
             operand3: None,
@@ -97,14 +255,61 @@ fn instruction_type_two(input: &str) -> IResult<&str, AssemblerInstruction> {
     ))
 }
 
-// <opcode> <register> <register> <register> (例えADD $12 $13 $14)
+// <opcode> <register> <register> <register> (例えADD $12 $13 $14, ADDF64 $f0 $f1 $f2)
 fn instruction_type_three(input: &str) -> IResult<&str, AssemblerInstruction> {
     let (input, (opcode, operand1, operand2, operand3)) =
-        tuple((opcode, register, register, register))(input)?;
+        tuple((opcode, any_register, any_register, any_register))(input)?;
+    Ok((
+        input,
+        AssemblerInstruction {
+            opcode,
+            label: None,
+            operand1: Some(operand1),
+            operand2: Some(operand2),
+            operand3: Some(operand3),
+        },
+    ))
+}
+
+// <opcode> <register> <register> (例えEQUAL $1 $2, INTTOFLOAT $3 $f4)
+fn instruction_type_four(input: &str) -> IResult<&str, AssemblerInstruction> {
+    let (input, (opcode, operand1, operand2)) = tuple((opcode, any_register, any_register))(input)?;
+    Ok((
+        input,
+        AssemblerInstruction {
+            opcode,
+            label: None,
+            operand1: Some(operand1),
+            operand2: Some(operand2),
+            operand3: None,
+        },
+    ))
+}
+
+// <opcode> <register> (例えJUMP $5)
+fn instruction_type_five(input: &str) -> IResult<&str, AssemblerInstruction> {
+    let (input, (opcode, operand1)) = tuple((opcode, any_register))(input)?;
+    Ok((
+        input,
+        AssemblerInstruction {
+            opcode,
+            label: None,
+            operand1: Some(operand1),
+            operand2: None,
+            operand3: None,
+        },
+    ))
+}
+
+// <opcode> <register> <register> <operand> (例えADDIMMEDIATE $1 $2 #3)
+fn instruction_type_six(input: &str) -> IResult<&str, AssemblerInstruction> {
+    let (input, (opcode, operand1, operand2, operand3)) =
+        tuple((opcode, any_register, any_register, any_operand))(input)?;
     Ok((
         input,
         AssemblerInstruction {
             opcode,
+            label: None,
             operand1: Some(operand1),
             operand2: Some(operand2),
             operand3: Some(operand3),
@@ -112,10 +317,24 @@ fn instruction_type_three(input: &str) -> IResult<&str, AssemblerInstruction> {
     ))
 }
 
+// <opcode> <operand> (例えCALLNATIVE #3)
+fn instruction_type_seven(input: &str) -> IResult<&str, AssemblerInstruction> {
+    let (input, (opcode, operand1)) = tuple((opcode, any_operand))(input)?;
+    Ok((
+        input,
+        AssemblerInstruction {
+            opcode,
+            label: None,
+            operand1: Some(operand1),
+            operand2: None,
+            operand3: None,
+        },
+    ))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::instruction::Opcode;
 
     #[test]
     fn test_parse_type_one_instruction() {
@@ -126,6 +345,7 @@ mod tests {
                 "",
                 AssemblerInstruction {
                     opcode: Token::Op { code: Opcode::LOAD },
+                    label: None,
                     operand1: Some(Token::Register { index: 1 }),
                     operand2: Some(Token::IntegerOperand { value: 2 }),
                     operand3: None,
@@ -143,6 +363,7 @@ mod tests {
                 "",
                 AssemblerInstruction {
                     opcode: Token::Op { code: Opcode::HALT },
+                    label: None,
                     operand1: None,
                     operand2: None,
                     operand3: None,
@@ -160,6 +381,7 @@ mod tests {
                 "",
                 AssemblerInstruction {
                     opcode: Token::Op { code: Opcode::ADD },
+                    label: None,
                     operand1: Some(Token::Register { index: 12 }),
                     operand2: Some(Token::Register { index: 13 }),
                     operand3: Some(Token::Register { index: 14 }),
@@ -167,4 +389,144 @@ mod tests {
             ))
         )
     }
+
+    #[test]
+    fn test_parse_type_four_instruction() {
+        let result = one_instruction("inttofloat $3 $f4");
+        assert_eq!(
+            result,
+            Ok((
+                "",
+                AssemblerInstruction {
+                    opcode: Token::Op {
+                        code: Opcode::INTTOFLOAT
+                    },
+                    label: None,
+                    operand1: Some(Token::Register { index: 3 }),
+                    operand2: Some(Token::FloatRegister { index: 4 }),
+                    operand3: None,
+                }
+            ))
+        )
+    }
+
+    #[test]
+    fn test_parse_type_five_instruction() {
+        let result = one_instruction("jump $5");
+        assert_eq!(
+            result,
+            Ok((
+                "",
+                AssemblerInstruction {
+                    opcode: Token::Op { code: Opcode::JUMP },
+                    label: None,
+                    operand1: Some(Token::Register { index: 5 }),
+                    operand2: None,
+                    operand3: None,
+                }
+            ))
+        )
+    }
+
+    #[test]
+    fn test_float_instruction_to_bytes() {
+        let (_, instruction) = one_instruction("loadf64 $f1 #2.5").unwrap();
+        let mut expected = vec![Opcode::LOADF64 as u8, 1];
+        expected.extend(2.5f64.to_be_bytes());
+        assert_eq!(instruction.to_bytes(&SymbolTable::new(), 0), Ok(expected));
+
+        let (_, instruction) = one_instruction("addf64 $f0 $f1 $f2").unwrap();
+        assert_eq!(
+            instruction.to_bytes(&SymbolTable::new(), 0),
+            Ok(vec![Opcode::ADDF64 as u8, 0, 1, 2])
+        );
+    }
+
+    #[test]
+    fn test_operands_must_fit_the_opcode() {
+        let to_bytes = |source| {
+            let (_, instruction) = one_instruction(source).unwrap();
+            instruction.to_bytes(&SymbolTable::new(), 0)
+        };
+        assert_eq!(
+            to_bytes("load $1 #2.5"),
+            Err(AssemblerError::WrongOperand {
+                opcode: Opcode::LOAD,
+                expected: OperandKind::Integer
+            })
+        );
+        assert_eq!(
+            to_bytes("loadf64 $f1 #2"),
+            Err(AssemblerError::WrongOperand {
+                opcode: Opcode::LOADF64,
+                expected: OperandKind::Float
+            })
+        );
+        assert_eq!(
+            to_bytes("add $1 $f2 $3"),
+            Err(AssemblerError::WrongOperand {
+                opcode: Opcode::ADD,
+                expected: OperandKind::Register
+            })
+        );
+        assert_eq!(
+            to_bytes("load $1"),
+            Err(AssemblerError::OperandCount {
+                opcode: Opcode::LOAD,
+                expected: 2,
+                found: 1
+            })
+        );
+        assert!(to_bytes("inttofloat $1 $f2").is_ok());
+    }
+
+    #[test]
+    fn test_parse_type_six_instruction() {
+        let result = one_instruction("addimmediate $1 $2 #300");
+        assert_eq!(
+            result,
+            Ok((
+                "",
+                AssemblerInstruction {
+                    opcode: Token::Op {
+                        code: Opcode::ADDIMMEDIATE
+                    },
+                    label: None,
+                    operand1: Some(Token::Register { index: 1 }),
+                    operand2: Some(Token::Register { index: 2 }),
+                    operand3: Some(Token::IntegerOperand { value: 300 }),
+                }
+            ))
+        );
+        let (_, instruction) = result.unwrap();
+        assert_eq!(
+            instruction.to_bytes(&SymbolTable::new(), 0),
+            Ok(vec![Opcode::ADDIMMEDIATE as u8, 1, 2, 1, 44])
+        );
+    }
+
+    #[test]
+    fn test_parse_type_seven_instruction() {
+        let result = one_instruction("callnative #300");
+        assert_eq!(
+            result,
+            Ok((
+                "",
+                AssemblerInstruction {
+                    opcode: Token::Op {
+                        code: Opcode::CALLNATIVE
+                    },
+                    label: None,
+                    operand1: Some(Token::IntegerOperand { value: 300 }),
+                    operand2: None,
+                    operand3: None,
+                }
+            ))
+        );
+        let (_, instruction) = result.unwrap();
+        assert_eq!(
+            instruction.to_bytes(&SymbolTable::new(), 0),
+            Ok(vec![Opcode::CALLNATIVE as u8, 1, 44])
+        );
+    }
 }
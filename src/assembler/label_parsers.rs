@@ -0,0 +1,81 @@
+use nom::{
+    branch::alt,
+    bytes::complete::tag,
+    character::complete::{alpha1, alphanumeric1, multispace0, space0},
+    combinator::recognize,
+    multi::many0,
+    sequence::{pair, tuple},
+    IResult,
+};
+
+use super::Token;
+
+// Letters, digits and underscores, not starting with a digit
+pub fn identifier(input: &str) -> IResult<&str, &str> {
+    recognize(pair(
+        alt((alpha1, tag("_"))),
+        many0(alt((alphanumeric1, tag("_")))),
+    ))(input)
+}
+
+// loop: (the instruction that follows may be on the next line)
+pub fn label_declaration(input: &str) -> IResult<&str, Token> {
+    let (input, _) = space0(input)?;
+    let (input, (name, _)) = tuple((identifier, tag(":")))(input)?;
+    let (input, _) = multispace0(input)?;
+    Ok((
+        input,
+        Token::LabelDeclaration {
+            name: name.to_string(),
+        },
+    ))
+}
+
+// @loop
+pub fn label_usage(input: &str) -> IResult<&str, Token> {
+    let (input, _) = space0(input)?;
+    let (input, (_, name)) = tuple((tag("@"), identifier))(input)?;
+    let (input, _) = space0(input)?;
+    Ok((
+        input,
+        Token::LabelUsage {
+            name: name.to_string(),
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_label_declaration() {
+        let result = label_declaration("loop_2:\n increment $0");
+        assert_eq!(
+            result,
+            Ok((
+                "increment $0",
+                Token::LabelDeclaration {
+                    name: String::from("loop_2")
+                }
+            ))
+        );
+        assert!(label_declaration("loop").is_err());
+        assert!(label_declaration("2loop:").is_err());
+    }
+
+    #[test]
+    fn test_parse_label_usage() {
+        let result = label_usage("@end ");
+        assert_eq!(
+            result,
+            Ok((
+                "",
+                Token::LabelUsage {
+                    name: String::from("end")
+                }
+            ))
+        );
+        assert!(label_usage("end").is_err());
+    }
+}
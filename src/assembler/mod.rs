@@ -1,13 +1,1269 @@
-use crate::instruction::Opcode;
+use std::fmt;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::debug_info::{DebugInfo, LineEntry, Location};
+use crate::instruction::{Opcode, OperandKind};
+use crate::object::{Object, Relocation, Target};
+use directive_parsers::{equ_directive, export_directive, import_directive};
+use expression_parsers::Expression;
+use instruction_parsers::{one_instruction, AssemblerInstruction};
+use label_parsers::label_declaration;
+use preprocessor::{ExpandedLine, RETURN_ADDRESS};
+use symbols::SymbolTable;
+
+pub mod directive_parsers;
+pub mod expression_parsers;
 pub mod instruction_parsers;
+pub mod label_parsers;
 pub mod opcode_parsers;
 pub mod operand_parsers;
+pub mod optimizer;
+pub mod preprocessor;
 pub mod program_parsers;
 pub mod register_parsers;
+pub mod symbols;
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Token {
-    Op { code: Opcode },
-    Register { index: u8 },
-    IntegerOperand { value: i32 },
+    Op {
+        code: Opcode,
+    },
+    Register {
+        index: u8,
+    },
+    FloatRegister {
+        index: u8,
+    },
+    /// `$name` for a register the parser does not know: an alias, or a number the VM has no
+    /// register for, both sorted out by the assembler
+    RegisterName {
+        name: String,
+    },
+    IntegerOperand {
+        value: i32,
+    },
+    FloatOperand {
+        value: f64,
+    },
+    LabelDeclaration {
+        name: String,
+    },
+    LabelUsage {
+        name: String,
+    },
+    /// An integer operand worked out once every label and constant is known
+    Expression {
+        expression: Expression,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AssemblerError {
+    /// Nothing that parses as an instruction starts here
+    UnexpectedInput,
+    UnknownOpcode,
+    /// A token turned up where the instruction's shape has no room for it
+    UnexpectedOperand,
+    /// An operand that is not the kind its opcode takes in that place, such as a float where
+    /// `LOAD` takes an integer
+    WrongOperand {
+        opcode: Opcode,
+        expected: OperandKind,
+    },
+    OperandCount {
+        opcode: Opcode,
+        expected: usize,
+        found: usize,
+    },
+    DuplicateLabel {
+        name: String,
+    },
+    UndefinedLabel {
+        name: String,
+    },
+    DuplicateMacro {
+        name: String,
+    },
+    MacroShadowsOpcode {
+        name: String,
+    },
+    NestedMacroDefinition,
+    UnexpectedEndm,
+    UnterminatedMacro {
+        name: String,
+    },
+    MacroArguments {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    /// Macros invoked more than `MAX_MACRO_DEPTH` deep, most likely recursively
+    MacroTooDeep {
+        name: String,
+    },
+    IncludeNotFound {
+        path: String,
+    },
+    /// A file includes itself, directly or through others
+    IncludeCycle {
+        path: String,
+    },
+    CannotRead {
+        path: String,
+        message: String,
+    },
+    DuplicateConstant {
+        name: String,
+    },
+    UndefinedConstant {
+        name: String,
+    },
+    /// A constant defined in terms of itself, directly or through others
+    ConstantCycle {
+        name: String,
+    },
+    ExpressionOverflow,
+    DivisionByZero,
+    /// An integer operand that does not fit in 16 bits
+    OperandOutOfRange {
+        value: i64,
+    },
+    /// A number written with more digits than an `i64` holds
+    NumberOutOfRange {
+        digits: String,
+    },
+    ImportWithoutLinking {
+        name: String,
+    },
+    /// An imported label in an expression, whose value the linker cannot work out
+    ImportInExpression {
+        name: String,
+    },
+    /// An operand in an object that depends on where the code is placed in a way the linker
+    /// cannot fix up, such as `@a + @b`
+    NotRelocatable,
+    /// `$name` where the VM has no such register, such as `$200`
+    NoSuchRegister {
+        name: String,
+    },
+    UndefinedAlias {
+        name: String,
+    },
+    DuplicateAlias {
+        name: String,
+    },
+    AliasShadowsRegister {
+        name: String,
+    },
+    NestedFunction,
+    UnexpectedEndfunc,
+    UnterminatedFunction {
+        name: String,
+    },
+    ReturnOutsideFunction,
+    /// `$sp` in a `.func` line's `uses`, which cannot be saved through itself
+    FunctionUsesStackPointer {
+        name: String,
+    },
+    /// A warning: a function overwriting a register that its `.func` line does not declare,
+    /// which the caller may still need
+    UndeclaredWrite {
+        function: String,
+        register: u8,
+    },
+}
+
+impl fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AssemblerError::UnexpectedInput => write!(f, "expected an instruction"),
+            AssemblerError::UnknownOpcode => write!(f, "unknown opcode"),
+            AssemblerError::UnexpectedOperand => write!(f, "operand in the wrong place"),
+            AssemblerError::WrongOperand { opcode, expected } => {
+                write!(f, "{:?} takes {} there", opcode, expected)
+            }
+            AssemblerError::OperandCount {
+                opcode,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{:?} takes {} operand(s), not {}",
+                opcode, expected, found
+            ),
+            AssemblerError::DuplicateLabel { name } => {
+                write!(f, "label {} is already declared", name)
+            }
+            AssemblerError::UndefinedLabel { name } => {
+                write!(f, "label {} is never declared", name)
+            }
+            AssemblerError::DuplicateMacro { name } => {
+                write!(f, "macro {} is already defined", name)
+            }
+            AssemblerError::MacroShadowsOpcode { name } => {
+                write!(f, "macro {} has the name of an opcode", name)
+            }
+            AssemblerError::NestedMacroDefinition => {
+                write!(f, "macros cannot be defined inside other macros")
+            }
+            AssemblerError::UnexpectedEndm => write!(f, ".endm without .macro"),
+            AssemblerError::UnterminatedMacro { name } => {
+                write!(f, "macro {} has no .endm", name)
+            }
+            AssemblerError::MacroArguments {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "macro {} takes {} argument(s), not {}",
+                name, expected, found
+            ),
+            AssemblerError::MacroTooDeep { name } => write!(
+                f,
+                "macro {} is nested more than {} deep",
+                name,
+                preprocessor::MAX_MACRO_DEPTH
+            ),
+            AssemblerError::IncludeNotFound { path } => {
+                write!(f, "cannot find {} to include", path)
+            }
+            AssemblerError::IncludeCycle { path } => write!(f, "{} includes itself", path),
+            AssemblerError::CannotRead { path, message } => {
+                write!(f, "cannot read {}: {}", path, message)
+            }
+            AssemblerError::DuplicateConstant { name } => {
+                write!(f, "constant {} is already defined", name)
+            }
+            AssemblerError::UndefinedConstant { name } => {
+                write!(f, "constant {} is never defined", name)
+            }
+            AssemblerError::ConstantCycle { name } => {
+                write!(f, "constant {} depends on itself", name)
+            }
+            AssemblerError::ExpressionOverflow => write!(f, "expression overflows"),
+            AssemblerError::DivisionByZero => write!(f, "division by zero in expression"),
+            AssemblerError::OperandOutOfRange { value } if *value < 0 => {
+                write!(f, "{} is negative, but this operand is unsigned", value)
+            }
+            AssemblerError::OperandOutOfRange { value } => {
+                write!(f, "{} does not fit in a 16-bit operand", value)
+            }
+            AssemblerError::NumberOutOfRange { digits } => {
+                write!(f, "{} does not fit in 64 bits", digits)
+            }
+            AssemblerError::ImportWithoutLinking { name } => write!(
+                f,
+                "label {} is imported, so the source must be assembled as an object and linked",
+                name
+            ),
+            AssemblerError::ImportInExpression { name } => {
+                write!(f, "imported label {} can only be used on its own", name)
+            }
+            AssemblerError::NotRelocatable => write!(
+                f,
+                "operand cannot be relocated: its labels must cancel out or leave one label plus a constant"
+            ),
+            AssemblerError::NoSuchRegister { name } => write!(
+                f,
+                "there is no register ${}; the VM has {} of each kind",
+                name,
+                crate::vm::REGISTER_COUNT
+            ),
+            AssemblerError::UndefinedAlias { name } => {
+                write!(f, "register alias ${} is never defined", name)
+            }
+            AssemblerError::DuplicateAlias { name } => {
+                write!(f, "register alias ${} is already defined", name)
+            }
+            AssemblerError::AliasShadowsRegister { name } => {
+                write!(f, "register alias ${} has the name of a register", name)
+            }
+            AssemblerError::NestedFunction => {
+                write!(f, "functions cannot be defined inside other functions")
+            }
+            AssemblerError::UnexpectedEndfunc => write!(f, ".endfunc without .func"),
+            AssemblerError::UnterminatedFunction { name } => {
+                write!(f, "function {} has no .endfunc", name)
+            }
+            AssemblerError::ReturnOutsideFunction => write!(f, ".return outside a function"),
+            AssemblerError::FunctionUsesStackPointer { name } => {
+                write!(f, "function {} cannot save $sp, the stack it saves to", name)
+            }
+            AssemblerError::UndeclaredWrite { function, register } => write!(
+                f,
+                "function {} writes ${}, which is not in its uses",
+                function, register
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AssemblerError {}
+
+/// An `AssemblerError` and the source position it refers to. Inside a macro, `location` is in
+/// the macro's body and `expansion` lists the invocations that led there; `included_from`
+/// lists the `.include` lines that led to the file. Both are outermost first.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceError {
+    pub location: Location,
+    pub error: AssemblerError,
+    pub expansion: Vec<Location>,
+    pub included_from: Vec<Location>,
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.error)?;
+        for site in self.expansion.iter().rev() {
+            write!(f, "\n  in the macro expanded at {}", site)?;
+        }
+        for site in self.included_from.iter().rev() {
+            write!(f, "\n  in the file included from {}", site)?;
+        }
+        Ok(())
+    }
+}
+
+/// The output of `Assembler::assemble`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Assembled {
+    pub program: Vec<u8>,
+    /// Present when `Assembler::emit_debug_info` is set
+    pub debug_info: Option<DebugInfo>,
+    /// Where the operands holding addresses in `program` are, for `loader::relocate`
+    pub relocations: Vec<usize>,
+    /// Problems that did not stop the program from being assembled
+    pub warnings: Vec<SourceError>,
+}
+
+/// One instruction and where its source starts: `position` at its label, if it has one, and
+/// `opcode_position` at the instruction itself.
+struct Statement {
+    position: usize,
+    opcode_position: usize,
+    instruction: AssemblerInstruction,
+}
+
+/// An `.equ` definition.
+struct Constant {
+    position: usize,
+    name: String,
+    expression: Expression,
+}
+
+/// Everything read from a source, with the positions of the directives.
+#[derive(Default)]
+struct Parsed {
+    statements: Vec<Statement>,
+    constants: Vec<Constant>,
+    imports: Vec<(usize, String)>,
+    exports: Vec<(usize, String)>,
+}
+
+/// Source after macro expansion, and where each of its lines came from.
+struct Expanded {
+    text: String,
+    /// Where each line starts in `text`
+    line_starts: Vec<usize>,
+    lines: Vec<ExpandedLine>,
+}
+
+impl Expanded {
+    fn new(lines: Vec<ExpandedLine>) -> Expanded {
+        let mut text = String::new();
+        let mut line_starts = vec![];
+        for line in &lines {
+            line_starts.push(text.len());
+            text.push_str(&line.text);
+            text.push('\n');
+        }
+        Expanded {
+            text,
+            line_starts,
+            lines,
+        }
+    }
+
+    /// Where the text at `position` was written, and the line it is on.
+    fn origin(&self, position: usize) -> (Location, &ExpandedLine) {
+        let index = self.line_starts.partition_point(|start| *start <= position) - 1;
+        let line = &self.lines[index];
+        let column = self.text[self.line_starts[index]..position].chars().count() + 1;
+        let location = Location {
+            file: line.file.clone(),
+            line: line.line,
+            column,
+        };
+        (location, line)
+    }
+
+    fn error(&self, position: usize, error: AssemblerError) -> SourceError {
+        let (location, line) = self.origin(position);
+        SourceError {
+            location,
+            error,
+            expansion: line.expansion.clone(),
+            included_from: line.included_from.clone(),
+        }
+    }
+}
+
+/// An instruction after encoding, with the location of its opcode and the line of the main
+/// file that it came from, through macros and includes.
+struct Encoded {
+    location: Location,
+    main_line: usize,
+    offset: usize,
+    bytes: Vec<u8>,
+    /// The operands that depend on where the code is placed, at offsets into the program
+    relocations: Vec<Relocation>,
+}
+
+/// What `token` refers to that moves with the code, if anything.
+fn relocation_target(
+    token: &Token,
+    symbols: &SymbolTable,
+) -> Result<Option<Target>, AssemblerError> {
+    match token {
+        Token::LabelUsage { name } if symbols.is_imported(name) => {
+            Ok(Some(Target::Import(name.clone())))
+        }
+        Token::LabelUsage { name } => Ok(symbols.value(name).map(Target::Local)),
+        Token::Expression { expression } => {
+            // An expression moves with the code when it counts one label, like `@table + 4`,
+            // and stays put when its labels cancel out, like `@end - @start`
+            let here = expression.evaluate(symbols)?;
+            let moved = expression.evaluate(&symbols.shifted(1))?;
+            match (moved - here, usize::try_from(here)) {
+                (0, _) => Ok(None),
+                (1, Ok(offset)) => Ok(Some(Target::Local(offset))),
+                _ => Err(AssemblerError::NotRelocatable),
+            }
+        }
+        _ => Ok(None),
+    }
+}
+
+/// A warning for each instruction in a function's body that overwrites a register the caller
+/// may need: one not declared in its `.func` line, `$ra`, an argument or a scratch register.
+fn undeclared_writes(source: &Expanded, statements: &[Statement]) -> Vec<SourceError> {
+    let mut warnings = vec![];
+    for statement in statements {
+        let (_, line) = source.origin(statement.opcode_position);
+        let function = match &line.function {
+            Some(function) => function,
+            None => continue,
+        };
+        let instruction = &statement.instruction;
+        let written = instruction
+            .code()
+            .and_then(Opcode::written_register)
+            .and_then(|index| {
+                [
+                    &instruction.operand1,
+                    &instruction.operand2,
+                    &instruction.operand3,
+                ][index]
+                    .as_ref()
+            });
+        if let Some(Token::Register { index }) = written {
+            if !function.uses.contains(index)
+                && *index != RETURN_ADDRESS
+                && !register_parsers::is_caller_saved(*index)
+            {
+                let warning = AssemblerError::UndeclaredWrite {
+                    function: function.name.clone(),
+                    register: *index,
+                };
+                warnings.push(source.error(statement.opcode_position, warning));
+            }
+        }
+    }
+    warnings
+}
+
+/// Room for the hex bytes of a listing line: the ten bytes of a `LOADF64`.
+const LISTING_BYTES_WIDTH: usize = 29;
+
+/// Turns a whole source file into bytecode, resolving labels and reporting every problem with
+/// its position in the file.
+pub struct Assembler {
+    /// The name used in locations when assembling source that was not read from a file
+    pub file_name: String,
+    pub emit_debug_info: bool,
+    /// Directories searched for `.include`d files that are not beside the including file
+    pub search_paths: Vec<PathBuf>,
+    /// Whether programs go through `optimizer::optimize` before they are encoded, which is off
+    /// unless asked for. Objects never do, since other objects may reach any of their exported
+    /// labels.
+    pub optimize: bool,
+}
+
+impl Assembler {
+    pub fn new(file_name: &str) -> Assembler {
+        Assembler {
+            file_name: file_name.to_string(),
+            emit_debug_info: false,
+            search_paths: vec![],
+            optimize: false,
+        }
+    }
+
+    fn read(path: &Path) -> Result<String, Vec<SourceError>> {
+        fs::read_to_string(path).map_err(|error| {
+            vec![SourceError {
+                location: Location {
+                    file: path.display().to_string(),
+                    line: 1,
+                    column: 1,
+                },
+                error: AssemblerError::CannotRead {
+                    path: path.display().to_string(),
+                    message: error.to_string(),
+                },
+                expansion: vec![],
+                included_from: vec![],
+            }]
+        })
+    }
+
+    fn parse(&self, source: &Expanded) -> Result<Parsed, Vec<SourceError>> {
+        let text = source.text.as_str();
+        let mut parsed = Parsed::default();
+        let mut errors = vec![];
+        let mut rest = text;
+        while !rest.trim_start().is_empty() {
+            let start = rest.trim_start();
+            let position = text.len() - start.len();
+            if let Ok((remaining, (name, expression))) = equ_directive(rest) {
+                parsed.constants.push(Constant {
+                    position,
+                    name,
+                    expression,
+                });
+                rest = remaining;
+                continue;
+            }
+            if let Ok((remaining, name)) = import_directive(rest) {
+                parsed.imports.push((position, name));
+                rest = remaining;
+                continue;
+            }
+            if let Ok((remaining, name)) = export_directive(rest) {
+                parsed.exports.push((position, name));
+                rest = remaining;
+                continue;
+            }
+            let (remaining, mut instruction) = match one_instruction(rest) {
+                Ok(parsed) => parsed,
+                Err(_) => {
+                    errors.push(source.error(position, AssemblerError::UnexpectedInput));
+                    return Err(errors);
+                }
+            };
+            let opcode_position = match label_declaration(start) {
+                Ok((after_label, _)) => text.len() - after_label.len(),
+                Err(_) => position,
+            };
+            let (_, line) = source.origin(opcode_position);
+            for operand in [
+                &mut instruction.operand1,
+                &mut instruction.operand2,
+                &mut instruction.operand3,
+            ] {
+                if let Some(Token::RegisterName { name }) = operand {
+                    match register_parsers::resolve(name, &line.aliases) {
+                        Ok(register) => *operand = Some(register),
+                        Err(error) => {
+                            // Point at the name itself rather than the instruction
+                            let offset = text[opcode_position..]
+                                .find(&format!("${}", name))
+                                .unwrap_or(0);
+                            errors.push(source.error(opcode_position + offset, error));
+                        }
+                    }
+                }
+            }
+            parsed.statements.push(Statement {
+                position,
+                opcode_position,
+                instruction,
+            });
+            rest = remaining;
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(parsed)
+    }
+
+    /// Expands, parses, lays out and encodes `source`, stopping short of joining the
+    /// instructions. `relocatable` code is an object's: it may import labels, and every operand
+    /// that depends on where it is placed must be one the linker can fix up.
+    fn encode(
+        &self,
+        file: &str,
+        source: &str,
+        relocatable: bool,
+    ) -> Result<(Vec<Encoded>, SymbolTable, Vec<SourceError>), Vec<SourceError>> {
+        let lines = preprocessor::expand(file, source, &self.search_paths)?;
+        let expanded = Expanded::new(lines);
+        let parsed = self.parse(&expanded)?;
+        let warnings = undeclared_writes(&expanded, &parsed.statements);
+        // Checked as written first, so that errors in code the optimizer drops are still reported
+        let (encoded, symbols) =
+            Assembler::lay_out(&expanded, &parsed, &parsed.statements, relocatable)?;
+        if !self.optimize || relocatable {
+            return Ok((encoded, symbols, warnings));
+        }
+        let instructions = parsed
+            .statements
+            .iter()
+            .map(|statement| statement.instruction.clone())
+            .collect();
+        let optimized: Vec<Statement> = optimizer::optimize_numbered(instructions)
+            .into_iter()
+            .map(|(index, instruction)| Statement {
+                instruction,
+                ..parsed.statements[index]
+            })
+            .collect();
+        let (encoded, symbols) = Assembler::lay_out(&expanded, &parsed, &optimized, relocatable)?;
+        Ok((encoded, symbols, warnings))
+    }
+
+    /// Gives the labels in `statements` their offsets and encodes them, along with the
+    /// directives in `parsed`.
+    fn lay_out(
+        expanded: &Expanded,
+        parsed: &Parsed,
+        statements: &[Statement],
+        relocatable: bool,
+    ) -> Result<(Vec<Encoded>, SymbolTable), Vec<SourceError>> {
+        let mut errors = vec![];
+
+        let mut symbols = SymbolTable::new();
+        let mut defined = vec![];
+        for constant in &parsed.constants {
+            match symbols.define_constant(&constant.name, constant.expression.clone()) {
+                Ok(()) => defined.push(constant),
+                Err(error) => {
+                    errors.push((constant.position, expanded.error(constant.position, error)))
+                }
+            }
+        }
+
+        for (position, name) in &parsed.imports {
+            let result = symbols.import(name).and_then(|()| match relocatable {
+                true => Ok(()),
+                false => Err(AssemblerError::ImportWithoutLinking { name: name.clone() }),
+            });
+            if let Err(error) = result {
+                errors.push((*position, expanded.error(*position, error)));
+            }
+        }
+
+        // First pass: lay out the instructions so every label has an offset
+        let mut offsets = vec![];
+        let mut offset = 0;
+        for statement in statements {
+            if let Some(Token::LabelDeclaration { name }) = &statement.instruction.label {
+                if let Err(error) = symbols.define(name, offset) {
+                    errors.push((
+                        statement.position,
+                        expanded.error(statement.position, error),
+                    ));
+                }
+            }
+            offsets.push(offset);
+            offset += statement.instruction.width();
+        }
+
+        for (position, name) in &parsed.exports {
+            match symbols.value(name) {
+                Some(_) => symbols.export(name),
+                None => {
+                    let error = AssemblerError::UndefinedLabel { name: name.clone() };
+                    errors.push((*position, expanded.error(*position, error)));
+                }
+            }
+        }
+
+        // Constants are checked where they are defined, even if nothing uses them
+        for constant in &defined {
+            // Evaluated by name, so that a cycle is reported against the constant that starts it
+            let name = Expression::Constant(constant.name.clone());
+            if let Err(error) = name.evaluate(&symbols) {
+                errors.push((constant.position, expanded.error(constant.position, error)));
+            }
+        }
+
+        let mut encoded = vec![];
+        for (statement, offset) in statements.iter().zip(offsets) {
+            let position = statement.opcode_position;
+            match statement.instruction.to_bytes(&symbols, offset) {
+                Ok(bytes) => {
+                    let relative = statement.instruction.code() == Some(Opcode::LOADRELATIVE);
+                    let mut relocations = vec![];
+                    for (start, token) in statement.instruction.operand_offsets() {
+                        match relocation_target(token, &symbols) {
+                            // A distance within the code stays right wherever the code goes
+                            Ok(Some(Target::Local(_))) if relative => {}
+                            Ok(Some(target)) => relocations.push(Relocation {
+                                offset: offset + start,
+                                target,
+                                relative,
+                            }),
+                            Ok(None) => {}
+                            Err(error) if relocatable => {
+                                errors.push((position, expanded.error(position, error)))
+                            }
+                            Err(_) => {}
+                        }
+                    }
+                    let (location, line) = expanded.origin(position);
+                    let main_line = line
+                        .included_from
+                        .first()
+                        .or(line.expansion.first())
+                        .map_or(location.line, |site| site.line);
+                    encoded.push(Encoded {
+                        location,
+                        main_line,
+                        offset,
+                        bytes,
+                        relocations,
+                    })
+                }
+                Err(error) => errors.push((position, expanded.error(position, error))),
+            }
+        }
+        if !errors.is_empty() {
+            errors.sort_by_key(|(position, _)| *position);
+            return Err(errors.into_iter().map(|(_, error)| error).collect());
+        }
+        Ok((encoded, symbols))
+    }
+
+    fn assemble_named(&self, file: &str, source: &str) -> Result<Assembled, Vec<SourceError>> {
+        let (encoded, symbols, warnings) = self.encode(file, source, false)?;
+        let debug_info = self.emit_debug_info.then(|| {
+            // The main file comes first, then included files in the order their code appears
+            let mut files = vec![file.to_string()];
+            let mut lines = vec![];
+            for instruction in &encoded {
+                let name = &instruction.location.file;
+                let index = match files.iter().position(|known| known == name) {
+                    Some(index) => index,
+                    None => {
+                        files.push(name.clone());
+                        files.len() - 1
+                    }
+                };
+                lines.push(LineEntry {
+                    offset: instruction.offset,
+                    file: index,
+                    line: instruction.location.line,
+                    column: instruction.location.column,
+                });
+            }
+            DebugInfo {
+                files,
+                lines,
+                labels: symbols.labels(),
+            }
+        });
+        let relocations = encoded
+            .iter()
+            .flat_map(|instruction| &instruction.relocations)
+            .map(|relocation| relocation.offset)
+            .collect();
+        Ok(Assembled {
+            program: encoded
+                .into_iter()
+                .flat_map(|instruction| instruction.bytes)
+                .collect(),
+            debug_info,
+            relocations,
+            warnings,
+        })
+    }
+
+    /// Assembles `source`, naming it `file_name` in locations.
+    pub fn assemble(&self, source: &str) -> Result<Assembled, Vec<SourceError>> {
+        self.assemble_named(&self.file_name, source)
+    }
+
+    pub fn assemble_file(&self, path: &Path) -> Result<Assembled, Vec<SourceError>> {
+        self.assemble_named(&path.display().to_string(), &Assembler::read(path)?)
+    }
+
+    /// Assembles `source` into an object for `Linker`. Labels that are used but declared in
+    /// another object must be named by `.import`, and only labels named by `.export` can be
+    /// used from other objects. Warnings are left to `assemble` and `listing` to report.
+    pub fn object(&self, source: &str) -> Result<Object, Vec<SourceError>> {
+        self.object_named(&self.file_name, source)
+    }
+
+    pub fn object_file(&self, path: &Path) -> Result<Object, Vec<SourceError>> {
+        self.object_named(&path.display().to_string(), &Assembler::read(path)?)
+    }
+
+    fn object_named(&self, file: &str, source: &str) -> Result<Object, Vec<SourceError>> {
+        let (encoded, symbols, _) = self.encode(file, source, true)?;
+        let mut object = Object {
+            name: file.to_string(),
+            exports: symbols.exports(),
+            imports: symbols.imports().to_vec(),
+            ..Object::default()
+        };
+        for instruction in encoded {
+            object.code.extend(instruction.bytes);
+            object.relocations.extend(instruction.relocations);
+        }
+        Ok(object)
+    }
+
+    /// Every line of `source` beside the offset and bytes of the code it produced, followed by
+    /// the symbol table.
+    pub fn listing(&self, source: &str) -> Result<String, Vec<SourceError>> {
+        self.listing_named(&self.file_name, source)
+    }
+
+    pub fn listing_file(&self, path: &Path) -> Result<String, Vec<SourceError>> {
+        self.listing_named(&path.display().to_string(), &Assembler::read(path)?)
+    }
+
+    fn listing_named(&self, file: &str, source: &str) -> Result<String, Vec<SourceError>> {
+        let (encoded, symbols, warnings) = self.encode(file, source, false)?;
+        let mut listing = String::new();
+        for (index, text) in source.lines().enumerate() {
+            // Code from a macro or an included file is listed beside the line that brought it in
+            let on_line: Vec<&Encoded> = encoded
+                .iter()
+                .filter(|instruction| instruction.main_line == index + 1)
+                .collect();
+            let offset = on_line
+                .first()
+                .map_or(String::new(), |instruction| instruction.offset.to_string());
+            let bytes: Vec<String> = on_line
+                .iter()
+                .flat_map(|instruction| &instruction.bytes)
+                .map(|byte| format!("{:02X}", byte))
+                .collect();
+            let line = format!(
+                "{:>5}  {:>6}  {:<width$}  {}",
+                index + 1,
+                offset,
+                bytes.join(" "),
+                text,
+                width = LISTING_BYTES_WIDTH
+            );
+            writeln!(listing, "{}", line.trim_end()).unwrap();
+        }
+
+        writeln!(listing, "\nSymbols:").unwrap();
+        for (name, offset) in symbols.labels() {
+            writeln!(listing, "{:>6}  {}", offset, name).unwrap();
+        }
+        for name in symbols.constant_names() {
+            let value = symbols.constant(name).unwrap().evaluate(&symbols).unwrap();
+            writeln!(listing, "{:>6}  {} (.equ)", value, name).unwrap();
+        }
+        if !warnings.is_empty() {
+            writeln!(listing, "\nWarnings:").unwrap();
+            for warning in warnings {
+                writeln!(listing, "{}", warning).unwrap();
+            }
+        }
+        Ok(listing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "load $1 #3
+load $2 @loop
+loop:
+    increment $0
+    jumpifless $0 $1 $2
+";
+
+    #[test]
+    fn test_assemble_with_debug_info() {
+        let mut assembler = Assembler::new("main.iasm");
+        assembler.emit_debug_info = true;
+        let assembled = assembler.assemble(SOURCE).unwrap();
+        assert_eq!(
+            assembled.program,
+            vec![1, 1, 0, 3, 1, 2, 0, 8, 31, 0, 43, 0, 1, 2]
+        );
+        let debug_info = assembled.debug_info.unwrap();
+        assert_eq!(debug_info.labels, vec![(String::from("loop"), 8)]);
+        assert_eq!(
+            debug_info.location(11).unwrap().to_string(),
+            "main.iasm:5:5"
+        );
+        assert_eq!(debug_info.location(8).unwrap().to_string(), "main.iasm:4:5");
+    }
+
+    #[test]
+    fn test_listing() {
+        let listing = Assembler::new("main.iasm").listing(SOURCE).unwrap();
+        assert_eq!(
+            listing,
+            "    1       0  01 01 00 03                    load $1 #3
+    2       4  01 02 00 08                    load $2 @loop
+    3                                         loop:
+    4       8  1F 00                              increment $0
+    5      10  2B 00 01 02                        jumpifless $0 $1 $2
+
+Symbols:
+     8  loop
+"
+        );
+        assert!(Assembler::new("main.iasm").listing("load $1 @x").is_err());
+    }
+
+    #[test]
+    fn test_assemble_macros() {
+        let source = ".macro addconstant target value
+    load $31 %value
+    add %target $31 %target
+.endm
+addconstant $1 #5
+addconstant $1 #7
+";
+        let assembler = Assembler::new("main.iasm");
+        assert_eq!(
+            assembler.assemble(source).unwrap().program,
+            vec![1, 31, 0, 5, 2, 1, 31, 1, 1, 31, 0, 7, 2, 1, 31, 1]
+        );
+        assert!(assembler
+            .listing(source)
+            .unwrap()
+            .contains("    6       8  01 1F 00 07 02 01 1F 01        addconstant $1 #7\n"));
+
+        let errors = assembler
+            .assemble(".macro bad register\n  load %register @nowhere\n.endm\nhalt\n  bad $2\n")
+            .unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "main.iasm:2:3: label nowhere is never declared
+  in the macro expanded at main.iasm:5:3"
+        );
+    }
+
+    #[test]
+    fn test_assemble_file_with_includes() {
+        let directory =
+            std::env::temp_dir().join(format!("esper-assemble-file-{}", std::process::id()));
+        fs::create_dir_all(directory.join("lib")).unwrap();
+        fs::write(
+            directory.join("main.iasm"),
+            "load $1 #2\n.include \"count.iasm\"\n",
+        )
+        .unwrap();
+        fs::write(directory.join("lib/count.iasm"), "  increment $0\n").unwrap();
+
+        let mut assembler = Assembler::new("unused");
+        assembler.emit_debug_info = true;
+        let main = directory.join("main.iasm");
+        let errors = assembler.assemble_file(&main).unwrap_err();
+        assert_eq!(
+            errors[0].error,
+            AssemblerError::IncludeNotFound {
+                path: String::from("count.iasm")
+            }
+        );
+
+        assembler.search_paths.push(directory.join("lib"));
+        let assembled = assembler.assemble_file(&main).unwrap();
+        assert_eq!(assembled.program, vec![1, 1, 0, 2, 31, 0]);
+        let debug_info = assembled.debug_info.unwrap();
+        assert_eq!(
+            debug_info.location(4).unwrap().to_string(),
+            format!("{}:1:3", directory.join("lib/count.iasm").display())
+        );
+        assert_eq!(debug_info.files[0], main.display().to_string());
+        assert!(assembler
+            .listing_file(&main)
+            .unwrap()
+            .contains("    2       4  1F 00"));
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_assemble_register_aliases() {
+        let source = ".alias counter $17
+.alias half $f2
+.macro double r
+    .alias two $f3
+    addf64 %r %r $two
+.endm
+load $counter #3
+add $a0 $counter $a1
+double $half
+";
+        let assembler = Assembler::new("main.iasm");
+        assert_eq!(
+            assembler.assemble(source).unwrap().program,
+            vec![1, 17, 0, 3, 2, 0, 17, 1, 17, 2, 2, 3]
+        );
+
+        let errors = assembler
+            .assemble("load $counter #1\nadd $1 $200 $2\nload $f32 #2\n")
+            .unwrap_err();
+        let messages: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "main.iasm:1:6: register alias $counter is never defined",
+                "main.iasm:2:8: there is no register $200; the VM has 32 of each kind",
+                "main.iasm:3:6: there is no register $f32; the VM has 32 of each kind",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assemble_constants_and_expressions() {
+        let source = ".equ BUF_SIZE 16
+.equ LENGTH @end - @start
+start: load $1 #BUF_SIZE * 4 + 1
+    load $2 #LENGTH
+    load $3 #-(2 * 3) + 10
+end: addimmediate $4 $4 #(BUF_SIZE + 4) / 5
+";
+        let assembler = Assembler::new("main.iasm");
+        let mut vm = crate::vm::VM::new();
+        vm.load_program(assembler.assemble(source).unwrap().program)
+            .unwrap();
+        vm.run().unwrap();
+        assert_eq!(&vm.registers[1..5], &[65, 12, 4, 4]);
+        assert!(assembler
+            .listing(source)
+            .unwrap()
+            .ends_with("Symbols:\n     0  start\n    12  end\n    16  BUF_SIZE (.equ)\n    12  LENGTH (.equ)\n"));
+
+        let errors = assembler
+            .assemble(
+                ".equ A B\n.equ B A + 1\n.equ A 2\nload $1 #C\nload $1 #70000\nload $1 #1 / 0\n\
+                 load $1 #-6\naddimmediate $1 $1 #-1\nload $1 #99999999999999999999\n",
+            )
+            .unwrap_err();
+        let messages: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "main.iasm:1:1: constant A depends on itself",
+                "main.iasm:2:1: constant B depends on itself",
+                "main.iasm:3:1: constant A is already defined",
+                "main.iasm:4:1: constant C is never defined",
+                "main.iasm:5:1: 70000 does not fit in a 16-bit operand",
+                "main.iasm:6:1: division by zero in expression",
+                "main.iasm:7:1: -6 is negative, but this operand is unsigned",
+                "main.iasm:8:1: -1 is negative, but this operand is unsigned",
+                "main.iasm:9:1: 99999999999999999999 does not fit in 64 bits",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assemble_object() {
+        let source = ".import print
+.export main
+main: load $1 @print
+    load $2 @table + 2
+    load $3 @table - @main
+table: halt
+";
+        let object = Assembler::new("main.iasm").object(source).unwrap();
+        assert_eq!(object.code, vec![1, 1, 0, 0, 1, 2, 0, 14, 1, 3, 0, 12, 0]);
+        assert_eq!(object.exports, vec![(String::from("main"), 0)]);
+        assert_eq!(object.imports, vec![String::from("print")]);
+        assert_eq!(
+            object.relocations,
+            vec![
+                Relocation {
+                    offset: 2,
+                    target: Target::Import(String::from("print")),
+                    relative: false,
+                },
+                Relocation {
+                    offset: 6,
+                    target: Target::Local(14),
+                    relative: false,
+                },
+            ]
+        );
+
+        let errors = Assembler::new("main.iasm")
+            .object(".import a\n.export b\nload $1 @a + 1\nc: load $1 @c + @c\n")
+            .unwrap_err();
+        let messages: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "main.iasm:2:1: label b is never declared",
+                "main.iasm:3:1: imported label a can only be used on its own",
+                "main.iasm:4:4: operand cannot be relocated: its labels must cancel out or leave \
+                 one label plus a constant",
+            ]
+        );
+        let errors = Assembler::new("main.iasm").assemble(source).unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "main.iasm:1:1: label print is imported, so the source must be assembled as an \
+             object and linked"
+        );
+    }
+
+    #[test]
+    fn test_assemble_functions() {
+        let source = ".include <call.iasm>
+    load $3 #64
+    allocate $3
+    load $5 #7
+    load $a0 #3
+    call square_plus
+    halt
+.func square_plus uses $5..$6
+    move $a0 $5
+    call square
+    add $a0 $5 $a0
+.endfunc
+.func square
+    multiply $a0 $a0 $a0
+    .return
+    load $a0 #0
+.endfunc
+";
+        let assembled = Assembler::new("main.iasm").assemble(source).unwrap();
+        assert!(assembled.warnings.is_empty());
+        let mut vm = crate::vm::VM::new();
+        vm.load_program(assembled.program).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.registers[0], 12);
+        assert_eq!(vm.registers[5], 7);
+        // Everything pushed was popped again
+        assert_eq!(vm.registers[30], 0);
+
+        let assembled = Assembler::new("main.iasm")
+            .assemble(
+                ".func f uses $5
+    load $5 #1
+    load $6 #1
+    move $a0 $24
+.endfunc
+load $6 #2
+",
+            )
+            .unwrap();
+        let warnings: Vec<String> = assembled
+            .warnings
+            .iter()
+            .map(|warning| warning.to_string())
+            .collect();
+        assert_eq!(
+            warnings,
+            vec!["main.iasm:3:5: function f writes $6, which is not in its uses"]
+        );
+
+        let errors = Assembler::new("main.iasm")
+            .assemble(
+                ".endfunc
+.return
+.func f uses $sp
+.endfunc
+.func g uses $3..$1
+.endfunc
+.func h
+.func i
+",
+            )
+            .unwrap_err();
+        let messages: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "main.iasm:1:1: .endfunc without .func",
+                "main.iasm:2:1: .return outside a function",
+                "main.iasm:3:1: function f cannot save $sp, the stack it saves to",
+                "main.iasm:5:1: expected an instruction",
+                "main.iasm:8:1: functions cannot be defined inside other functions",
+                "main.iasm:7:1: function h has no .endfunc",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assemble_without_debug_info() {
+        let assembled = Assembler::new("main.iasm").assemble("halt").unwrap();
+        assert_eq!(assembled.program, vec![0]);
+        assert_eq!(assembled.debug_info, None);
+    }
+
+    #[test]
+    fn test_assemble_optimized() {
+        let source = "load $1 #2\nload $2 #3\nadd $1 $2 $3\nmultiply $3 $3 $4\nload $1 #2\n\
+                      subtractimmediate $4 $5 #5\nhalt\nload $6 #1\n";
+        let run = |source: &str, optimize: bool| {
+            let mut assembler = Assembler::new("main.iasm");
+            assembler.optimize = optimize;
+            let program = assembler.assemble(source).unwrap().program;
+            let mut vm = crate::vm::VM::new();
+            vm.load_program(program.clone()).unwrap();
+            vm.run().unwrap();
+            (program, vm.registers)
+        };
+        let (plain, plain_registers) = run(source, false);
+        let (optimized, optimized_registers) = run(source, true);
+        assert!(optimized.len() < plain.len());
+        assert_eq!(&optimized_registers[1..6], &[2, 3, 5, 25, 20]);
+        assert_eq!(optimized_registers, plain_registers);
+
+        // A jump to a numbered byte lands somewhere else once code moves, so it is left alone
+        let source = "load $3 #1\nload $3 #1\nload $1 #15\njump $1\nhalt\nload $4 #9\nhalt\n";
+        let (plain, plain_registers) = run(source, false);
+        let (optimized, optimized_registers) = run(source, true);
+        assert_eq!(optimized, plain);
+        assert_eq!(plain_registers[4], 9);
+        assert_eq!(optimized_registers, plain_registers);
+
+        // Code the optimizer would drop is still checked
+        let mut assembler = Assembler::new("main.iasm");
+        assembler.optimize = true;
+        let errors = assembler.assemble("halt\nload $1 #2.5\n").unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "main.iasm:2:1: LOAD takes an integer there"
+        );
+    }
+
+    #[test]
+    fn test_assemble_reports_every_error_with_location() {
+        let errors = Assembler::new("main.iasm")
+            .assemble("a: halt\n  load $1 @b\na: frobnicate $1\n")
+            .unwrap_err();
+        let messages: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "main.iasm:2:3: label b is never declared",
+                "main.iasm:3:1: label a is already declared",
+                "main.iasm:3:4: unknown opcode",
+            ]
+        );
+
+        let errors = Assembler::new("main.iasm").assemble("halt\n%").unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "main.iasm:2:1: expected an instruction"
+        );
+    }
 }
@@ -1,13 +1,15 @@
 use super::Token;
 use crate::instruction::Opcode;
 use nom::{
-    character::complete::{alpha1, space0},
+    character::complete::{alpha1, alphanumeric0, space0},
+    combinator::recognize,
+    sequence::pair,
     IResult,
 };
 
 pub fn opcode(input: &str) -> IResult<&str, Token> {
     let (input, _) = space0(input)?;
-    let (input, opcode) = alpha1(input)?;
+    let (input, opcode) = recognize(pair(alpha1, alphanumeric0))(input)?;
     let (input, _) = space0(input)?;
     Ok((
         input,
@@ -24,11 +26,21 @@ mod tests {
     #[test]
     fn test_parse_opcode() {
         let result = opcode("load");
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (rest, token) = result.unwrap();
         assert_eq!(token, Token::Op { code: Opcode::LOAD });
         assert_eq!(rest, "");
 
+        let result = opcode("loadf64 $f0");
+        let (rest, token) = result.unwrap();
+        assert_eq!(
+            token,
+            Token::Op {
+                code: Opcode::LOADF64
+            }
+        );
+        assert_eq!(rest, "$f0");
+
         let result = opcode("Toad");
         let (_, token) = result.unwrap();
         assert_eq!(
@@ -1,10 +1,13 @@
 use nom::{
+    branch::alt,
     bytes::complete::tag,
-    character::complete::{digit1, space0},
-    sequence::tuple,
+    character::complete::{char, digit1, space0},
+    combinator::{opt, peek, recognize},
+    sequence::{preceded, tuple},
     IResult,
 };
 
+use super::expression_parsers::{expression, Expression};
 use super::Token;
 
 pub fn integer_operand(input: &str) -> IResult<&str, Token> {
@@ -19,19 +22,86 @@ pub fn integer_operand(input: &str) -> IResult<&str, Token> {
     ))
 }
 
+// #1.5, #-0.25 (the decimal point is what tells it apart from an integer operand)
+pub fn float_operand(input: &str) -> IResult<&str, Token> {
+    let (input, _) = space0(input)?;
+    let (input, (_, number)) = tuple((
+        tag("#"),
+        recognize(tuple((opt(char('-')), digit1, char('.'), digit1))),
+    ))(input)?;
+    let (input, _) = space0(input)?;
+    Ok((
+        input,
+        Token::FloatOperand {
+            value: number.parse::<f64>().unwrap(),
+        },
+    ))
+}
+
+// #12, #BUF_SIZE * 4 + 1, @loop, @end - @start (anything an integer operand can be)
+pub fn expression_operand(input: &str) -> IResult<&str, Token> {
+    let (input, _) = space0(input)?;
+    let (input, expression) = alt((
+        preceded(tag("#"), expression),
+        preceded(peek(tag("@")), expression),
+    ))(input)?;
+    let (input, _) = space0(input)?;
+    let token = match expression {
+        Expression::Number(value) if i32::try_from(value).is_ok() => Token::IntegerOperand {
+            value: value as i32,
+        },
+        Expression::Label(name) => Token::LabelUsage { name },
+        expression => Token::Expression { expression },
+    };
+    Ok((input, token))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn test_parse_integer_operand() {
-		let result = integer_operand("#2022");
-        assert_eq!(result.is_ok(), true);
-		let (rest, token) = result.unwrap();
+        let result = integer_operand("#2022");
+        assert!(result.is_ok());
+        let (rest, token) = result.unwrap();
         assert_eq!(rest, "");
         assert_eq!(token, Token::IntegerOperand { value: 2022 });
 
         let result = integer_operand("123");
-        assert_eq!(result.is_err(), true);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_float_operand() {
+        let result = float_operand("#3.25");
+        assert_eq!(result, Ok(("", Token::FloatOperand { value: 3.25 })));
+
+        let result = float_operand("#-0.5 ");
+        assert_eq!(result, Ok(("", Token::FloatOperand { value: -0.5 })));
+
+        let result = float_operand("#12");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_expression_operand() {
+        assert_eq!(
+            expression_operand("#12 "),
+            Ok(("", Token::IntegerOperand { value: 12 }))
+        );
+        assert_eq!(
+            expression_operand("@end"),
+            Ok((
+                "",
+                Token::LabelUsage {
+                    name: String::from("end")
+                }
+            ))
+        );
+        let (rest, token) = expression_operand("#BUF_SIZE * 4 + 1 halt").unwrap();
+        assert_eq!(rest, "halt");
+        assert!(matches!(token, Token::Expression { .. }));
+        assert!(expression_operand("12").is_err());
     }
 }
@@ -0,0 +1,585 @@
+use std::collections::{HashMap, HashSet};
+
+use super::expression_parsers::Expression;
+use super::instruction_parsers::AssemblerInstruction;
+use super::symbols::SymbolTable;
+use super::Token;
+use crate::instruction::Opcode;
+
+/// An instruction and the index of the one it was made from in the list being optimized.
+pub type Numbered = (usize, AssemblerInstruction);
+
+/// What a register is known to hold at some point in straight-line code.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Value {
+    Integer(i32),
+    /// A label's offset, as `LOAD` gives it
+    Absolute(String),
+    /// A label's address, as `LOADRELATIVE` gives it
+    Relative(String),
+}
+
+/// How an instruction changes the integer registers.
+enum Effect {
+    None,
+    /// It writes one register, with a value if it is known
+    Write(u8, Option<Value>),
+    /// It may write any of them
+    Unknown,
+}
+
+fn register_operand(token: &Option<Token>) -> Option<u8> {
+    match token {
+        Some(Token::Register { index }) => Some(*index),
+        _ => None,
+    }
+}
+
+/// The integer operand of `LOAD` and the immediate arithmetic, which the VM reads as 16 bits
+/// unsigned. Others are left for the encoder to reject.
+fn immediate(token: &Option<Token>) -> Option<i32> {
+    let value = match token {
+        Some(Token::IntegerOperand { value }) => *value as i64,
+        Some(Token::Expression { expression }) => expression.evaluate(&SymbolTable::new()).ok()?,
+        _ => return None,
+    };
+    u16::try_from(value).ok().map(i32::from)
+}
+
+fn mentions_label(expression: &Expression) -> bool {
+    match expression {
+        Expression::Label(_) => true,
+        Expression::Number(_) | Expression::TooLarge(_) | Expression::Constant(_) => false,
+        Expression::Negate(operand) => mentions_label(operand),
+        Expression::Binary { left, right, .. } => mentions_label(left) || mentions_label(right),
+    }
+}
+
+fn label_usage(token: &Option<Token>) -> Option<&str> {
+    match token {
+        Some(Token::LabelUsage { name }) => Some(name),
+        _ => None,
+    }
+}
+
+fn declared_label(instruction: &AssemblerInstruction) -> Option<&str> {
+    match &instruction.label {
+        Some(Token::LabelDeclaration { name }) => Some(name),
+        _ => None,
+    }
+}
+
+/// Whether the instruction relies on where code is, in a way that moving code would break:
+/// relative jumps, `LOADRELATIVE` by a number and expressions of label offsets.
+fn depends_on_layout(instruction: &AssemblerInstruction) -> bool {
+    let operands = [
+        &instruction.operand1,
+        &instruction.operand2,
+        &instruction.operand3,
+    ];
+    let measures_code = operands.iter().any(|operand| {
+        matches!(operand, Some(Token::Expression { expression }) if mentions_label(expression))
+    });
+    measures_code
+        || match instruction.code() {
+            Some(
+                Opcode::JUMPFORWARD
+                | Opcode::JUMPBACKWARD
+                | Opcode::JUMPFORWARDIF
+                | Opcode::JUMPFORWARDIFNOT
+                | Opcode::JUMPBACKWARDIF
+                | Opcode::JUMPBACKWARDIFNOT,
+            ) => true,
+            Some(Opcode::LOADRELATIVE) => label_usage(&instruction.operand2).is_none(),
+            _ => false,
+        }
+}
+
+/// The register a jump takes its target from, for the jumps that take one.
+fn jump_target(instruction: &AssemblerInstruction) -> Option<u8> {
+    match instruction.code()? {
+        Opcode::JUMP | Opcode::JUMPIF | Opcode::JUMPIFNOT => {
+            register_operand(&instruction.operand1)
+        }
+        Opcode::JUMPIFEQUAL
+        | Opcode::JUMPIFNOTEQUAL
+        | Opcode::JUMPIFGREATER
+        | Opcode::JUMPIFLESS
+        | Opcode::JUMPIFGREATEREQUAL
+        | Opcode::JUMPIFLESSEQUAL => register_operand(&instruction.operand3),
+        _ => None,
+    }
+}
+
+/// Whether some jump goes through a register that holds a number rather than a code address,
+/// such as `load $1 #15` then `jump $1`, which lands on whatever is at byte 15. What a register
+/// holds is taken from the last instruction before the jump that writes it, following copies:
+/// a label load or a word restored from the heap gives an address, and anything else, or no
+/// write at all, a number.
+fn jumps_to_numbers(instructions: &[Numbered]) -> bool {
+    instructions
+        .iter()
+        .enumerate()
+        .any(|(position, (_, jump))| {
+            let mut register = match jump_target(jump) {
+                Some(register) => register,
+                None => return false,
+            };
+            for (_, instruction) in instructions[..position].iter().rev() {
+                let code = match instruction.code() {
+                    Some(code) => code,
+                    None => continue,
+                };
+                let operands = [
+                    &instruction.operand1,
+                    &instruction.operand2,
+                    &instruction.operand3,
+                ];
+                let written = code
+                    .written_register()
+                    .and_then(|index| register_operand(operands[index]));
+                if written != Some(register) {
+                    continue;
+                }
+                match code {
+                    Opcode::MOVE => match register_operand(&instruction.operand1) {
+                        Some(source) => register = source,
+                        None => return true,
+                    },
+                    Opcode::LOADWORD => return false,
+                    _ => return label_load(instruction).is_none(),
+                }
+            }
+            true
+        })
+}
+
+/// The register a `LOAD` or `LOADRELATIVE` of a label writes, and the label.
+fn label_load(instruction: &AssemblerInstruction) -> Option<(Opcode, u8, &str)> {
+    let code = instruction.code()?;
+    if !matches!(code, Opcode::LOAD | Opcode::LOADRELATIVE) {
+        return None;
+    }
+    let target = register_operand(&instruction.operand1)?;
+    Some((code, target, label_usage(&instruction.operand2)?))
+}
+
+/// Whether execution never goes on to the next instruction.
+fn ends_flow(instruction: &AssemblerInstruction) -> bool {
+    matches!(instruction.code(), Some(Opcode::HALT | Opcode::JUMP))
+}
+
+fn effect(instruction: &AssemblerInstruction, known: &HashMap<u8, Value>) -> Effect {
+    let code = match instruction.code() {
+        Some(code) => code,
+        None => return Effect::Unknown,
+    };
+    let operands = [
+        &instruction.operand1,
+        &instruction.operand2,
+        &instruction.operand3,
+    ];
+    let integer = |index: usize| match register_operand(operands[index]) {
+        Some(register) => match known.get(&register) {
+            Some(Value::Integer(value)) => Some(*value),
+            _ => None,
+        },
+        None => None,
+    };
+    // A result that overflows is left alone, since the VM may be trapping overflows
+    let checked = |(value, overflowed): (i32, bool)| (!overflowed).then_some(Value::Integer(value));
+    let value = match code {
+        Opcode::LOAD => match label_usage(&instruction.operand2) {
+            Some(name) => Some(Value::Absolute(name.to_string())),
+            None => immediate(&instruction.operand2).map(Value::Integer),
+        },
+        Opcode::LOADRELATIVE => {
+            label_usage(&instruction.operand2).map(|name| Value::Relative(name.to_string()))
+        }
+        Opcode::ADD => integer(0)
+            .zip(integer(1))
+            .and_then(|(a, b)| checked(a.overflowing_add(b))),
+        Opcode::SUBTRACT => integer(0)
+            .zip(integer(1))
+            .and_then(|(a, b)| checked(a.overflowing_sub(b))),
+        Opcode::MULTIPLY => integer(0)
+            .zip(integer(1))
+            .and_then(|(a, b)| checked(a.overflowing_mul(b))),
+        Opcode::DIVIDE => integer(0)
+            .zip(integer(1).filter(|b| *b != 0))
+            .and_then(|(a, b)| checked(a.overflowing_div(b))),
+        Opcode::ADDIMMEDIATE => integer(0)
+            .zip(immediate(&instruction.operand3))
+            .and_then(|(a, b)| checked(a.overflowing_add(b))),
+        Opcode::SUBTRACTIMMEDIATE => integer(0)
+            .zip(immediate(&instruction.operand3))
+            .and_then(|(a, b)| checked(a.overflowing_sub(b))),
+        Opcode::INCREMENT => integer(0).and_then(|a| checked(a.overflowing_add(1))),
+        Opcode::DECREMENT => integer(0).and_then(|a| checked(a.overflowing_sub(1))),
+        Opcode::MOVE => register_operand(&instruction.operand1)
+            .and_then(|register| known.get(&register).cloned()),
+        Opcode::HALT
+        | Opcode::JUMP
+        | Opcode::JUMPIF
+        | Opcode::JUMPIFNOT
+        | Opcode::JUMPIFEQUAL
+        | Opcode::JUMPIFNOTEQUAL
+        | Opcode::JUMPIFGREATER
+        | Opcode::JUMPIFLESS
+        | Opcode::JUMPIFGREATEREQUAL
+        | Opcode::JUMPIFLESSEQUAL
+        | Opcode::EQUAL
+        | Opcode::NOTEQUAL
+        | Opcode::GREATER
+        | Opcode::LESS
+        | Opcode::GREATEREQUAL
+        | Opcode::LESSEQUAL
+        | Opcode::LOADF64
+        | Opcode::ADDF64
+        | Opcode::SUBTRACTF64
+        | Opcode::MULTIPLYF64
+        | Opcode::DIVIDEF64
+        | Opcode::EQUALF64
+        | Opcode::NOTEQUALF64
+        | Opcode::GREATERF64
+        | Opcode::LESSF64
+        | Opcode::GREATEREQUALF64
+        | Opcode::LESSEQUALF64
+        | Opcode::INTTOFLOAT
+        | Opcode::ALLOCATE
+        | Opcode::STOREBYTE
+        | Opcode::STOREWORD => return Effect::None,
+        _ => None,
+    };
+    match code
+        .written_register()
+        .and_then(|index| register_operand(operands[index]))
+    {
+        Some(register) => Effect::Write(register, value),
+        None => Effect::Unknown,
+    }
+}
+
+fn load(label: Option<Token>, register: u8, value: i32) -> AssemblerInstruction {
+    AssemblerInstruction {
+        opcode: Token::Op { code: Opcode::LOAD },
+        label,
+        operand1: Some(Token::Register { index: register }),
+        operand2: Some(Token::IntegerOperand { value }),
+        operand3: None,
+    }
+}
+
+/// Works out the registers each instruction writes where the operands are known, replacing
+/// arithmetic on constants by a `LOAD` of the result, and dropping instructions that write a
+/// register the value it already has. What is known is forgotten at every label, since code
+/// may jump there from anywhere.
+fn fold_constants(instructions: Vec<Numbered>) -> Vec<Numbered> {
+    let mut known: HashMap<u8, Value> = HashMap::new();
+    let mut folded = vec![];
+    for (index, mut instruction) in instructions {
+        if instruction.label.is_some() {
+            known.clear();
+        }
+        match effect(&instruction, &known) {
+            Effect::None => {}
+            Effect::Unknown => known.clear(),
+            Effect::Write(register, None) => {
+                known.remove(&register);
+            }
+            Effect::Write(register, Some(value)) => {
+                // `DIVIDE` also sets the remainder
+                let redundant = known.get(&register) == Some(&value)
+                    && instruction.label.is_none()
+                    && instruction.code() != Some(Opcode::DIVIDE);
+                if redundant {
+                    continue;
+                }
+                let foldable = matches!(
+                    instruction.code(),
+                    Some(
+                        Opcode::ADD
+                            | Opcode::SUBTRACT
+                            | Opcode::MULTIPLY
+                            | Opcode::ADDIMMEDIATE
+                            | Opcode::SUBTRACTIMMEDIATE
+                    )
+                );
+                if let Value::Integer(result @ 0..=0xFFFF) = value {
+                    if foldable {
+                        instruction = load(instruction.label, register, result);
+                    }
+                }
+                known.insert(register, value);
+            }
+        }
+        folded.push((index, instruction));
+    }
+    folded
+}
+
+/// Sends jumps to a label that only jumps on straight to where that jump goes.
+///
+/// ```text
+/// loadrelative $29 @first       loadrelative $29 @second
+/// jump $29                      jump $29
+/// ...                     ->    ...
+/// first:                        first:
+/// loadrelative $29 @second      loadrelative $29 @second
+/// jump $29                      jump $29
+/// ```
+///
+/// Either way `$29` holds the address of `second` when it is reached.
+fn thread_jumps(mut instructions: Vec<Numbered>) -> Vec<Numbered> {
+    let mut forwards = HashMap::new();
+    for pair in instructions.windows(2) {
+        let (first, second) = (&pair[0].1, &pair[1].1);
+        if let (Some(label), Some((code, target, next))) =
+            (declared_label(first), label_load(first))
+        {
+            if second.code() == Some(Opcode::JUMP) && jump_target(second) == Some(target) {
+                forwards.insert(label.to_string(), (code, target, next.to_string()));
+            }
+        }
+    }
+    for index in 0..instructions.len().saturating_sub(1) {
+        let (code, target, label) = match label_load(&instructions[index].1) {
+            Some((code, target, label)) => (code, target, label.to_string()),
+            None => continue,
+        };
+        if jump_target(&instructions[index + 1].1) != Some(target) {
+            continue;
+        }
+        let mut destination = label;
+        let mut seen = HashSet::new();
+        while let Some((forward_code, forward_target, next)) = forwards.get(&destination) {
+            if (*forward_code, *forward_target) != (code, target) || !seen.insert(next.clone()) {
+                break;
+            }
+            destination = next.clone();
+        }
+        instructions[index].1.operand2 = Some(Token::LabelUsage { name: destination });
+    }
+    instructions
+}
+
+/// Drops what can never run: whatever follows a `HALT` or a `JUMP` up to the next label that
+/// something refers to, and jumps to the very next instruction.
+fn remove_dead_code(instructions: Vec<Numbered>) -> Vec<Numbered> {
+    let referenced: HashSet<String> = instructions
+        .iter()
+        .flat_map(|(_, instruction)| {
+            [
+                &instruction.operand1,
+                &instruction.operand2,
+                &instruction.operand3,
+            ]
+        })
+        .filter_map(|operand| label_usage(operand).map(str::to_string))
+        .collect();
+    let mut reachable = true;
+    let mut live: Vec<Numbered> = vec![];
+    for (index, instruction) in instructions {
+        let entered = declared_label(&instruction).is_some_and(|name| referenced.contains(name));
+        reachable |= entered;
+        if reachable {
+            reachable = !ends_flow(&instruction);
+            live.push((index, instruction));
+        }
+    }
+
+    let mut kept: Vec<Numbered> = vec![];
+    for (position, (index, instruction)) in live.iter().enumerate() {
+        let to_next = instruction.code() == Some(Opcode::JUMP)
+            && instruction.label.is_none()
+            && kept
+                .last()
+                .and_then(|(_, previous)| label_load(previous))
+                .is_some_and(|(_, target, label)| {
+                    jump_target(instruction) == Some(target)
+                        && live
+                            .get(position + 1)
+                            .and_then(|(_, next)| declared_label(next))
+                            == Some(label)
+                });
+        if !to_next {
+            kept.push((*index, instruction.clone()));
+        }
+    }
+    kept
+}
+
+/// Rewrites a program's instructions into ones that leave the registers and heap as they were
+/// but take fewer steps or bytes, repeating until nothing changes:
+///
+/// - arithmetic on registers loaded with constants becomes a `LOAD` of the result;
+/// - instructions that give a register the value it already holds are dropped;
+/// - jumps to a jump go straight to where that jump goes;
+/// - code that can never run is dropped.
+///
+/// Code must only reach other code through labels. A program that measures or steps over its
+/// own code, with relative jumps, jumps to numbered bytes, `LOADRELATIVE` by a number or label
+/// arithmetic, is returned as it is. Registers holding code addresses will hold different ones, as the code moves.
+pub fn optimize(instructions: Vec<AssemblerInstruction>) -> Vec<AssemblerInstruction> {
+    optimize_numbered(instructions)
+        .into_iter()
+        .map(|(_, instruction)| instruction)
+        .collect()
+}
+
+/// `optimize`, giving beside each instruction kept the index in `instructions` of the one it
+/// was made from.
+pub fn optimize_numbered(instructions: Vec<AssemblerInstruction>) -> Vec<Numbered> {
+    let mut instructions: Vec<Numbered> = instructions.into_iter().enumerate().collect();
+    if jumps_to_numbers(&instructions)
+        || instructions
+            .iter()
+            .any(|(_, instruction)| depends_on_layout(instruction))
+    {
+        return instructions;
+    }
+    loop {
+        let optimized = remove_dead_code(fold_constants(thread_jumps(instructions.clone())));
+        if optimized == instructions {
+            return optimized;
+        }
+        instructions = optimized;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::program_parsers::{program, Program};
+    use crate::vm::VM;
+
+    fn parse(source: &str) -> Vec<AssemblerInstruction> {
+        let (rest, parsed) = program(source).unwrap();
+        assert_eq!(rest, "");
+        parsed.instructions
+    }
+
+    fn run(instructions: Vec<AssemblerInstruction>) -> VM {
+        let bytes = Program { instructions }.to_bytes().unwrap();
+        let mut vm = VM::new();
+        vm.load_program(bytes).unwrap();
+        vm.run().unwrap();
+        vm
+    }
+
+    #[test]
+    fn test_fold_constants() {
+        let optimized = optimize(parse(
+            "load $1 #2\nload $2 #3\nadd $1 $2 $3\nmultiply $3 $3 $4\nsubtractimmediate $4 $5 #5\n\
+             load $6 #300\nmultiply $6 $6 $7\ndivide $7 $2 $8\nhalt",
+        ));
+        // 90000 does not fit in a `LOAD`, and `DIVIDE` sets the remainder too
+        assert_eq!(
+            optimized,
+            parse(
+                "load $1 #2\nload $2 #3\nload $3 #5\nload $4 #25\nload $5 #20\n\
+                 load $6 #300\nmultiply $6 $6 $7\ndivide $7 $2 $8\nhalt"
+            )
+        );
+    }
+
+    #[test]
+    fn test_remove_redundant_loads() {
+        let optimized = optimize(parse(
+            "load $1 #7\nload $2 #7\nmove $1 $2\nload $1 #7\nloadbyte $3 $1\nload $1 #7\n\
+             loop: load $1 #7\nsyscall #0\nload $1 #7\nhalt",
+        ));
+        // `LOADBYTE` writes $1 a value from the heap, and what is known is forgotten at a label
+        // and after a syscall
+        assert_eq!(
+            optimized,
+            parse(
+                "load $1 #7\nload $2 #7\nloadbyte $3 $1\nload $1 #7\nloop: load $1 #7\n\
+                 syscall #0\nload $1 #7\nhalt"
+            )
+        );
+    }
+
+    #[test]
+    fn test_remove_dead_code() {
+        let optimized = optimize(parse(
+            "load $1 @end\njump $1\nload $2 #3\nadd $2 $2 $2\nunused: increment $2\n\
+             end: halt\nhalt",
+        ));
+        assert_eq!(optimized, parse("load $1 @end\nend: halt"));
+    }
+
+    #[test]
+    fn test_thread_jumps() {
+        let optimized = optimize(parse(
+            "loadrelative $29 @first\njumpifequal $1 $2 $29\nhalt\n\
+             first: loadrelative $29 @second\njump $29\n\
+             second: load $3 #1\nhalt",
+        ));
+        // Nothing refers to `first` any more, so it goes too
+        assert_eq!(
+            optimized,
+            parse(
+                "loadrelative $29 @second\njumpifequal $1 $2 $29\nhalt\n\
+                 second: load $3 #1\nhalt"
+            )
+        );
+
+        // Only through the same register, which the jump to `first` relies on being `second`
+        let other_register = parse(
+            "loadrelative $28 @first\njumpifequal $1 $2 $28\nsecond: halt\n\
+             first: loadrelative $29 @second\njump $29",
+        );
+        assert_eq!(optimize(other_register.clone()), other_register);
+    }
+
+    #[test]
+    fn test_layout_dependent_programs_are_left_alone() {
+        for source in [
+            "load $1 #4\njumpforward $1\nload $2 #3\nload $2 #3\nhalt",
+            "loadrelative $1 #4\nload $2 #3\nload $2 #3\nhalt",
+            "load $1 @end - @start\nstart: load $2 #3\nload $2 #3\nend: halt",
+            "load $3 #1\nload $3 #1\nload $1 #15\njump $1\nhalt\nload $4 #9\nhalt",
+            "load $1 #12\nmove $1 $2\nload $3 #1\nload $3 #1\njump $2\nhalt",
+        ] {
+            let instructions = parse(source);
+            assert_eq!(optimize(instructions.clone()), instructions);
+        }
+    }
+
+    #[test]
+    fn test_optimized_programs_run_the_same() {
+        // Each program, and the registers that hold code addresses, which move
+        let programs = [
+            (
+                "load $1 #6\nload $2 #7\nmultiply $1 $2 $3\nsubtractimmediate $3 $3 #2\nhalt",
+                vec![],
+            ),
+            (
+                "load $1 #0\nload $2 #10\nload $3 #0\nloadrelative $29 @loop\n\
+                 loop: add $3 $1 $3\nincrement $1\nload $4 #0\n\
+                 loadrelative $29 @loop\njumpifless $1 $2 $29\n\
+                 loadrelative $29 @done\njump $29\nload $3 #99\ndone: halt",
+                vec![29],
+            ),
+            (
+                "load $1 #5\nloadrelative $ra @back\nloadrelative $29 @square\njump $29\n\
+                 back: move $1 $5\nload $6 #1\nadd $6 $6 $7\nhalt\n\
+                 square: loadrelative $28 @squaring\njump $28\n\
+                 squaring: multiply $1 $1 $1\njump $ra",
+                vec![28, 29, 31],
+            ),
+        ];
+        for (source, addresses) in programs {
+            let instructions = parse(source);
+            let optimized = optimize(instructions.clone());
+            assert_ne!(optimized, instructions, "{}", source);
+            let (mut before, mut after) = (run(instructions).registers, run(optimized).registers);
+            for register in addresses {
+                before[register] = 0;
+                after[register] = 0;
+            }
+            assert_eq!(before, after, "{}", source);
+        }
+    }
+}
@@ -0,0 +1,866 @@
+use std::collections::HashMap;
+use std::fs;
+use std::iter;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use nom::branch::alt;
+use nom::combinator::all_consuming;
+
+use super::label_parsers::{identifier, label_declaration};
+use super::register_parsers::{float_register, is_numbered, register, resolve, BUILTIN_REGISTERS};
+use super::{AssemblerError, SourceError, Token};
+use crate::debug_info::Location;
+use crate::instruction::Opcode;
+use crate::stdlib;
+
+/// How deeply macros may invoke other macros, which also stops runaway recursion.
+pub const MAX_MACRO_DEPTH: usize = 16;
+
+/// Where `.func` keeps the address a function returns to, which it saves like the registers the
+/// function uses.
+pub const RETURN_ADDRESS: u8 = 31;
+/// Where `.func` keeps the address of the next free byte of the stack it saves registers to.
+pub const STACK_POINTER: u8 = 30;
+
+/// A `.func` block: the registers it saves on entry and restores on return, besides `$ra`, in
+/// the order they are saved.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Function {
+    pub name: String,
+    pub uses: Vec<u8>,
+}
+
+/// A line of source after preprocessing and where it came from. `expansion` lists the macro
+/// invocations that produced it and `included_from` the `.include` lines that led to the file
+/// it was written in (or, for a line from a macro, to the outermost invocation), both outermost
+/// first and both empty for lines written directly in the main file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExpandedLine {
+    pub text: String,
+    pub file: String,
+    pub line: usize,
+    pub expansion: Vec<Location>,
+    pub included_from: Vec<Location>,
+    /// The register aliases in scope on the line, by name
+    pub aliases: Rc<HashMap<String, Token>>,
+    /// The function whose body the line is in, if any; not set on the code `.func` generates
+    pub function: Option<Rc<Function>>,
+}
+
+struct Macro {
+    parameters: Vec<String>,
+    /// The file the body was written in
+    file: String,
+    /// Line numbers and text
+    body: Vec<(usize, String)>,
+    /// Labels declared in the body, renamed in every expansion so each gets its own
+    locals: Vec<String>,
+}
+
+fn is_identifier_character(character: char) -> bool {
+    character.is_ascii_alphanumeric() || character == '_'
+}
+
+/// `text` with `%parameter` replaced by its argument and each local label renamed to `rename`
+/// of it, wherever it is declared (`name:`) or used (`@name`).
+fn substitute(
+    text: &str,
+    arguments: &HashMap<&str, &str>,
+    locals: &[String],
+    rename: impl Fn(&str) -> String,
+) -> String {
+    let mut result = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(|character: char| is_identifier_character(character)) {
+        let end = rest[start..]
+            .find(|character: char| !is_identifier_character(character))
+            .map_or(rest.len(), |length| start + length);
+        let (before, word, after) = (&rest[..start], &rest[start..end], &rest[end..]);
+        result.push_str(before);
+        if before.ends_with('%') && arguments.contains_key(word) {
+            result.pop();
+            result.push_str(arguments[word]);
+        } else if locals.iter().any(|local| local == word)
+            && (before.ends_with('@') || (before.trim().is_empty() && after.starts_with(':')))
+        {
+            result.push_str(&rename(word));
+        } else {
+            result.push_str(word);
+        }
+        rest = after;
+    }
+    result.push_str(rest);
+    result
+}
+
+fn column_of(text: &str) -> usize {
+    text.len() - text.trim_start().len() + 1
+}
+
+struct Expander<'a> {
+    search_paths: &'a [PathBuf],
+    macros: HashMap<String, Macro>,
+    expansions: usize,
+    /// The `.include` lines of the files being read, outermost first
+    includes: Vec<Location>,
+    /// The files being read, outermost first, for spotting include cycles
+    open_files: Vec<PathBuf>,
+    /// The aliases of the file or macro expansion being read; shared with the lines already
+    /// emitted until another alias is defined
+    aliases: Rc<HashMap<String, Token>>,
+    /// The `.func` block of the file being read, while inside one
+    function: Option<Rc<Function>>,
+    lines: Vec<ExpandedLine>,
+    errors: Vec<SourceError>,
+}
+
+impl Expander<'_> {
+    fn error(
+        &mut self,
+        file: &str,
+        line: usize,
+        text: &str,
+        expansion: &[Location],
+        error: AssemblerError,
+    ) {
+        self.errors.push(SourceError {
+            location: Location {
+                file: file.to_string(),
+                line,
+                column: column_of(text),
+            },
+            error,
+            expansion: expansion.to_vec(),
+            included_from: self.includes.clone(),
+        });
+    }
+
+    fn push_line(&mut self, file: &str, line: usize, text: &str, expansion: &[Location]) {
+        self.lines.push(ExpandedLine {
+            text: text.to_string(),
+            file: file.to_string(),
+            line,
+            expansion: expansion.to_vec(),
+            included_from: self.includes.clone(),
+            aliases: Rc::clone(&self.aliases),
+            function: self.function.clone(),
+        });
+    }
+
+    /// Handles `.alias name $register`, which lasts until the end of the file or macro.
+    fn alias(&mut self, file: &str, line: usize, text: &str, expansion: &[Location]) {
+        let words: Vec<&str> = text.split_whitespace().skip(1).collect();
+        let (name, target) = match words[..] {
+            [name, target] if all_consuming(identifier)(name).is_ok() => (name, target),
+            _ => return self.error(file, line, text, expansion, AssemblerError::UnexpectedInput),
+        };
+        let register = match all_consuming(alt((float_register, register)))(target) {
+            Ok((_, Token::RegisterName { name })) => resolve(&name, &self.aliases),
+            Ok((_, register)) => Ok(register),
+            Err(_) => Err(AssemblerError::UnexpectedInput),
+        };
+        let error = match register {
+            Err(error) => error,
+            Ok(_) if is_numbered(name) || BUILTIN_REGISTERS.iter().any(|(n, _)| *n == name) => {
+                AssemblerError::AliasShadowsRegister {
+                    name: name.to_string(),
+                }
+            }
+            Ok(_) if self.aliases.contains_key(name) => AssemblerError::DuplicateAlias {
+                name: name.to_string(),
+            },
+            Ok(register) => {
+                Rc::make_mut(&mut self.aliases).insert(name.to_string(), register);
+                return;
+            }
+        };
+        self.error(file, line, text, expansion, error);
+    }
+
+    /// The integer register `$name` stands for, given the aliases in scope.
+    fn integer_register(&self, text: &str) -> Result<u8, AssemblerError> {
+        match all_consuming(register)(text) {
+            Ok((_, Token::Register { index })) => Ok(index),
+            Ok((_, Token::RegisterName { name })) => match resolve(&name, &self.aliases)? {
+                Token::Register { index } => Ok(index),
+                _ => Err(AssemblerError::UnexpectedInput),
+            },
+            _ => Err(AssemblerError::UnexpectedInput),
+        }
+    }
+
+    /// Reads `.func name uses $a..$b $c ...`, where the `uses` list is optional.
+    fn function(&self, text: &str) -> Result<Function, AssemblerError> {
+        let mut words = text.split_whitespace().skip(1);
+        let name = match words.next() {
+            Some(name) if all_consuming(identifier)(name).is_ok() => name.to_string(),
+            _ => return Err(AssemblerError::UnexpectedInput),
+        };
+        let mut uses = vec![];
+        match words.next() {
+            Some("uses") => {}
+            Some(_) => return Err(AssemblerError::UnexpectedInput),
+            None => return Ok(Function { name, uses }),
+        }
+        let mut empty = true;
+        for word in words {
+            empty = false;
+            let (first, last) = match word.split_once("..") {
+                Some((first, last)) => {
+                    (self.integer_register(first)?, self.integer_register(last)?)
+                }
+                None => {
+                    let register = self.integer_register(word)?;
+                    (register, register)
+                }
+            };
+            if first > last {
+                return Err(AssemblerError::UnexpectedInput);
+            }
+            for register in first..=last {
+                if register == STACK_POINTER {
+                    return Err(AssemblerError::FunctionUsesStackPointer { name });
+                }
+                // The return address is always saved
+                if register != RETURN_ADDRESS && !uses.contains(&register) {
+                    uses.push(register);
+                }
+            }
+        }
+        if empty {
+            return Err(AssemblerError::UnexpectedInput);
+        }
+        Ok(Function { name, uses })
+    }
+
+    /// Emits the code `.func` stands for: the function's label, then pushes of `$ra` and the
+    /// registers it uses onto the stack at `$sp`.
+    fn enter(&mut self, file: &str, line: usize, function: &Function) {
+        self.push_line(file, line, &format!("{}:", function.name), &[]);
+        for register in iter::once(RETURN_ADDRESS).chain(function.uses.iter().copied()) {
+            let store = format!("    storeword ${} ${}", register, STACK_POINTER);
+            self.push_line(file, line, &store, &[]);
+            let advance = format!("    addimmediate ${0} ${0} #4", STACK_POINTER);
+            self.push_line(file, line, &advance, &[]);
+        }
+    }
+
+    /// Emits the code `.return` and `.endfunc` stand for: pops of everything `.func` pushed, in
+    /// reverse, then a jump back to the caller.
+    fn leave(&mut self, file: &str, line: usize, text: &str, expansion: &[Location]) {
+        let function = match self.function.take() {
+            Some(function) => function,
+            None => {
+                let error = AssemblerError::ReturnOutsideFunction;
+                return self.error(file, line, text, expansion, error);
+            }
+        };
+        for register in iter::once(RETURN_ADDRESS)
+            .chain(function.uses.iter().copied())
+            .rev()
+        {
+            let retreat = format!("    subtractimmediate ${0} ${0} #4", STACK_POINTER);
+            self.push_line(file, line, &retreat, expansion);
+            let load = format!("    loadword ${} ${}", STACK_POINTER, register);
+            self.push_line(file, line, &load, expansion);
+        }
+        let jump = format!("    jump ${}", RETURN_ADDRESS);
+        self.push_line(file, line, &jump, expansion);
+        self.function = Some(function);
+    }
+
+    /// A macro argument as the body should see it: an alias from the invoking scope is replaced
+    /// by its register, since the body has aliases of its own.
+    fn argument(&self, argument: &str) -> String {
+        let alias = argument
+            .strip_prefix('$')
+            .and_then(|name| self.aliases.get(name));
+        match alias {
+            Some(Token::FloatRegister { index }) => format!("$f{}", index),
+            Some(Token::Register { index }) => format!("${}", index),
+            _ => argument.to_string(),
+        }
+    }
+
+    /// Adds `text` to the output, expanding it first if it invokes a macro.
+    fn emit(&mut self, file: &str, line: usize, text: &str, expansion: &[Location], depth: usize) {
+        if text.split_whitespace().next() == Some(".alias") {
+            return self.alias(file, line, text, expansion);
+        }
+        let (label, rest) = match label_declaration(text) {
+            Ok((rest, _)) => (Some(&text[..text.len() - rest.len()]), rest),
+            Err(_) => (None, text),
+        };
+        if rest.split_whitespace().next() == Some(".return") {
+            if let Some(label) = label {
+                self.push_line(file, line, label.trim_end(), expansion);
+            }
+            return self.leave(file, line, text, expansion);
+        }
+        let mut words = rest.split_whitespace();
+        let name = match words.next() {
+            Some(name) if self.macros.contains_key(name) => name,
+            _ => return self.push_line(file, line, text, expansion),
+        };
+        if depth == MAX_MACRO_DEPTH {
+            let error = AssemblerError::MacroTooDeep {
+                name: name.to_string(),
+            };
+            return self.error(file, line, text, expansion, error);
+        }
+        let arguments: Vec<String> = words.map(|word| self.argument(word)).collect();
+        let definition = &self.macros[name];
+        if arguments.len() != definition.parameters.len() {
+            let error = AssemblerError::MacroArguments {
+                name: name.to_string(),
+                expected: definition.parameters.len(),
+                found: arguments.len(),
+            };
+            return self.error(file, line, text, expansion, error);
+        }
+        let number = self.expansions + 1;
+        let arguments: HashMap<&str, &str> = definition
+            .parameters
+            .iter()
+            .map(String::as_str)
+            .zip(arguments.iter().map(String::as_str))
+            .collect();
+        let rename = |local: &str| format!("{}__{}_{}", name, number, local);
+        let body: Vec<(usize, String)> = definition
+            .body
+            .iter()
+            .map(|(body_line, body_text)| {
+                let text = substitute(body_text, &arguments, &definition.locals, rename);
+                (*body_line, text)
+            })
+            .collect();
+        let body_file = definition.file.clone();
+        self.expansions = number;
+
+        if let Some(label) = label {
+            self.push_line(file, line, label.trim_end(), expansion);
+        }
+        let mut site = expansion.to_vec();
+        site.push(Location {
+            file: file.to_string(),
+            line,
+            column: column_of(text),
+        });
+        let outer = mem::take(&mut self.aliases);
+        for (body_line, body_text) in body {
+            self.emit(&body_file, body_line, &body_text, &site, depth + 1);
+        }
+        self.aliases = outer;
+    }
+
+    fn define(&mut self, line: usize, text: &str, name: &str, definition: Macro) {
+        let error = if self.macros.contains_key(name) {
+            AssemblerError::DuplicateMacro {
+                name: name.to_string(),
+            }
+        } else if Opcode::from(name) != Opcode::ILLEGAL {
+            AssemblerError::MacroShadowsOpcode {
+                name: name.to_string(),
+            }
+        } else {
+            self.macros.insert(name.to_string(), definition);
+            return;
+        };
+        let file = definition.file;
+        self.error(&file, line, text, &[], error);
+    }
+
+    /// Reads the file named by `.include "path"`, looking beside `file` (the including file)
+    /// first and then in each search path, or the bundled file named by `.include <name>`.
+    fn include(&mut self, file: &str, line: usize, text: &str, argument: &str) {
+        if let Some(name) = argument
+            .strip_prefix('<')
+            .and_then(|name| name.strip_suffix('>'))
+        {
+            return match stdlib::file(name) {
+                Some(source) => self.expand_included(file, line, text, argument, argument, source),
+                None => {
+                    let error = AssemblerError::IncludeNotFound {
+                        path: argument.to_string(),
+                    };
+                    self.error(file, line, text, &[], error)
+                }
+            };
+        }
+        let path = match argument
+            .strip_prefix('"')
+            .and_then(|path| path.strip_suffix('"'))
+        {
+            Some(path) => path,
+            None => return self.error(file, line, text, &[], AssemblerError::UnexpectedInput),
+        };
+        let beside = Path::new(file).parent().unwrap_or(Path::new("")).join(path);
+        let found = iter::once(beside)
+            .chain(
+                self.search_paths
+                    .iter()
+                    .map(|directory| directory.join(path)),
+            )
+            .find(|candidate| candidate.is_file());
+        let found = match found {
+            Some(found) => found,
+            None => {
+                let error = AssemblerError::IncludeNotFound {
+                    path: path.to_string(),
+                };
+                return self.error(file, line, text, &[], error);
+            }
+        };
+        let source = match fs::read_to_string(&found) {
+            Ok(source) => source,
+            Err(error) => {
+                let error = AssemblerError::CannotRead {
+                    path: path.to_string(),
+                    message: error.to_string(),
+                };
+                return self.error(file, line, text, &[], error);
+            }
+        };
+        let name = found.display().to_string();
+        self.expand_included(file, line, text, path, &name, &source);
+    }
+
+    /// Expands `source`, which `file` includes as `path` and which is known as `name`, unless
+    /// it is already being read.
+    fn expand_included(
+        &mut self,
+        file: &str,
+        line: usize,
+        text: &str,
+        path: &str,
+        name: &str,
+        source: &str,
+    ) {
+        let canonical = fs::canonicalize(name).unwrap_or_else(|_| PathBuf::from(name));
+        if self.open_files.contains(&canonical) {
+            let error = AssemblerError::IncludeCycle {
+                path: path.to_string(),
+            };
+            return self.error(file, line, text, &[], error);
+        }
+        self.includes.push(Location {
+            file: file.to_string(),
+            line,
+            column: column_of(text),
+        });
+        self.open_files.push(canonical);
+        self.expand_file(name, source);
+        self.open_files.pop();
+        self.includes.pop();
+    }
+
+    fn expand_file(&mut self, file: &str, source: &str) {
+        let outer = mem::take(&mut self.aliases);
+        let outer_function = self.function.take();
+        // The definition being read: its line, text, name and contents so far
+        let mut defining: Option<(usize, &str, String, Macro)> = None;
+        // The line and text of the `.func` whose body is being read
+        let mut function_start: Option<(usize, &str)> = None;
+        for (index, text) in source.lines().enumerate() {
+            let line = index + 1;
+            let mut words = text.split_whitespace();
+            match words.next() {
+                Some(".macro") => {
+                    if defining.is_some() {
+                        let error = AssemblerError::NestedMacroDefinition;
+                        self.error(file, line, text, &[], error);
+                        continue;
+                    }
+                    let name = match words.next() {
+                        Some(name) => name.to_string(),
+                        None => {
+                            self.error(file, line, text, &[], AssemblerError::UnexpectedInput);
+                            continue;
+                        }
+                    };
+                    let definition = Macro {
+                        parameters: words.map(str::to_string).collect(),
+                        file: file.to_string(),
+                        body: vec![],
+                        locals: vec![],
+                    };
+                    defining = Some((line, text, name, definition));
+                }
+                Some(".endm") => match defining.take() {
+                    Some((line, text, name, definition)) => {
+                        self.define(line, text, &name, definition)
+                    }
+                    None => self.error(file, line, text, &[], AssemblerError::UnexpectedEndm),
+                },
+                _ if defining.is_some() => {
+                    let (_, _, _, definition) = defining.as_mut().unwrap();
+                    if let Ok((_, Token::LabelDeclaration { name })) = label_declaration(text) {
+                        definition.locals.push(name);
+                    }
+                    definition.body.push((line, text.to_string()));
+                }
+                Some(".include") => {
+                    let argument = text.trim_start()[".include".len()..].trim();
+                    self.include(file, line, text, argument);
+                }
+                Some(".func") if function_start.is_some() => {
+                    self.error(file, line, text, &[], AssemblerError::NestedFunction);
+                }
+                Some(".func") => {
+                    match self.function(text) {
+                        Ok(function) => {
+                            self.enter(file, line, &function);
+                            self.function = Some(Rc::new(function));
+                        }
+                        Err(error) => self.error(file, line, text, &[], error),
+                    }
+                    // Even a function that could not be read is ended by its `.endfunc`
+                    function_start = Some((line, text));
+                }
+                Some(".endfunc") if function_start.is_some() => {
+                    if self.function.is_some() {
+                        self.leave(file, line, text, &[]);
+                    }
+                    self.function = None;
+                    function_start = None;
+                }
+                Some(".endfunc") => {
+                    self.error(file, line, text, &[], AssemblerError::UnexpectedEndfunc)
+                }
+                _ => self.emit(file, line, text, &[], 0),
+            }
+        }
+        if let Some((line, text, name, _)) = defining {
+            let error = AssemblerError::UnterminatedMacro { name };
+            self.error(file, line, text, &[], error);
+        }
+        if let (Some((line, text)), Some(function)) = (function_start, self.function.take()) {
+            let error = AssemblerError::UnterminatedFunction {
+                name: function.name.clone(),
+            };
+            self.error(file, line, text, &[], error);
+        }
+        self.aliases = outer;
+        self.function = outer_function;
+    }
+}
+
+/// Prepares `source`, which was read from `file`, for parsing. `.include "path"` is replaced by
+/// the lines of that file, found beside the including file or else in one of `search_paths`.
+/// `.macro name parameters ... .endm` definitions are collected and every invocation is replaced
+/// by the macro's body. Parameters are written `%name` in the body, and labels declared in the
+/// body are renamed in each expansion so that they do not collide. `.alias name $register` lets
+/// `$name` stand for the register in the rest of the file or macro body it is written in.
+/// `.func name uses $a..$b` starts a function that saves `$ra` and the registers it uses on
+/// entry and restores them at `.return` and at its `.endfunc`, before jumping back to `$ra`.
+/// They are saved 4 bytes each to a stack growing up from the heap address in `$sp`, which is 0
+/// until the program sets it, so the program must allocate room for it first.
+pub fn expand(
+    file: &str,
+    source: &str,
+    search_paths: &[PathBuf],
+) -> Result<Vec<ExpandedLine>, Vec<SourceError>> {
+    let mut expander = Expander {
+        search_paths,
+        macros: HashMap::new(),
+        expansions: 0,
+        includes: vec![],
+        open_files: fs::canonicalize(file).into_iter().collect(),
+        aliases: Rc::default(),
+        function: None,
+        lines: vec![],
+        errors: vec![],
+    };
+    expander.expand_file(file, source);
+    if expander.errors.is_empty() {
+        Ok(expander.lines)
+    } else {
+        Err(expander.errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(lines: &[ExpandedLine]) -> Vec<&str> {
+        lines.iter().map(|line| line.text.as_str()).collect()
+    }
+
+    #[test]
+    fn test_expand_substitutes_parameters() {
+        let source = ".macro addconstant target value
+    load $31 %value
+    add %target $31 %target
+.endm
+start: addconstant $1 #5
+halt";
+        let lines = expand("main.iasm", source, &[]).unwrap();
+        assert_eq!(
+            texts(&lines),
+            vec!["start:", "    load $31 #5", "    add $1 $31 $1", "halt"]
+        );
+        assert_eq!(lines[1].line, 2);
+        assert_eq!(lines[1].expansion[0].to_string(), "main.iasm:5:1");
+        assert!(lines[3].expansion.is_empty());
+    }
+
+    #[test]
+    fn test_expand_renames_local_labels() {
+        let source = ".macro countto limit
+    load $2 @again
+again: increment $0
+    jumpifless $0 %limit $2
+.endm
+countto $1
+countto $3";
+        let lines = expand("main.iasm", source, &[]).unwrap();
+        assert_eq!(
+            texts(&lines),
+            vec![
+                "    load $2 @countto__1_again",
+                "countto__1_again: increment $0",
+                "    jumpifless $0 $1 $2",
+                "    load $2 @countto__2_again",
+                "countto__2_again: increment $0",
+                "    jumpifless $0 $3 $2",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expand_nested_macros() {
+        let source = ".macro twice what
+    %what
+    %what
+.endm
+.macro four
+    twice increment
+.endm
+four";
+        let lines = expand("main.iasm", source, &[]).unwrap();
+        assert_eq!(texts(&lines), vec!["    increment", "    increment"]);
+        assert_eq!(lines[0].expansion.len(), 2);
+        assert_eq!(lines[0].line, 2);
+    }
+
+    #[test]
+    fn test_expand_errors() {
+        let errors = expand("main.iasm", ".macro loop\n    loop\n.endm\nloop", &[]).unwrap_err();
+        assert_eq!(
+            errors[0].error,
+            AssemblerError::MacroTooDeep {
+                name: String::from("loop")
+            }
+        );
+        assert_eq!(errors[0].location.line, 2);
+        assert_eq!(errors[0].expansion.len(), MAX_MACRO_DEPTH);
+
+        let errors = expand(
+            "main.iasm",
+            ".macro load\n.endm\n.macro one a\n.endm\n  one\n.endm\n.macro open",
+            &[],
+        )
+        .unwrap_err();
+        let messages: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "main.iasm:1:1: macro load has the name of an opcode",
+                "main.iasm:5:3: macro one takes 1 argument(s), not 0",
+                "main.iasm:6:1: .endm without .macro",
+                "main.iasm:7:1: macro open has no .endm",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expand_functions() {
+        let source = ".alias total $7
+.func sum uses $5..$total $12
+    done: .return
+.endfunc";
+        let lines = expand("main.iasm", source, &[]).unwrap();
+        let restore = [
+            "    subtractimmediate $30 $30 #4",
+            "    loadword $30 $12",
+            "    subtractimmediate $30 $30 #4",
+            "    loadword $30 $7",
+            "    subtractimmediate $30 $30 #4",
+            "    loadword $30 $6",
+            "    subtractimmediate $30 $30 #4",
+            "    loadword $30 $5",
+            "    subtractimmediate $30 $30 #4",
+            "    loadword $30 $31",
+            "    jump $31",
+        ];
+        let mut expected = vec![
+            "sum:",
+            "    storeword $31 $30",
+            "    addimmediate $30 $30 #4",
+            "    storeword $5 $30",
+            "    addimmediate $30 $30 #4",
+            "    storeword $6 $30",
+            "    addimmediate $30 $30 #4",
+            "    storeword $7 $30",
+            "    addimmediate $30 $30 #4",
+            "    storeword $12 $30",
+            "    addimmediate $30 $30 #4",
+            "    done:",
+        ];
+        // Once for the `.return`, and again for the `.endfunc`
+        expected.extend(restore);
+        expected.extend(restore);
+        assert_eq!(texts(&lines), expected);
+        let function = lines[11].function.as_ref().unwrap();
+        assert_eq!(function.name, "sum");
+        assert_eq!(function.uses, vec![5, 6, 7, 12]);
+        assert_eq!(lines[12].function, None);
+        assert_eq!(lines[1].function, None);
+    }
+
+    /// A fresh directory under the system temporary directory holding `files`.
+    fn directory_with(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("esper-include-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        for (path, contents) in files {
+            let path = directory.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        directory
+    }
+
+    #[test]
+    fn test_expand_scopes_aliases() {
+        let source = ".alias counter $17
+.macro bump r
+    .alias step $3
+    add %r $step %r
+.endm
+bump $counter
+load $counter #1
+.alias counter $18
+.alias a0 $4
+.alias f3 $5
+.alias total $f40";
+        let result = expand("main.iasm", source, &[]);
+        let messages: Vec<String> = result
+            .unwrap_err()
+            .iter()
+            .map(|error| error.to_string())
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                "main.iasm:8:1: register alias $counter is already defined",
+                "main.iasm:9:1: register alias $a0 has the name of a register",
+                "main.iasm:10:1: register alias $f3 has the name of a register",
+                "main.iasm:11:1: there is no register $f40; the VM has 32 of each kind",
+            ]
+        );
+
+        let lines = expand(
+            "main.iasm",
+            &source[..source.find("\n.alias counter $18").unwrap()],
+            &[],
+        )
+        .unwrap();
+        assert_eq!(
+            texts(&lines),
+            vec!["    add $17 $step $17", "load $counter #1"]
+        );
+        assert_eq!(
+            lines[0].aliases.get("step"),
+            Some(&Token::Register { index: 3 })
+        );
+        assert_eq!(lines[0].aliases.get("counter"), None);
+        assert_eq!(
+            lines[1].aliases.get("counter"),
+            Some(&Token::Register { index: 17 })
+        );
+        assert_eq!(lines[1].aliases.get("step"), None);
+    }
+
+    #[test]
+    fn test_expand_includes() {
+        let directory = directory_with(
+            "nested",
+            &[
+                (
+                    "src/main.iasm",
+                    ".include \"lib/inc.iasm\"\ninc2 $1\n.include \"halt.iasm\"\n",
+                ),
+                (
+                    "src/lib/inc.iasm",
+                    ".macro inc2 r\n  increment %r\n  increment %r\n.endm\n",
+                ),
+                ("shared/halt.iasm", "halt\n"),
+            ],
+        );
+        let main = directory.join("src/main.iasm");
+        let main_name = main.display().to_string();
+        let lines = expand(
+            &main_name,
+            &fs::read_to_string(&main).unwrap(),
+            &[directory.join("shared")],
+        )
+        .unwrap();
+        assert_eq!(
+            texts(&lines),
+            vec!["  increment $1", "  increment $1", "halt"]
+        );
+        assert_eq!(
+            lines[0].file,
+            directory.join("src/lib/inc.iasm").display().to_string()
+        );
+        assert_eq!(lines[0].line, 2);
+        assert!(lines[0].included_from.is_empty());
+        assert_eq!(lines[0].expansion[0].line, 2);
+        assert_eq!(
+            lines[2].file,
+            directory.join("shared/halt.iasm").display().to_string()
+        );
+        assert_eq!(
+            lines[2].included_from[0].to_string(),
+            format!("{}:3:1", main_name)
+        );
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_expand_include_errors() {
+        let directory = directory_with(
+            "cycle",
+            &[
+                ("a.iasm", "halt\n.include \"b.iasm\"\n"),
+                (
+                    "b.iasm",
+                    "  .include \"a.iasm\"\n.include \"missing.iasm\"\n",
+                ),
+            ],
+        );
+        let a = directory.join("a.iasm");
+        let a_name = a.display().to_string();
+        let errors = expand(&a_name, &fs::read_to_string(&a).unwrap(), &[]).unwrap_err();
+        let b_name = directory.join("b.iasm").display().to_string();
+        let messages: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                format!(
+                    "{}:1:3: a.iasm includes itself\n  in the file included from {}:2:1",
+                    b_name, a_name
+                ),
+                format!(
+                    "{}:2:1: cannot find missing.iasm to include\n  in the file included from {}:2:1",
+                    b_name, a_name
+                ),
+            ]
+        );
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -1,18 +1,34 @@
 use super::instruction_parsers::*;
+use super::symbols::SymbolTable;
+use super::{AssemblerError, Token};
 use nom::{multi::many1, IResult};
 
 #[derive(Debug, PartialEq)]
 pub struct Program {
-    instructions: Vec<AssemblerInstruction>,
+    pub instructions: Vec<AssemblerInstruction>,
 }
 
 impl Program {
-    pub fn to_bytes(&self) -> Vec<u8> {
+    /// The offset of every label declared in the program.
+    pub fn symbols(&self) -> Result<SymbolTable, AssemblerError> {
+        let mut symbols = SymbolTable::new();
+        let mut offset = 0;
+        for instruction in &self.instructions {
+            if let Some(Token::LabelDeclaration { name }) = &instruction.label {
+                symbols.define(name, offset)?;
+            }
+            offset += instruction.width();
+        }
+        Ok(symbols)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, AssemblerError> {
+        let symbols = self.symbols()?;
         let mut program = vec![];
         for instruction in &self.instructions {
-            program.append(&mut instruction.to_bytes());
+            program.append(&mut instruction.to_bytes(&symbols, program.len())?);
         }
-        program
+        Ok(program)
     }
 }
 
@@ -37,12 +53,14 @@ mod tests {
                     instructions: vec![
                         AssemblerInstruction {
                             opcode: Token::Op { code: Opcode::LOAD },
+                            label: None,
                             operand1: Some(Token::Register { index: 1 }),
                             operand2: Some(Token::IntegerOperand { value: 2 }),
                             operand3: None,
                         },
                         AssemblerInstruction {
                             opcode: Token::Op { code: Opcode::LOAD },
+                            label: None,
                             operand1: Some(Token::Register { index: 2 }),
                             operand2: Some(Token::IntegerOperand { value: 1 }),
                             operand3: None,
@@ -56,10 +74,66 @@ mod tests {
     #[test]
     fn test_program_to_bytes() {
         let result = program("load $1 #2");
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (_, program) = result.unwrap();
-        let bytecode = program.to_bytes();
+        let bytecode = program.to_bytes().unwrap();
         assert_eq!(bytecode.len(), 4);
         println!("{:?}", bytecode);
     }
+
+    #[test]
+    fn test_program_with_mixed_shapes_to_bytes() {
+        let result = program("load $1 #2 add $1 $1 $2 equal $1 $2 jump $0 halt");
+        let (rest, program) = result.unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(
+            program.to_bytes(),
+            Ok(vec![1, 1, 0, 2, 2, 1, 1, 2, 9, 1, 2, 6, 0, 0])
+        );
+    }
+
+    #[test]
+    fn test_conditional_jumps_to_bytes() {
+        let (_, program) = program("jumpifnot $1 jumpbackwardif $2 jumpifless $0 $1 $2").unwrap();
+        assert_eq!(program.to_bytes(), Ok(vec![35, 1, 38, 2, 43, 0, 1, 2]));
+    }
+
+    #[test]
+    fn test_memory_and_syscalls_to_bytes() {
+        let (_, program) =
+            program("allocate $0 storebyte $1 $2 loadbyte $2 $3 syscall #1").unwrap();
+        assert_eq!(
+            program.to_bytes(),
+            Ok(vec![47, 0, 49, 1, 2, 48, 2, 3, 50, 0, 1])
+        );
+    }
+
+    #[test]
+    fn test_parse_multiline_program() {
+        let (rest, program) = program("load $1 #2\n  load $2 #1\r\nadd $1 $2 $3\n").unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(program.instructions.len(), 3);
+    }
+
+    #[test]
+    fn test_labels_to_bytes() {
+        let (rest, program) =
+            program("main: load $2 @loop\nloop:\n  increment $0\n  jumpifless $0 $1 $2\n").unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(
+            program.instructions[1].label,
+            Some(Token::LabelDeclaration {
+                name: String::from("loop")
+            })
+        );
+        assert_eq!(program.to_bytes(), Ok(vec![1, 2, 0, 4, 31, 0, 43, 0, 1, 2]));
+
+        let (_, undefined) = super::program("load $2 @nowhere").unwrap();
+        assert_eq!(
+            undefined.to_bytes(),
+            Err(AssemblerError::UndefinedLabel {
+                name: String::from("nowhere")
+            })
+        );
+    }
 }
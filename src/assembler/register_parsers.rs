@@ -1,22 +1,86 @@
+use std::collections::HashMap;
+
 use nom::{
+    branch::alt,
     bytes::complete::tag,
     character::complete::{digit1, space0},
-    sequence::tuple,
+    combinator::map_opt,
+    sequence::preceded,
     IResult,
 };
 
-use super::Token;
+use super::label_parsers::identifier;
+use super::{AssemblerError, Token};
+use crate::vm::REGISTER_COUNT;
+
+/// Names every program can use for registers with a fixed job: syscalls, native functions and
+/// the bundled routines take their arguments in `$a0`, `$a1` and `$a2` and leave their result in
+/// `$a0`, routines return to the address in `$ra`, and `.func` spills registers to the heap at
+/// `$sp`.
+pub const BUILTIN_REGISTERS: [(&str, u8); 5] =
+    [("a0", 0), ("a1", 1), ("a2", 2), ("sp", 30), ("ra", 31)];
+
+/// Whether a routine may overwrite the register without saving it: one of the arguments, or
+/// one of the scratch registers `$24` to `$29`.
+pub fn is_caller_saved(index: u8) -> bool {
+    matches!(index, 0..=2 | 24..=29)
+}
+
+fn builtin(name: &str) -> Option<Token> {
+    BUILTIN_REGISTERS
+        .iter()
+        .find(|(builtin, _)| *builtin == name)
+        .map(|(_, index)| Token::Register { index: *index })
+}
+
+fn in_range(index: &str) -> Option<u8> {
+    index
+        .parse::<u8>()
+        .ok()
+        .filter(|index| (*index as usize) < REGISTER_COUNT)
+}
 
+/// Whether `$name` is written like a numbered register, whether or not the VM has it.
+pub fn is_numbered(name: &str) -> bool {
+    let digits = name.strip_prefix('f').unwrap_or(name);
+    !digits.is_empty() && digits.chars().all(|character| character.is_ascii_digit())
+}
+
+// $3, $a0, $counter (a name left for the assembler to look up among the aliases in scope)
 pub fn register(input: &str) -> IResult<&str, Token> {
     let (input, _) = space0(input)?;
-    let (input, (_, index)) = tuple((tag("$"), digit1))(input)?;
+    let (input, name) = preceded(tag("$"), alt((digit1, identifier)))(input)?;
     let (input, _) = space0(input)?;
-    Ok((
-        input,
-        Token::Register {
-            index: index.parse::<u8>().unwrap(),
-        },
-    ))
+    let token = match in_range(name) {
+        Some(index) => Token::Register { index },
+        None => builtin(name).unwrap_or_else(|| Token::RegisterName {
+            name: name.to_string(),
+        }),
+    };
+    Ok((input, token))
+}
+
+// $f3 (one the VM does not have, like $f40, is left to `register` to report)
+pub fn float_register(input: &str) -> IResult<&str, Token> {
+    let (input, _) = space0(input)?;
+    let (input, index) = preceded(tag("$f"), map_opt(digit1, in_range))(input)?;
+    let (input, _) = space0(input)?;
+    Ok((input, Token::FloatRegister { index }))
+}
+
+/// The register that `$name` stands for, given the aliases in scope.
+pub fn resolve(name: &str, aliases: &HashMap<String, Token>) -> Result<Token, AssemblerError> {
+    if is_numbered(name) {
+        return Err(AssemblerError::NoSuchRegister {
+            name: name.to_string(),
+        });
+    }
+    aliases
+        .get(name)
+        .cloned()
+        .ok_or_else(|| AssemblerError::UndefinedAlias {
+            name: name.to_string(),
+        })
 }
 
 #[cfg(test)]
@@ -32,9 +96,76 @@ mod tests {
         assert_eq!(result, Ok(("", Token::Register { index: 5 })));
 
         let result = register("A");
-        assert_eq!(result.is_ok(), false);
+        assert!(result.is_err());
 
         let result = register("$f");
-        assert_eq!(result.is_ok(), false);
+        assert_eq!(
+            result,
+            Ok((
+                "",
+                Token::RegisterName {
+                    name: String::from("f")
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_named_register() {
+        assert_eq!(register("$a1"), Ok(("", Token::Register { index: 1 })));
+        assert_eq!(
+            register("$counter "),
+            Ok((
+                "",
+                Token::RegisterName {
+                    name: String::from("counter")
+                }
+            ))
+        );
+        assert_eq!(
+            register("$200"),
+            Ok((
+                "",
+                Token::RegisterName {
+                    name: String::from("200")
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_float_register() {
+        let result = float_register("$f0");
+        assert_eq!(result, Ok(("", Token::FloatRegister { index: 0 })));
+
+        let result = float_register(" $f31 ");
+        assert_eq!(result, Ok(("", Token::FloatRegister { index: 31 })));
+
+        let result = float_register("$3");
+        assert!(result.is_err());
+
+        let result = float_register("$f32");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_resolve_register() {
+        let aliases = HashMap::from([(String::from("x"), Token::FloatRegister { index: 2 })]);
+        assert_eq!(
+            resolve("x", &aliases),
+            Ok(Token::FloatRegister { index: 2 })
+        );
+        assert_eq!(
+            resolve("y", &aliases),
+            Err(AssemblerError::UndefinedAlias {
+                name: String::from("y")
+            })
+        );
+        assert_eq!(
+            resolve("f40", &aliases),
+            Err(AssemblerError::NoSuchRegister {
+                name: String::from("f40")
+            })
+        );
     }
 }
@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+
+use super::expression_parsers::Expression;
+use super::AssemblerError;
+
+/// The byte offset of every label in a program, the `.equ` constants it defines, and the labels
+/// it shares with other objects through `.import` and `.export`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SymbolTable {
+    symbols: HashMap<String, usize>,
+    /// Kept unevaluated so that constants can refer to labels and constants defined later
+    constants: HashMap<String, Expression>,
+    /// Labels defined by another object, in the order they were imported
+    imports: Vec<String>,
+    exports: Vec<String>,
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable::default()
+    }
+
+    pub fn define(&mut self, name: &str, offset: usize) -> Result<(), AssemblerError> {
+        if self.symbols.contains_key(name) || self.is_imported(name) {
+            return Err(AssemblerError::DuplicateLabel {
+                name: name.to_string(),
+            });
+        }
+        self.symbols.insert(name.to_string(), offset);
+        Ok(())
+    }
+
+    pub fn value(&self, name: &str) -> Option<usize> {
+        self.symbols.get(name).copied()
+    }
+
+    /// Declares that `name` is a label in another object.
+    pub fn import(&mut self, name: &str) -> Result<(), AssemblerError> {
+        if self.symbols.contains_key(name) || self.is_imported(name) {
+            return Err(AssemblerError::DuplicateLabel {
+                name: name.to_string(),
+            });
+        }
+        self.imports.push(name.to_string());
+        Ok(())
+    }
+
+    pub fn is_imported(&self, name: &str) -> bool {
+        self.imports.iter().any(|import| import == name)
+    }
+
+    pub fn imports(&self) -> &[String] {
+        &self.imports
+    }
+
+    /// Makes the label `name` available to other objects; it need not be declared yet.
+    pub fn export(&mut self, name: &str) {
+        if !self.exports.iter().any(|export| export == name) {
+            self.exports.push(name.to_string());
+        }
+    }
+
+    /// Every exported label that is declared, and its offset.
+    pub fn exports(&self) -> Vec<(String, usize)> {
+        self.exports
+            .iter()
+            .filter_map(|name| Some((name.clone(), self.value(name)?)))
+            .collect()
+    }
+
+    /// A copy with every label `distance` bytes further on, as if the code had been placed
+    /// there.
+    pub fn shifted(&self, distance: usize) -> SymbolTable {
+        let mut shifted = self.clone();
+        for offset in shifted.symbols.values_mut() {
+            *offset += distance;
+        }
+        shifted
+    }
+
+    pub fn define_constant(
+        &mut self,
+        name: &str,
+        expression: Expression,
+    ) -> Result<(), AssemblerError> {
+        if self.constants.contains_key(name) {
+            return Err(AssemblerError::DuplicateConstant {
+                name: name.to_string(),
+            });
+        }
+        self.constants.insert(name.to_string(), expression);
+        Ok(())
+    }
+
+    pub fn constant(&self, name: &str) -> Option<&Expression> {
+        self.constants.get(name)
+    }
+
+    /// The names of every constant, sorted.
+    pub fn constant_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.constants.keys().map(String::as_str).collect();
+        names.sort();
+        names
+    }
+
+    /// Every label and its offset, in offset order.
+    pub fn labels(&self) -> Vec<(String, usize)> {
+        let mut labels: Vec<(String, usize)> = self
+            .symbols
+            .iter()
+            .map(|(name, offset)| (name.clone(), *offset))
+            .collect();
+        labels.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+        labels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_symbol_table() {
+        let mut symbols = SymbolTable::new();
+        symbols.define("loop", 8).unwrap();
+        symbols.define("main", 0).unwrap();
+        assert_eq!(symbols.value("loop"), Some(8));
+        assert_eq!(symbols.value("end"), None);
+        assert_eq!(
+            symbols.define("loop", 12),
+            Err(AssemblerError::DuplicateLabel {
+                name: String::from("loop")
+            })
+        );
+        assert_eq!(
+            symbols.labels(),
+            vec![(String::from("main"), 0), (String::from("loop"), 8)]
+        );
+
+        symbols
+            .define_constant("SIZE", Expression::Number(4))
+            .unwrap();
+        assert_eq!(symbols.constant("SIZE"), Some(&Expression::Number(4)));
+        assert_eq!(
+            symbols.define_constant("SIZE", Expression::Number(5)),
+            Err(AssemblerError::DuplicateConstant {
+                name: String::from("SIZE")
+            })
+        );
+        assert_eq!(symbols.constant_names(), vec!["SIZE"]);
+
+        symbols.import("print").unwrap();
+        assert!(symbols.is_imported("print"));
+        assert!(symbols.import("loop").is_err());
+        assert!(symbols.define("print", 0).is_err());
+        symbols.export("main");
+        symbols.export("missing");
+        assert_eq!(symbols.exports(), vec![(String::from("main"), 0)]);
+        assert_eq!(symbols.shifted(4).value("loop"), Some(12));
+    }
+}
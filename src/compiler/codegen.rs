@@ -0,0 +1,787 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap};
+
+use super::{CompileError, Expression, Function, Operator, Script, Statement};
+use crate::assembler::instruction_parsers::AssemblerInstruction;
+use crate::assembler::preprocessor::{RETURN_ADDRESS, STACK_POINTER};
+use crate::assembler::program_parsers::{program, Program};
+use crate::assembler::Token;
+use crate::instruction::Opcode;
+use crate::stdlib;
+
+/// Where return values go, and where `print` takes its argument.
+const RESULT: u8 = 0;
+/// Given to variables in order, until they run out.
+const VARIABLE_REGISTERS: [u8; 14] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
+/// Hold the values of expressions still being worked out, innermost last.
+const TEMPORARY_REGISTERS: [u8; 6] = [17, 18, 19, 20, 21, 22];
+/// The start of the running function's frame.
+const FRAME_POINTER: u8 = 23;
+/// Values brought in from the heap and constants, only for the length of one operation.
+const SCRATCH: [u8; 3] = [24, 25, 26];
+/// The heap address of a frame slot, as it is loaded or stored.
+const ADDRESS: u8 = 27;
+/// Where jumps and calls take their target from, as `call` does.
+const TARGET: u8 = 29;
+/// The heap bytes `print` writes digits to, below the stack.
+const PRINT_BUFFER: u16 = 16;
+
+/// Where a value is kept.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Place {
+    Register(u8),
+    /// 4 bytes at this index in the frame
+    Slot(usize),
+}
+
+fn register(index: u8) -> Token {
+    Token::Register { index }
+}
+
+fn integer(value: i32) -> Token {
+    Token::IntegerOperand { value }
+}
+
+fn label(name: &str) -> Token {
+    Token::LabelUsage {
+        name: name.to_string(),
+    }
+}
+
+fn instruction(code: Opcode, operands: Vec<Token>) -> AssemblerInstruction {
+    let mut operands = operands.into_iter();
+    AssemblerInstruction {
+        opcode: Token::Op { code },
+        label: None,
+        operand1: operands.next(),
+        operand2: operands.next(),
+        operand3: operands.next(),
+    }
+}
+
+/// The byte offset of a frame slot, as an immediate.
+fn slot_offset(slot: usize) -> Token {
+    integer(slot as i32 * 4)
+}
+
+/// The jump taken when `operator` holds, and the one taken when it does not.
+fn jumps(operator: Operator) -> Option<(Opcode, Opcode)> {
+    match operator {
+        Operator::Equal => Some((Opcode::JUMPIFEQUAL, Opcode::JUMPIFNOTEQUAL)),
+        Operator::NotEqual => Some((Opcode::JUMPIFNOTEQUAL, Opcode::JUMPIFEQUAL)),
+        Operator::Less => Some((Opcode::JUMPIFLESS, Opcode::JUMPIFGREATEREQUAL)),
+        Operator::Greater => Some((Opcode::JUMPIFGREATER, Opcode::JUMPIFLESSEQUAL)),
+        Operator::LessEqual => Some((Opcode::JUMPIFLESSEQUAL, Opcode::JUMPIFGREATER)),
+        Operator::GreaterEqual => Some((Opcode::JUMPIFGREATEREQUAL, Opcode::JUMPIFLESS)),
+        _ => None,
+    }
+}
+
+/// What is known about the function being generated.
+#[derive(Default)]
+struct Frame {
+    /// Variables by name, innermost block last
+    scopes: Vec<HashMap<String, Place>>,
+    /// How many of `VARIABLE_REGISTERS` are taken by variables in scope
+    variable_registers: usize,
+    /// How many slots the frame has
+    slots: usize,
+    /// How many temporaries are in use
+    temporaries: usize,
+    /// The slots of the temporaries past the last of `TEMPORARY_REGISTERS`, by depth
+    temporary_slots: Vec<usize>,
+    /// Every register the function writes that its caller may rely on
+    used: BTreeSet<u8>,
+    /// Where `return` goes, outside the main program
+    return_label: Option<String>,
+}
+
+/// Generates the code for a script. Functions get a frame at `$sp`, made of the arguments that
+/// the caller stores there and the slots of spilled variables and temporaries, followed by the
+/// registers the function saves: `$ra`, every register it uses and the caller's frame pointer.
+pub struct Generator {
+    stack_bytes: u16,
+    /// The arity of every function, by name
+    arities: HashMap<String, usize>,
+    frame: Frame,
+    instructions: Vec<AssemblerInstruction>,
+    /// The label for the next instruction
+    pending_label: Option<String>,
+    /// Labels placed where another already was, and that label
+    aliases: HashMap<String, String>,
+    labels: usize,
+    prints: bool,
+    errors: Vec<(usize, CompileError)>,
+}
+
+impl Generator {
+    pub fn new(stack_bytes: u16) -> Generator {
+        Generator {
+            stack_bytes,
+            arities: HashMap::new(),
+            frame: Frame::default(),
+            instructions: vec![],
+            pending_label: None,
+            aliases: HashMap::new(),
+            labels: 0,
+            prints: false,
+            errors: vec![],
+        }
+    }
+
+    fn emit(&mut self, code: Opcode, operands: Vec<Token>) {
+        let mut instruction = instruction(code, operands);
+        if let Some(name) = self.pending_label.take() {
+            instruction.label = Some(Token::LabelDeclaration { name });
+        }
+        self.instructions.push(instruction);
+    }
+
+    fn new_label(&mut self, purpose: &str) -> String {
+        self.labels += 1;
+        format!("{}_{}", purpose, self.labels)
+    }
+
+    /// Labels the next instruction emitted.
+    fn place(&mut self, name: String) {
+        match &self.pending_label {
+            Some(pending) => {
+                self.aliases.insert(name, pending.clone());
+            }
+            None => self.pending_label = Some(name),
+        }
+    }
+
+    /// Loads the address of `name` into `TARGET` and jumps there.
+    fn jump(&mut self, name: &str) {
+        self.emit(Opcode::LOADRELATIVE, vec![register(TARGET), label(name)]);
+        self.emit(Opcode::JUMP, vec![register(TARGET)]);
+    }
+
+    /// Jumps to `name` with the return address in `$ra`, and comes back.
+    fn call(&mut self, name: &str) {
+        let back = self.new_label("back");
+        self.emit(
+            Opcode::LOADRELATIVE,
+            vec![register(RETURN_ADDRESS), label(&back)],
+        );
+        self.jump(name);
+        self.place(back);
+    }
+
+    fn error(&mut self, position: usize, error: CompileError) {
+        self.errors.push((position, error));
+    }
+
+    fn new_slot(&mut self) -> usize {
+        self.frame.slots += 1;
+        self.frame.slots - 1
+    }
+
+    /// Loads the heap address of `slot` into `ADDRESS`.
+    fn address(&mut self, slot: usize) {
+        self.emit(
+            Opcode::ADDIMMEDIATE,
+            vec![
+                register(FRAME_POINTER),
+                register(ADDRESS),
+                slot_offset(slot),
+            ],
+        );
+    }
+
+    /// The register holding the value at `place`, loaded into `scratch` if it is in the heap.
+    fn read(&mut self, place: Place, scratch: u8) -> u8 {
+        match place {
+            Place::Register(index) => index,
+            Place::Slot(slot) => {
+                self.address(slot);
+                self.emit(Opcode::LOADWORD, vec![register(ADDRESS), register(scratch)]);
+                scratch
+            }
+        }
+    }
+
+    /// The register to work out a value for `place` in: its own, or `scratch` to be passed to
+    /// `write`.
+    fn destination(place: Place, scratch: u8) -> u8 {
+        match place {
+            Place::Register(index) => index,
+            Place::Slot(_) => scratch,
+        }
+    }
+
+    /// Copies `source` to `place`.
+    fn write(&mut self, source: u8, place: Place) {
+        match place {
+            Place::Register(index) if index == source => {}
+            Place::Register(index) => {
+                self.emit(Opcode::MOVE, vec![register(source), register(index)]);
+            }
+            Place::Slot(slot) => {
+                self.address(slot);
+                self.emit(Opcode::STOREWORD, vec![register(source), register(ADDRESS)]);
+            }
+        }
+    }
+
+    fn push_temporary(&mut self) -> Place {
+        let depth = self.frame.temporaries;
+        self.frame.temporaries += 1;
+        if let Some(index) = TEMPORARY_REGISTERS.get(depth) {
+            self.frame.used.insert(*index);
+            return Place::Register(*index);
+        }
+        let spilled = depth - TEMPORARY_REGISTERS.len();
+        if spilled == self.frame.temporary_slots.len() {
+            let slot = self.new_slot();
+            self.frame.temporary_slots.push(slot);
+        }
+        Place::Slot(self.frame.temporary_slots[spilled])
+    }
+
+    fn pop_temporary(&mut self) {
+        self.frame.temporaries -= 1;
+    }
+
+    /// A place for a new variable, in a register if one is free and in the frame if not.
+    fn new_variable(&mut self) -> Place {
+        match VARIABLE_REGISTERS.get(self.frame.variable_registers) {
+            Some(index) => {
+                self.frame.variable_registers += 1;
+                self.frame.used.insert(*index);
+                Place::Register(*index)
+            }
+            None => Place::Slot(self.new_slot()),
+        }
+    }
+
+    fn declare(&mut self, name: &str, place: Place, position: usize) {
+        let scope = self.frame.scopes.last_mut().unwrap();
+        if scope.contains_key(name) {
+            let error = CompileError::DuplicateVariable {
+                name: name.to_string(),
+            };
+            return self.error(position, error);
+        }
+        scope.insert(name.to_string(), place);
+    }
+
+    fn variable(&mut self, name: &str, position: usize) -> Option<Place> {
+        let found = self
+            .frame
+            .scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name).copied());
+        if found.is_none() {
+            let error = CompileError::UndefinedVariable {
+                name: name.to_string(),
+            };
+            self.error(position, error);
+        }
+        found
+    }
+
+    /// Loads `value` into `target`. `LOAD` only takes 16 bits, so others are built up.
+    fn constant(&mut self, value: i32, target: u8) {
+        if let Ok(value) = u16::try_from(value) {
+            return self.emit(Opcode::LOAD, vec![register(target), integer(value as i32)]);
+        }
+        let scratch = if target == SCRATCH[2] {
+            SCRATCH[1]
+        } else {
+            SCRATCH[2]
+        };
+        if value < 0 {
+            // -1 - (-1 - value), where -1 - value is positive
+            self.constant(-1 - value, target);
+            self.emit(Opcode::LOAD, vec![register(scratch), integer(0)]);
+            self.emit(
+                Opcode::SUBTRACT,
+                vec![register(scratch), register(target), register(target)],
+            );
+            return self.emit(Opcode::DECREMENT, vec![register(target)]);
+        }
+        self.emit(Opcode::LOAD, vec![register(target), integer(value >> 16)]);
+        self.emit(Opcode::LOAD, vec![register(scratch), integer(256)]);
+        for _ in 0..2 {
+            self.emit(
+                Opcode::MULTIPLY,
+                vec![register(target), register(scratch), register(target)],
+            );
+        }
+        if value & 0xFFFF != 0 {
+            self.emit(
+                Opcode::LOAD,
+                vec![register(scratch), integer(value & 0xFFFF)],
+            );
+            self.emit(
+                Opcode::ADD,
+                vec![register(target), register(scratch), register(target)],
+            );
+        }
+    }
+
+    fn number(&mut self, value: i64, position: usize) -> i32 {
+        i32::try_from(value).unwrap_or_else(|_| {
+            self.error(position, CompileError::NumberOutOfRange { value });
+            0
+        })
+    }
+
+    /// Works out `left` into `place` and `right` into wherever is handiest, and returns the
+    /// registers holding each.
+    fn operands(&mut self, left: &Expression, right: &Expression, place: Place) -> (u8, u8) {
+        self.expression(left, place);
+        if let Expression::Variable { name, position } = right {
+            let variable = self.variable(name, *position);
+            let right = variable.map_or(SCRATCH[1], |variable| self.read(variable, SCRATCH[1]));
+            let left = self.read(place, SCRATCH[0]);
+            return (left, right);
+        }
+        let temporary = self.push_temporary();
+        self.expression(right, temporary);
+        let right = self.read(temporary, SCRATCH[1]);
+        self.pop_temporary();
+        let left = self.read(place, SCRATCH[0]);
+        (left, right)
+    }
+
+    fn binary(&mut self, operator: Operator, left: &Expression, right: &Expression, place: Place) {
+        let target = Generator::destination(place, SCRATCH[0]);
+        // Small constants go straight into the instruction
+        if let (Operator::Add | Operator::Subtract, Expression::Number { value, .. }) =
+            (operator, right)
+        {
+            if let Ok(value) = u16::try_from(*value) {
+                self.expression(left, place);
+                let source = self.read(place, SCRATCH[0]);
+                let code = match operator {
+                    Operator::Add => Opcode::ADDIMMEDIATE,
+                    _ => Opcode::SUBTRACTIMMEDIATE,
+                };
+                self.emit(
+                    code,
+                    vec![register(source), register(target), integer(value as i32)],
+                );
+                return self.write(target, place);
+            }
+        }
+        let (a, b) = self.operands(left, right, place);
+        let arithmetic = |code| vec![(code, vec![register(a), register(b), register(target)])];
+        let code = match operator {
+            Operator::Add => arithmetic(Opcode::ADD),
+            Operator::Subtract => arithmetic(Opcode::SUBTRACT),
+            Operator::Multiply => arithmetic(Opcode::MULTIPLY),
+            Operator::Divide => arithmetic(Opcode::DIVIDE),
+            Operator::Remainder => {
+                let mut code = arithmetic(Opcode::DIVIDE);
+                code.push((Opcode::GETREMAINDER, vec![register(target)]));
+                code
+            }
+            comparison => {
+                let (holds, _) = jumps(comparison).unwrap();
+                let (yes, done) = (self.new_label("true"), self.new_label("compared"));
+                self.emit(Opcode::LOADRELATIVE, vec![register(TARGET), label(&yes)]);
+                self.emit(holds, vec![register(a), register(b), register(TARGET)]);
+                self.emit(Opcode::LOAD, vec![register(target), integer(0)]);
+                self.jump(&done);
+                self.place(yes);
+                self.emit(Opcode::LOAD, vec![register(target), integer(1)]);
+                self.place(done);
+                vec![]
+            }
+        };
+        for (code, operands) in code {
+            self.emit(code, operands);
+        }
+        self.write(target, place);
+    }
+
+    fn call_function(&mut self, name: &str, arguments: &[Expression], position: usize) {
+        match self.arities.get(name) {
+            None => self.error(
+                position,
+                CompileError::UndefinedFunction {
+                    name: name.to_string(),
+                },
+            ),
+            Some(expected) if *expected != arguments.len() => {
+                let error = CompileError::Arguments {
+                    name: name.to_string(),
+                    expected: *expected,
+                    found: arguments.len(),
+                };
+                self.error(position, error);
+            }
+            Some(_) => {}
+        }
+        // Every argument is worked out before any is stored, since working one out may call
+        // another function whose frame goes in the same place
+        let temporaries: Vec<Place> = arguments
+            .iter()
+            .map(|argument| {
+                let temporary = self.push_temporary();
+                self.expression(argument, temporary);
+                temporary
+            })
+            .collect();
+        for (index, temporary) in temporaries.into_iter().enumerate() {
+            let value = self.read(temporary, SCRATCH[0]);
+            self.emit(
+                Opcode::ADDIMMEDIATE,
+                vec![
+                    register(STACK_POINTER),
+                    register(ADDRESS),
+                    slot_offset(index),
+                ],
+            );
+            self.emit(Opcode::STOREWORD, vec![register(value), register(ADDRESS)]);
+        }
+        for _ in arguments {
+            self.pop_temporary();
+        }
+        self.call(&format!("function_{}", name));
+    }
+
+    /// Generates code that leaves the value of `expression` at `place`.
+    fn expression(&mut self, expression: &Expression, place: Place) {
+        let target = Generator::destination(place, SCRATCH[0]);
+        match expression {
+            Expression::Number { value, position } => {
+                let value = self.number(*value, *position);
+                self.constant(value, target);
+            }
+            Expression::Negate(operand) => match operand.as_ref() {
+                Expression::Number { value, position } => {
+                    let value = self.number(-value, *position);
+                    self.constant(value, target);
+                }
+                operand => {
+                    self.expression(operand, place);
+                    let source = self.read(place, SCRATCH[0]);
+                    self.emit(Opcode::LOAD, vec![register(SCRATCH[1]), integer(0)]);
+                    self.emit(
+                        Opcode::SUBTRACT,
+                        vec![register(SCRATCH[1]), register(source), register(target)],
+                    );
+                }
+            },
+            Expression::Variable { name, position } => {
+                if let Some(variable) = self.variable(name, *position) {
+                    let source = self.read(variable, target);
+                    return self.write(source, place);
+                }
+            }
+            Expression::Call {
+                name,
+                arguments,
+                position,
+            } => {
+                self.call_function(name, arguments, *position);
+                return self.write(RESULT, place);
+            }
+            Expression::Binary {
+                operator,
+                left,
+                right,
+            } => return self.binary(*operator, left, right, place),
+        }
+        self.write(target, place);
+    }
+
+    /// Generates code that jumps to `name` if `condition` is 0.
+    fn branch_unless(&mut self, condition: &Expression, name: &str) {
+        let temporary = self.push_temporary();
+        match condition {
+            Expression::Binary {
+                operator,
+                left,
+                right,
+            } if jumps(*operator).is_some() => {
+                let (a, b) = self.operands(left, right, temporary);
+                let (_, fails) = jumps(*operator).unwrap();
+                self.emit(Opcode::LOADRELATIVE, vec![register(TARGET), label(name)]);
+                self.emit(fails, vec![register(a), register(b), register(TARGET)]);
+            }
+            condition => {
+                self.expression(condition, temporary);
+                let value = self.read(temporary, SCRATCH[0]);
+                self.emit(Opcode::LOAD, vec![register(SCRATCH[1]), integer(0)]);
+                self.emit(Opcode::LOADRELATIVE, vec![register(TARGET), label(name)]);
+                self.emit(
+                    Opcode::JUMPIFEQUAL,
+                    vec![register(value), register(SCRATCH[1]), register(TARGET)],
+                );
+            }
+        }
+        self.pop_temporary();
+    }
+
+    fn block(&mut self, statements: &[Statement]) {
+        self.frame.scopes.push(HashMap::new());
+        let registers = self.frame.variable_registers;
+        for statement in statements {
+            self.statement(statement);
+        }
+        // The block's variables are gone, so their registers can be given out again
+        self.frame.variable_registers = registers;
+        self.frame.scopes.pop();
+    }
+
+    fn statement(&mut self, statement: &Statement) {
+        match statement {
+            Statement::Let {
+                name,
+                value,
+                position,
+            } => {
+                // Declared afterwards, so that the value can use a variable it shadows
+                let place = self.new_variable();
+                self.expression(value, place);
+                self.declare(name, place, *position);
+            }
+            Statement::Assign {
+                name,
+                value,
+                position,
+            } => {
+                // Worked out elsewhere first, since the value may use the variable
+                let temporary = self.push_temporary();
+                self.expression(value, temporary);
+                if let Some(variable) = self.variable(name, *position) {
+                    let source = self.read(temporary, SCRATCH[0]);
+                    self.write(source, variable);
+                }
+                self.pop_temporary();
+            }
+            Statement::If {
+                condition,
+                then,
+                otherwise,
+            } => {
+                let (other, done) = (self.new_label("else"), self.new_label("endif"));
+                self.branch_unless(condition, &other);
+                self.block(then);
+                if !otherwise.is_empty() {
+                    self.jump(&done);
+                }
+                self.place(other);
+                self.block(otherwise);
+                self.place(done);
+            }
+            Statement::While { condition, body } => {
+                let (start, done) = (self.new_label("while"), self.new_label("endwhile"));
+                self.place(start.clone());
+                self.branch_unless(condition, &done);
+                self.block(body);
+                self.jump(&start);
+                self.place(done);
+            }
+            Statement::Print(value) => {
+                self.prints = true;
+                let temporary = self.push_temporary();
+                self.expression(value, temporary);
+                let source = self.read(temporary, SCRATCH[0]);
+                self.pop_temporary();
+                self.write(source, Place::Register(RESULT));
+                self.emit(Opcode::LOAD, vec![register(1), integer(0)]);
+                self.call("std_to_digits");
+                // A newline after the digits, then SYSCALL Write of them all to the console
+                self.emit(Opcode::LOAD, vec![register(SCRATCH[0]), integer(10)]);
+                self.emit(
+                    Opcode::STOREBYTE,
+                    vec![register(SCRATCH[0]), register(RESULT)],
+                );
+                self.emit(Opcode::MOVE, vec![register(RESULT), register(2)]);
+                self.emit(Opcode::INCREMENT, vec![register(2)]);
+                self.emit(Opcode::LOAD, vec![register(1), integer(0)]);
+                self.emit(Opcode::LOAD, vec![register(0), integer(1)]);
+                self.emit(Opcode::SYSCALL, vec![integer(1)]);
+            }
+            Statement::Return { value, position } => {
+                let return_label = match self.frame.return_label.clone() {
+                    Some(return_label) => return_label,
+                    None => return self.error(*position, CompileError::ReturnOutsideFunction),
+                };
+                match value {
+                    Some(value) => {
+                        let temporary = self.push_temporary();
+                        self.expression(value, temporary);
+                        let source = self.read(temporary, SCRATCH[0]);
+                        self.pop_temporary();
+                        self.write(source, Place::Register(RESULT));
+                    }
+                    None => self.emit(Opcode::LOAD, vec![register(RESULT), integer(0)]),
+                }
+                self.jump(&return_label);
+            }
+            Statement::Expression(value) => {
+                let temporary = self.push_temporary();
+                self.expression(value, temporary);
+                self.pop_temporary();
+            }
+        }
+    }
+
+    /// The code that saves `saved` above a frame of `slots` slots at `$sp` and makes it the
+    /// running frame, labelled `name`.
+    fn prologue(name: String, slots: usize, saved: &[u8]) -> Vec<AssemblerInstruction> {
+        let mut code = vec![];
+        for (index, saved) in saved.iter().enumerate() {
+            code.push(instruction(
+                Opcode::ADDIMMEDIATE,
+                vec![
+                    register(STACK_POINTER),
+                    register(ADDRESS),
+                    slot_offset(slots + index),
+                ],
+            ));
+            code.push(instruction(
+                Opcode::STOREWORD,
+                vec![register(*saved), register(ADDRESS)],
+            ));
+        }
+        code.push(instruction(
+            Opcode::MOVE,
+            vec![register(STACK_POINTER), register(FRAME_POINTER)],
+        ));
+        code.push(instruction(
+            Opcode::ADDIMMEDIATE,
+            vec![
+                register(FRAME_POINTER),
+                register(STACK_POINTER),
+                slot_offset(slots + saved.len()),
+            ],
+        ));
+        code[0].label = Some(Token::LabelDeclaration { name });
+        code
+    }
+
+    fn function(&mut self, function: &Function) {
+        let return_label = self.new_label("return");
+        self.frame = Frame {
+            return_label: Some(return_label.clone()),
+            slots: function.parameters.len(),
+            scopes: vec![HashMap::new()],
+            ..Frame::default()
+        };
+        let body_start = self.instructions.len();
+        // The caller stores the arguments in the first slots; those given registers are loaded
+        for (index, (name, position)) in function.parameters.iter().enumerate() {
+            let place = match self.new_variable() {
+                Place::Register(parameter) => {
+                    self.address(index);
+                    self.emit(
+                        Opcode::LOADWORD,
+                        vec![register(ADDRESS), register(parameter)],
+                    );
+                    Place::Register(parameter)
+                }
+                Place::Slot(_) => {
+                    // The slot taken for it is not needed after all
+                    self.frame.slots -= 1;
+                    Place::Slot(index)
+                }
+            };
+            self.declare(name, place, *position);
+        }
+        self.block(&function.body);
+        self.emit(Opcode::LOAD, vec![register(RESULT), integer(0)]);
+        self.place(return_label);
+
+        // The frame pointer goes last, since the others are restored through it
+        let mut saved = vec![RETURN_ADDRESS];
+        saved.extend(&self.frame.used);
+        saved.push(FRAME_POINTER);
+        let slots = self.frame.slots;
+        self.emit(
+            Opcode::MOVE,
+            vec![register(FRAME_POINTER), register(STACK_POINTER)],
+        );
+        for (index, register_index) in saved.iter().enumerate() {
+            self.address(slots + index);
+            self.emit(
+                Opcode::LOADWORD,
+                vec![register(ADDRESS), register(*register_index)],
+            );
+        }
+        self.emit(Opcode::JUMP, vec![register(RETURN_ADDRESS)]);
+
+        let prologue = Generator::prologue(format!("function_{}", function.name), slots, &saved);
+        self.instructions.splice(body_start..body_start, prologue);
+    }
+
+    /// The instructions for `script`, or every error found in it with its position.
+    pub fn generate(mut self, script: &Script) -> Result<Program, Vec<(usize, CompileError)>> {
+        for function in &script.functions {
+            if self.arities.contains_key(&function.name) {
+                let error = CompileError::DuplicateFunction {
+                    name: function.name.clone(),
+                };
+                self.error(function.position, error);
+                continue;
+            }
+            self.arities
+                .insert(function.name.clone(), function.parameters.len());
+        }
+
+        // The main program allocates the heap, and its frame starts after the print buffer
+        self.frame = Frame {
+            scopes: vec![HashMap::new()],
+            ..Frame::default()
+        };
+        self.block(&script.statements);
+        self.emit(Opcode::HALT, vec![]);
+        let setup = vec![
+            instruction(
+                Opcode::LOAD,
+                vec![register(SCRATCH[0]), integer(self.stack_bytes as i32)],
+            ),
+            instruction(Opcode::ALLOCATE, vec![register(SCRATCH[0])]),
+            instruction(
+                Opcode::LOAD,
+                vec![register(FRAME_POINTER), integer(PRINT_BUFFER as i32)],
+            ),
+            instruction(
+                Opcode::ADDIMMEDIATE,
+                vec![
+                    register(FRAME_POINTER),
+                    register(STACK_POINTER),
+                    slot_offset(self.frame.slots),
+                ],
+            ),
+        ];
+        self.instructions.splice(0..0, setup);
+
+        for function in &script.functions {
+            self.function(function);
+        }
+        if self.prints {
+            let (_, digits) = program(stdlib::DIGITS).expect("the bundled library parses");
+            self.instructions.extend(digits.instructions);
+        }
+
+        if !self.errors.is_empty() {
+            self.errors.sort_by_key(|(position, _)| Reverse(*position));
+            return Err(self.errors);
+        }
+        for instruction in &mut self.instructions {
+            for operand in [
+                &mut instruction.operand1,
+                &mut instruction.operand2,
+                &mut instruction.operand3,
+            ] {
+                if let Some(Token::LabelUsage { name }) = operand {
+                    if let Some(alias) = self.aliases.get(name) {
+                        *name = alias.clone();
+                    }
+                }
+            }
+        }
+        Ok(Program {
+            instructions: self.instructions,
+        })
+    }
+}
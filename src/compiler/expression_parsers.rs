@@ -0,0 +1,317 @@
+use std::cmp::Ordering;
+
+use nom::{
+    branch::alt,
+    bytes::complete::{is_not, tag},
+    character::complete::{alpha1, alphanumeric1, digit1, multispace1},
+    combinator::{cut, map, not, opt, recognize, value},
+    error::{ErrorKind, ParseError},
+    multi::many0,
+    sequence::{delimited, pair, preceded, terminated, tuple},
+    IResult,
+};
+
+use super::{Expression, Operator};
+
+/// Why parsing stopped: the input it could not go on from, and what it wanted to find there.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SyntaxError<'a> {
+    pub input: &'a str,
+    pub expected: &'static str,
+}
+
+impl<'a> ParseError<&'a str> for SyntaxError<'a> {
+    fn from_error_kind(input: &'a str, _: ErrorKind) -> Self {
+        SyntaxError {
+            input,
+            expected: "something else",
+        }
+    }
+
+    fn append(_: &'a str, _: ErrorKind, other: Self) -> Self {
+        other
+    }
+
+    /// Of two alternatives that failed, the one that got further says more, as does one that
+    /// knows what it expected.
+    fn or(self, other: Self) -> Self {
+        let vague = self.expected == "something else";
+        match other.input.len().cmp(&self.input.len()) {
+            Ordering::Less => other,
+            Ordering::Equal if vague => other,
+            _ => self,
+        }
+    }
+}
+
+pub type ParseResult<'a, O> = IResult<&'a str, O, SyntaxError<'a>>;
+
+/// Words that cannot name a variable or a function.
+pub const KEYWORDS: [&str; 7] = ["let", "if", "else", "while", "fn", "return", "print"];
+
+/// `parser`, except that when it does not match, the error says `expected` is missing at the
+/// start of the input. A failure from past a `cut` is passed on as it is.
+pub fn expect<'a, O>(
+    expected: &'static str,
+    mut parser: impl FnMut(&'a str) -> ParseResult<'a, O>,
+) -> impl FnMut(&'a str) -> ParseResult<'a, O> {
+    move |input| match parser(input) {
+        Err(nom::Err::Error(_)) => Err(nom::Err::Error(SyntaxError { input, expected })),
+        result => result,
+    }
+}
+
+// Whitespace and // comments, which may separate any two tokens
+pub fn blank(input: &str) -> ParseResult<'_, ()> {
+    value(
+        (),
+        many0(alt((
+            value((), multispace1),
+            value((), pair(tag("//"), opt(is_not("\n")))),
+        ))),
+    )(input)
+}
+
+// +, <=, ( (and whatever blank comes before it)
+pub fn symbol<'a>(text: &'static str) -> impl FnMut(&'a str) -> ParseResult<'a, &'a str> {
+    preceded(blank, tag(text))
+}
+
+fn identifier(input: &str) -> ParseResult<'_, &str> {
+    recognize(pair(
+        alt((alpha1, tag("_"))),
+        many0(alt((alphanumeric1, tag("_")))),
+    ))(input)
+}
+
+// while (but not the start of whilst)
+pub fn keyword<'a>(word: &'static str) -> impl FnMut(&'a str) -> ParseResult<'a, &'a str> {
+    preceded(
+        blank,
+        terminated(tag(word), not(alt((alphanumeric1, tag("_"))))),
+    )
+}
+
+// counter, gcd, _tmp (with its position, counted as the length of the input from there)
+pub fn name(input: &str) -> ParseResult<'_, (String, usize)> {
+    let (input, _) = blank(input)?;
+    let position = input.len();
+    let (rest, name) = identifier(input)?;
+    if KEYWORDS.contains(&name) {
+        return Err(nom::Err::Error(SyntaxError {
+            input,
+            expected: "a name",
+        }));
+    }
+    Ok((rest, (name.to_string(), position)))
+}
+
+/// Items separated by commas, possibly none. After a comma, `expected` must follow.
+pub fn list<'a, O>(
+    expected: &'static str,
+    mut item: impl FnMut(&'a str) -> ParseResult<'a, O>,
+) -> impl FnMut(&'a str) -> ParseResult<'a, Vec<O>> {
+    move |input| {
+        let mut items = vec![];
+        let mut input = match item(input) {
+            Ok((rest, first)) => {
+                items.push(first);
+                rest
+            }
+            Err(nom::Err::Error(_)) => return Ok((input, items)),
+            Err(error) => return Err(error),
+        };
+        while let Ok((rest, _)) = symbol(",")(input) {
+            let (rest, next) = cut(expect(expected, &mut item))(rest)?;
+            items.push(next);
+            input = rest;
+        }
+        Ok((input, items))
+    }
+}
+
+// 42
+fn number(input: &str) -> ParseResult<'_, Expression> {
+    let (input, _) = blank(input)?;
+    let position = input.len();
+    let (input, digits) = digit1(input)?;
+    // Too long for an i64 is certainly too long for the VM, which `Generator` reports
+    let value = digits.parse().unwrap_or(i64::MAX);
+    Ok((input, Expression::Number { value, position }))
+}
+
+// gcd(a, 4), counter
+fn call_or_variable(input: &str) -> ParseResult<'_, Expression> {
+    let (input, (name, position)) = name(input)?;
+    let (input, arguments) = opt(preceded(
+        symbol("("),
+        cut(terminated(
+            list("an expression", expression),
+            expect("`)`", symbol(")")),
+        )),
+    ))(input)?;
+    let expression = match arguments {
+        Some(arguments) => Expression::Call {
+            name,
+            arguments,
+            position,
+        },
+        None => Expression::Variable { name, position },
+    };
+    Ok((input, expression))
+}
+
+// 42, counter, gcd(a, 4), (a + 1), -a
+fn unary(input: &str) -> ParseResult<'_, Expression> {
+    alt((
+        number,
+        call_or_variable,
+        delimited(
+            symbol("("),
+            cut(expect("an expression", expression)),
+            cut(expect("`)`", symbol(")"))),
+        ),
+        map(
+            preceded(symbol("-"), cut(expect("an expression", unary))),
+            |operand| Expression::Negate(Box::new(operand)),
+        ),
+    ))(input)
+}
+
+/// Operands joined left to right by any of `operators`, like `a - b + c`.
+fn chain<'a>(
+    operators: &'static [(&'static str, Operator)],
+    mut operand: impl FnMut(&'a str) -> ParseResult<'a, Expression> + Copy,
+) -> impl FnMut(&'a str) -> ParseResult<'a, Expression> {
+    move |input| {
+        let (mut input, mut left) = operand(input)?;
+        loop {
+            let found = operators.iter().find_map(|(text, operator)| {
+                symbol(text)(input).ok().map(|(rest, _)| (rest, *operator))
+            });
+            let (rest, operator) = match found {
+                Some(found) => found,
+                None => return Ok((input, left)),
+            };
+            let (rest, right) = cut(expect("an expression", operand))(rest)?;
+            left = Expression::Binary {
+                operator,
+                left: Box::new(left),
+                right: Box::new(right),
+            };
+            input = rest;
+        }
+    }
+}
+
+fn term(input: &str) -> ParseResult<'_, Expression> {
+    chain(
+        &[
+            ("*", Operator::Multiply),
+            ("/", Operator::Divide),
+            ("%", Operator::Remainder),
+        ],
+        unary,
+    )(input)
+}
+
+fn sum(input: &str) -> ParseResult<'_, Expression> {
+    chain(&[("+", Operator::Add), ("-", Operator::Subtract)], term)(input)
+}
+
+// a + 1, a * (b - 2) % 3, gcd(a, b) == 1 (comparisons do not chain)
+pub fn expression(input: &str) -> ParseResult<'_, Expression> {
+    let (input, left) = sum(input)?;
+    // Longest first, since `<` is a prefix of `<=`
+    let comparison = alt((
+        value(Operator::Equal, symbol("==")),
+        value(Operator::NotEqual, symbol("!=")),
+        value(Operator::LessEqual, symbol("<=")),
+        value(Operator::GreaterEqual, symbol(">=")),
+        value(Operator::Less, symbol("<")),
+        value(Operator::Greater, symbol(">")),
+    ));
+    let (input, right) = opt(tuple((comparison, cut(expect("an expression", sum)))))(input)?;
+    let expression = match right {
+        Some((operator, right)) => Expression::Binary {
+            operator,
+            left: Box::new(left),
+            right: Box::new(right),
+        },
+        None => left,
+    };
+    Ok((input, expression))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variable(name: &str, position: usize) -> Expression {
+        Expression::Variable {
+            name: name.to_string(),
+            position,
+        }
+    }
+
+    #[test]
+    fn test_parse_precedence() {
+        let (rest, parsed) = expression("a + b * 2 < -c").unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(
+            parsed,
+            Expression::Binary {
+                operator: Operator::Less,
+                left: Box::new(Expression::Binary {
+                    operator: Operator::Add,
+                    left: Box::new(variable("a", 14)),
+                    right: Box::new(Expression::Binary {
+                        operator: Operator::Multiply,
+                        left: Box::new(variable("b", 10)),
+                        right: Box::new(Expression::Number {
+                            value: 2,
+                            position: 6
+                        }),
+                    }),
+                }),
+                right: Box::new(Expression::Negate(Box::new(variable("c", 1)))),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_calls_and_comments() {
+        let (rest, parsed) = expression("f(x, // the first\n (1))").unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(
+            parsed,
+            Expression::Call {
+                name: String::from("f"),
+                arguments: vec![
+                    variable("x", 21),
+                    Expression::Number {
+                        value: 1,
+                        position: 3
+                    }
+                ],
+                position: 23,
+            }
+        );
+        let (rest, _) = expression("a - 1 ;").unwrap();
+        assert_eq!(rest, " ;");
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |input| match expression(input) {
+            Err(nom::Err::Error(error)) | Err(nom::Err::Failure(error)) => {
+                (error.input.len(), error.expected)
+            }
+            Ok(_) | Err(nom::Err::Incomplete(_)) => panic!("{} parsed", input),
+        };
+        assert_eq!(error("1 + * 2"), (4, "an expression"));
+        assert_eq!(error("f(1, 2"), (0, "`)`"));
+        assert_eq!(error("(1 + 2;"), (1, "`)`"));
+        assert_eq!(error("while"), (5, "a name"));
+    }
+}
@@ -0,0 +1,442 @@
+use std::fmt;
+
+use crate::assembler::optimizer::optimize;
+use crate::assembler::program_parsers::Program;
+use crate::debug_info::Location;
+use codegen::Generator;
+
+pub mod codegen;
+pub mod expression_parsers;
+pub mod statement_parsers;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+    Equal,
+    NotEqual,
+    Less,
+    Greater,
+    LessEqual,
+    GreaterEqual,
+}
+
+/// An integer expression in a script. A `position` is where the node starts, as the number of
+/// bytes from there to the end of the source, which is what the parsers see.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Expression {
+    Number {
+        value: i64,
+        position: usize,
+    },
+    Variable {
+        name: String,
+        position: usize,
+    },
+    Call {
+        name: String,
+        arguments: Vec<Expression>,
+        position: usize,
+    },
+    Negate(Box<Expression>),
+    /// Comparisons are 1 when they hold and 0 when they do not
+    Binary {
+        operator: Operator,
+        left: Box<Expression>,
+        right: Box<Expression>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Statement {
+    /// Declares a variable for the rest of the enclosing block
+    Let {
+        name: String,
+        value: Expression,
+        position: usize,
+    },
+    Assign {
+        name: String,
+        value: Expression,
+        position: usize,
+    },
+    /// Runs `then` if `condition` is not 0, and `otherwise` if it is
+    If {
+        condition: Expression,
+        then: Vec<Statement>,
+        otherwise: Vec<Statement>,
+    },
+    While {
+        condition: Expression,
+        body: Vec<Statement>,
+    },
+    /// Writes the value in decimal and a newline to the console
+    Print(Expression),
+    /// Returns the value, or 0 without one
+    Return {
+        value: Option<Expression>,
+        position: usize,
+    },
+    /// Evaluates an expression for its calls
+    Expression(Expression),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Function {
+    pub name: String,
+    /// Names and positions
+    pub parameters: Vec<(String, usize)>,
+    pub body: Vec<Statement>,
+    pub position: usize,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Script {
+    pub functions: Vec<Function>,
+    pub statements: Vec<Statement>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CompileError {
+    Syntax {
+        expected: &'static str,
+    },
+    UndefinedVariable {
+        name: String,
+    },
+    DuplicateVariable {
+        name: String,
+    },
+    UndefinedFunction {
+        name: String,
+    },
+    DuplicateFunction {
+        name: String,
+    },
+    Arguments {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    ReturnOutsideFunction,
+    /// A number that does not fit in a VM register
+    NumberOutOfRange {
+        value: i64,
+    },
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompileError::Syntax { expected } => write!(f, "expected {}", expected),
+            CompileError::UndefinedVariable { name } => {
+                write!(f, "variable {} is never declared", name)
+            }
+            CompileError::DuplicateVariable { name } => {
+                write!(f, "variable {} is already declared", name)
+            }
+            CompileError::UndefinedFunction { name } => {
+                write!(f, "function {} is never defined", name)
+            }
+            CompileError::DuplicateFunction { name } => {
+                write!(f, "function {} is already defined", name)
+            }
+            CompileError::Arguments {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "function {} takes {} argument(s), not {}",
+                name, expected, found
+            ),
+            CompileError::ReturnOutsideFunction => write!(f, "return outside a function"),
+            CompileError::NumberOutOfRange { value } => {
+                write!(f, "{} does not fit in a 32-bit integer", value)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+/// A `CompileError` and where in the script it is.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScriptError {
+    pub location: Location,
+    pub error: CompileError,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.error)
+    }
+}
+
+/// Turns a script into the instructions the assembler would make of the same program.
+///
+/// A script is a list of statements, which run in order, and of functions, which may be defined
+/// anywhere in it. Every value is a 32-bit integer:
+///
+/// ```text
+/// fn gcd(a, b) {
+///     while b != 0 {
+///         let rest = a % b;
+///         a = b;
+///         b = rest;
+///     }
+///     return a;
+/// }
+///
+/// print gcd(12, 18); // 6
+/// ```
+///
+/// Variables live in registers while there are registers for them and in the heap after that.
+/// Each call gets a frame on a stack in the heap, which the program allocates when it starts,
+/// so it should run on a VM whose heap is empty.
+pub struct Compiler {
+    /// The name used in locations
+    pub file_name: String,
+    /// How many bytes of heap the program allocates for its stack
+    pub stack_bytes: u16,
+    /// Whether the instructions go through `optimizer::optimize`, which is off unless asked for
+    pub optimize: bool,
+}
+
+impl Compiler {
+    pub fn new(file_name: &str) -> Compiler {
+        Compiler {
+            file_name: file_name.to_string(),
+            stack_bytes: 4096,
+            optimize: false,
+        }
+    }
+
+    fn location(&self, source: &str, position: usize) -> Location {
+        let before = &source[..source.len() - position];
+        let line_start = before.rfind('\n').map_or(0, |index| index + 1);
+        Location {
+            file: self.file_name.clone(),
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+
+    pub fn compile(&self, source: &str) -> Result<Program, Vec<ScriptError>> {
+        let script = statement_parsers::script(source).map_err(|error| {
+            vec![ScriptError {
+                location: self.location(source, error.input.len()),
+                error: CompileError::Syntax {
+                    expected: error.expected,
+                },
+            }]
+        })?;
+        let mut program = Generator::new(self.stack_bytes)
+            .generate(&script)
+            .map_err(|errors| {
+                errors
+                    .into_iter()
+                    .map(|(position, error)| ScriptError {
+                        location: self.location(source, position),
+                        error,
+                    })
+                    .collect::<Vec<_>>()
+            })?;
+        if self.optimize {
+            program.instructions = optimize(program.instructions);
+        }
+        Ok(program)
+    }
+}
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Self::new("<script>")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::console_vm;
+    use crate::vm::VM;
+
+    /// Compiles and runs `source`, returning what it printed and the VM it ran on.
+    fn run(source: &str) -> (String, VM) {
+        execute(Compiler::new("test.es").compile(source).unwrap())
+    }
+
+    fn execute(program: Program) -> (String, VM) {
+        let (mut vm, output) = console_vm(b"");
+        vm.load_program(program.to_bytes().unwrap()).unwrap();
+        vm.run().unwrap();
+        let printed = String::from_utf8(output.contents()).unwrap();
+        (printed, vm)
+    }
+
+    #[test]
+    fn test_arithmetic_and_print() {
+        let (printed, _) = run("let a = 7;
+let b = a * 6 - 2;
+print b;
+print b / 3 + b % 3;
+print -a * (2 + 3);
+print 100000;
+print -2147483647;
+print -2147483647 - 1;
+print 1 < 2;
+print 1 >= 2;");
+        assert_eq!(
+            printed,
+            "40\n14\n-35\n100000\n-2147483647\n-2147483648\n1\n0\n"
+        );
+    }
+
+    #[test]
+    fn test_control_flow() {
+        let (printed, _) = run("let i = 0;
+let total = 0;
+while i < 5 {
+    i = i + 1;
+    if i % 2 == 0 {
+        total = total + i;
+    } else if i == 5 {
+        total = total * 10;
+    } else {
+        let i = 100; // a new variable, gone at the end of the block
+        total = total + i / 100;
+    }
+}
+print total;
+print i;");
+        // total: 1, 3, 4, 8, 80
+        assert_eq!(printed, "80\n5\n");
+    }
+
+    #[test]
+    fn test_functions() {
+        let (printed, vm) = run("fn gcd(a, b) {
+    while b != 0 {
+        let rest = a % b;
+        a = b;
+        b = rest;
+    }
+    return a;
+}
+
+fn factorial(n) {
+    if n <= 1 {
+        return 1;
+    }
+    return n * factorial(n - 1);
+}
+
+fn nothing() {
+    print 7;
+}
+
+print gcd(12, 18);
+print factorial(10);
+print factorial(3) + gcd(factorial(4), 36) * 2;
+print nothing();");
+        assert_eq!(printed, "6\n3628800\n30\n7\n0\n");
+        // Every frame was popped again
+        assert_eq!(vm.registers[30], 16);
+    }
+
+    #[test]
+    fn test_spilling() {
+        // More variables than registers, and more arguments and nesting than temporaries
+        let mut source = String::new();
+        for index in 0..20 {
+            source.push_str(&format!("let v{} = {};\n", index, index));
+        }
+        let sum: Vec<String> = (0..20).map(|index| format!("v{}", index)).collect();
+        source.push_str(&format!("print {};\n", sum.join(" + ")));
+        source
+            .push_str("print v19 - (v18 - (v17 - (v16 - (v15 - (v14 - (v13 - (v12 - v11)))))));\n");
+        source.push_str(
+            "fn many(a, b, c, d, e, f, g, h) { return a + b * (c + d * (e + f * (g + h))); }\n",
+        );
+        source.push_str("print many(v1, v2, v3, v4, v5, v6, v7, v8);\n");
+        let (printed, _) = run(&source);
+        // 19 - (18 - (17 - ... (12 - 11)))) = 15; 1 + 2 * (3 + 4 * (5 + 6 * (7 + 8)))
+        assert_eq!(printed, "190\n15\n767\n");
+    }
+
+    #[test]
+    fn test_optimized_scripts_run_the_same() {
+        let source = "fn collatz(n) {
+    let steps = 0;
+    while n != 1 {
+        if n % 2 == 0 { n = n / 2; } else { n = 3 * n + 1; }
+        steps = steps + 1;
+    }
+    return steps;
+}
+let total = 2 * 3 + 4;
+let i = 1;
+while i <= total {
+    print collatz(i) - (1 + 1);
+    i = i + 1;
+}";
+        let mut compiler = Compiler::new("test.es");
+        compiler.optimize = false;
+        let plain = compiler.compile(source).unwrap();
+        compiler.optimize = true;
+        let optimized = compiler.compile(source).unwrap();
+        assert!(optimized.instructions.len() < plain.instructions.len());
+        let (plain_printed, plain_vm) = execute(plain);
+        let (printed, vm) = execute(optimized);
+        assert_eq!(printed, plain_printed);
+        assert!(printed.starts_with("-2\n-1\n5\n0\n3\n"));
+        // Apart from the code addresses left in $27, $29 and $ra, which move with the code
+        let (mut registers, mut plain_registers) = (vm.registers, plain_vm.registers);
+        for register in [27, 29, 31] {
+            registers[register] = 0;
+            plain_registers[register] = 0;
+        }
+        assert_eq!(registers, plain_registers);
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        let compiler = Compiler::new("test.es");
+        let messages = |source| -> Vec<String> {
+            compiler
+                .compile(source)
+                .unwrap_err()
+                .iter()
+                .map(|error| error.to_string())
+                .collect()
+        };
+        assert_eq!(
+            messages("let a = 1;\nprint a +;"),
+            vec!["test.es:2:10: expected an expression"]
+        );
+        assert_eq!(
+            messages(
+                "fn f(a, a) { return b; }
+fn f() {}
+let x = 1;
+let x = g(2);
+print f(1);
+return 4;
+print 4294967296;"
+            ),
+            vec![
+                "test.es:1:9: variable a is already declared",
+                "test.es:1:21: variable b is never declared",
+                "test.es:2:1: function f is already defined",
+                "test.es:4:5: variable x is already declared",
+                "test.es:4:9: function g is never defined",
+                "test.es:5:7: function f takes 2 argument(s), not 1",
+                "test.es:6:1: return outside a function",
+                "test.es:7:7: 4294967296 does not fit in a 32-bit integer",
+            ]
+        );
+    }
+}
@@ -0,0 +1,257 @@
+use nom::{
+    branch::alt,
+    bytes::complete::tag,
+    combinator::{cut, eof, map, not, opt},
+    multi::many0,
+    sequence::{delimited, preceded, terminated, tuple},
+};
+
+use super::expression_parsers::{
+    blank, expect, expression, keyword, list, name, symbol, ParseResult, SyntaxError,
+};
+use super::{Function, Script, Statement};
+
+fn semicolon(input: &str) -> ParseResult<'_, &str> {
+    cut(expect("`;`", symbol(";")))(input)
+}
+
+fn condition(input: &str) -> ParseResult<'_, super::Expression> {
+    cut(expect("a condition", expression))(input)
+}
+
+// { statements }
+fn block(input: &str) -> ParseResult<'_, Vec<Statement>> {
+    preceded(
+        cut(expect("`{`", symbol("{"))),
+        cut(terminated(many0(statement), expect("`}`", symbol("}")))),
+    )(input)
+}
+
+// let total = a + 1;
+fn let_statement(input: &str) -> ParseResult<'_, Statement> {
+    let (input, _) = keyword("let")(input)?;
+    let (input, (name, position)) = cut(expect("a variable name", name))(input)?;
+    let (input, _) = cut(expect("`=`", symbol("=")))(input)?;
+    let (input, value) = cut(expect("an expression", expression))(input)?;
+    let (input, _) = semicolon(input)?;
+    Ok((
+        input,
+        Statement::Let {
+            name,
+            value,
+            position,
+        },
+    ))
+}
+
+// total = total + 1;
+fn assignment(input: &str) -> ParseResult<'_, Statement> {
+    let (input, (name, position)) = name(input)?;
+    let (input, _) = terminated(symbol("="), not(tag("=")))(input)?;
+    let (input, value) = cut(expect("an expression", expression))(input)?;
+    let (input, _) = semicolon(input)?;
+    Ok((
+        input,
+        Statement::Assign {
+            name,
+            value,
+            position,
+        },
+    ))
+}
+
+// if a < b { ... } else if a == b { ... } else { ... }
+fn if_statement(input: &str) -> ParseResult<'_, Statement> {
+    let (input, _) = keyword("if")(input)?;
+    let (input, (condition, then)) = tuple((condition, block))(input)?;
+    let (input, otherwise) = opt(preceded(
+        keyword("else"),
+        cut(alt((map(if_statement, |statement| vec![statement]), block))),
+    ))(input)?;
+    Ok((
+        input,
+        Statement::If {
+            condition,
+            then,
+            otherwise: otherwise.unwrap_or_default(),
+        },
+    ))
+}
+
+// while i < 10 { ... }
+fn while_statement(input: &str) -> ParseResult<'_, Statement> {
+    let (input, _) = keyword("while")(input)?;
+    let (input, (condition, body)) = tuple((condition, block))(input)?;
+    Ok((input, Statement::While { condition, body }))
+}
+
+// print a * 2;
+fn print_statement(input: &str) -> ParseResult<'_, Statement> {
+    let (input, _) = keyword("print")(input)?;
+    let (input, value) = cut(expect("an expression", expression))(input)?;
+    let (input, _) = semicolon(input)?;
+    Ok((input, Statement::Print(value)))
+}
+
+// return a; return;
+fn return_statement(input: &str) -> ParseResult<'_, Statement> {
+    let (input, _) = blank(input)?;
+    let position = input.len();
+    let (input, _) = keyword("return")(input)?;
+    let (input, value) = opt(expression)(input)?;
+    let (input, _) = semicolon(input)?;
+    Ok((input, Statement::Return { value, position }))
+}
+
+// f(a);
+fn expression_statement(input: &str) -> ParseResult<'_, Statement> {
+    let (input, value) = expression(input)?;
+    let (input, _) = semicolon(input)?;
+    Ok((input, Statement::Expression(value)))
+}
+
+pub fn statement(input: &str) -> ParseResult<'_, Statement> {
+    expect(
+        "a statement",
+        alt((
+            let_statement,
+            if_statement,
+            while_statement,
+            print_statement,
+            return_statement,
+            assignment,
+            expression_statement,
+        )),
+    )(input)
+}
+
+// fn gcd(a, b) { ... }
+fn function(input: &str) -> ParseResult<'_, Function> {
+    let (input, _) = blank(input)?;
+    let position = input.len();
+    let (input, _) = keyword("fn")(input)?;
+    let (input, (function_name, _)) = cut(expect("a function name", name))(input)?;
+    let (input, parameters) = cut(delimited(
+        expect("`(`", symbol("(")),
+        list("a parameter name", name),
+        expect("`)`", symbol(")")),
+    ))(input)?;
+    let (input, body) = block(input)?;
+    Ok((
+        input,
+        Function {
+            name: function_name,
+            parameters,
+            body,
+            position,
+        },
+    ))
+}
+
+/// A whole script: functions, which may come in any order, and the statements that run, in
+/// order, when the script starts.
+pub fn script(input: &str) -> Result<Script, SyntaxError<'_>> {
+    let mut script = Script::default();
+    let mut input = input;
+    loop {
+        if let Ok((rest, _)) = tuple((blank, eof::<&str, SyntaxError>))(input) {
+            input = rest;
+            break;
+        }
+        let parsed = match function(input) {
+            Ok((rest, function)) => {
+                script.functions.push(function);
+                Ok(rest)
+            }
+            Err(nom::Err::Error(_)) => statement(input).map(|(rest, statement)| {
+                script.statements.push(statement);
+                rest
+            }),
+            Err(error) => Err(error),
+        };
+        input = match parsed {
+            Ok(rest) => rest,
+            Err(nom::Err::Error(error)) | Err(nom::Err::Failure(error)) => return Err(error),
+            Err(nom::Err::Incomplete(_)) => unreachable!("complete parsers never ask for more"),
+        };
+    }
+    debug_assert!(input.is_empty());
+    Ok(script)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Expression;
+    use super::*;
+
+    fn number(value: i64, position: usize) -> Expression {
+        Expression::Number { value, position }
+    }
+
+    #[test]
+    fn test_parse_statements() {
+        let source = "let a = 1;\nif a { a = 2; } else if 0 { } else { print a; }\nreturn;";
+        let (rest, parsed) = many0(statement)(source).unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(
+            parsed,
+            vec![
+                Statement::Let {
+                    name: String::from("a"),
+                    value: number(1, 58),
+                    position: 62,
+                },
+                Statement::If {
+                    condition: Expression::Variable {
+                        name: String::from("a"),
+                        position: 52,
+                    },
+                    then: vec![Statement::Assign {
+                        name: String::from("a"),
+                        value: number(2, 44),
+                        position: 48,
+                    }],
+                    otherwise: vec![Statement::If {
+                        condition: number(0, 31),
+                        then: vec![],
+                        otherwise: vec![Statement::Print(Expression::Variable {
+                            name: String::from("a"),
+                            position: 12,
+                        })],
+                    }],
+                },
+                Statement::Return {
+                    value: None,
+                    position: 7,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_script() {
+        let source = "print twice(2);\n// doubles n\nfn twice(n) {\n    return n * 2;\n}\n";
+        let parsed = script(source).unwrap();
+        assert_eq!(parsed.statements.len(), 1);
+        assert_eq!(parsed.functions.len(), 1);
+        let function = &parsed.functions[0];
+        assert_eq!(function.name, "twice");
+        assert_eq!(function.parameters, vec![(String::from("n"), 25)]);
+        assert_eq!(function.body.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_script_errors() {
+        let error = |source: &'static str| {
+            let error = script(source).unwrap_err();
+            (source.len() - error.input.len(), error.expected)
+        };
+        assert_eq!(error("let x = 1"), (9, "`;`"));
+        assert_eq!(error("let = 1;"), (3, "a variable name"));
+        assert_eq!(error("while x < 3 print x;"), (11, "`{`"));
+        assert_eq!(error("fn f(a, 1) {}"), (7, "a parameter name"));
+        assert_eq!(error("if x { print x; "), (15, "`}`"));
+        assert_eq!(error("x + ;"), (3, "an expression"));
+        assert_eq!(error("}"), (0, "a statement"));
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::debug_info::DebugInfo;
+use crate::instruction::Instruction;
+
+/// How often a conditional jump went each way.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Branch {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+/// Which instructions ran and which way each conditional jump went. Attach one to
+/// `VM::coverage` before a run; results accumulate across runs until it is replaced.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Coverage {
+    /// Whether an instruction starting at each byte offset has executed
+    executed: Vec<bool>,
+    branches: BTreeMap<usize, Branch>,
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+
+    pub fn record(&mut self, offset: usize) {
+        if offset >= self.executed.len() {
+            self.executed.resize(offset + 1, false);
+        }
+        self.executed[offset] = true;
+    }
+
+    pub fn record_branch(&mut self, offset: usize, taken: bool) {
+        let branch = self.branches.entry(offset).or_default();
+        if taken {
+            branch.taken += 1;
+        } else {
+            branch.not_taken += 1;
+        }
+    }
+
+    pub fn executed(&self, offset: usize) -> bool {
+        self.executed.get(offset).copied().unwrap_or(false)
+    }
+
+    pub fn branch(&self, offset: usize) -> Option<Branch> {
+        self.branches.get(&offset).copied()
+    }
+}
+
+/// Where one instruction of the source ended up.
+struct Placement {
+    line: usize,
+    offset: usize,
+    is_conditional_jump: bool,
+}
+
+/// The instructions `debug_info` places in its first file, which is the one being annotated.
+fn place_instructions(program: &[u8], debug_info: &DebugInfo) -> Vec<Placement> {
+    debug_info
+        .lines
+        .iter()
+        .filter(|entry| entry.file == 0)
+        .map(|entry| Placement {
+            line: entry.line,
+            offset: entry.offset,
+            is_conditional_jump: program
+                .get(entry.offset..)
+                .and_then(Instruction::decode)
+                .is_some_and(|instruction| instruction.opcode.is_conditional_jump()),
+        })
+        .collect()
+}
+
+fn percentage(part: usize, whole: usize) -> f64 {
+    if whole == 0 {
+        100.0
+    } else {
+        100.0 * part as f64 / whole as f64
+    }
+}
+
+/// An annotated listing of `source` followed by summary percentages. Each line is marked `+`
+/// if all of its instructions ran, `-` if none did, `~` if only some did, and left blank if it
+/// has no code; conditional jumps also show how often they went each way. `program` and
+/// `debug_info` are what the assembler made from `source`.
+pub fn report(source: &str, program: &[u8], debug_info: &DebugInfo, coverage: &Coverage) -> String {
+    let placements = place_instructions(program, debug_info);
+    let mut report = String::new();
+    for (index, text) in source.lines().enumerate() {
+        let on_line: Vec<&Placement> = placements
+            .iter()
+            .filter(|placement| placement.line == index + 1)
+            .collect();
+        let ran = on_line
+            .iter()
+            .filter(|placement| coverage.executed(placement.offset))
+            .count();
+        let marker = match (ran, on_line.len()) {
+            (_, 0) => ' ',
+            (0, _) => '-',
+            (ran, total) if ran == total => '+',
+            _ => '~',
+        };
+        let mut line = format!("{} {:>4} | {}", marker, index + 1, text);
+        for placement in on_line
+            .iter()
+            .filter(|placement| placement.is_conditional_jump)
+        {
+            let branch = coverage.branch(placement.offset).unwrap_or_default();
+            write!(
+                line,
+                "    [taken {}, not taken {}]",
+                branch.taken, branch.not_taken
+            )
+            .unwrap();
+        }
+        writeln!(report, "{}", line.trim_end()).unwrap();
+    }
+
+    let instructions_run = placements
+        .iter()
+        .filter(|placement| coverage.executed(placement.offset))
+        .count();
+    let branches: Vec<Branch> = placements
+        .iter()
+        .filter(|placement| placement.is_conditional_jump)
+        .map(|placement| coverage.branch(placement.offset).unwrap_or_default())
+        .collect();
+    let outcomes_seen: usize = branches
+        .iter()
+        .map(|branch| (branch.taken > 0) as usize + (branch.not_taken > 0) as usize)
+        .sum();
+    writeln!(
+        report,
+        "\nInstructions: {}/{} ({:.1}%)",
+        instructions_run,
+        placements.len(),
+        percentage(instructions_run, placements.len())
+    )
+    .unwrap();
+    writeln!(
+        report,
+        "Branch outcomes: {}/{} ({:.1}%)",
+        outcomes_seen,
+        branches.len() * 2,
+        percentage(outcomes_seen, branches.len() * 2)
+    )
+    .unwrap();
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::vm::VM;
+
+    const SOURCE: &str = "load $1 #3
+load $2 #8
+
+increment $0
+jumpifless $0 $1 $2
+jumpifequal $0 $0 $3
+load $5 #1
+";
+
+    fn covered() -> (Vec<u8>, DebugInfo, Coverage) {
+        let mut assembler = Assembler::new("test.iasm");
+        assembler.emit_debug_info = true;
+        let assembled = assembler.assemble(SOURCE).unwrap();
+        let mut test_vm = VM::new();
+        test_vm.coverage = Some(Coverage::new());
+        test_vm.registers[3] = 22;
+        test_vm.program = assembled.program.clone();
+        test_vm.run().unwrap();
+        (
+            assembled.program,
+            assembled.debug_info.unwrap(),
+            test_vm.coverage.unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_coverage_records_branches() {
+        let (_, _, coverage) = covered();
+        assert!(coverage.executed(8));
+        assert!(!coverage.executed(9));
+        assert!(!coverage.executed(18));
+        assert_eq!(
+            coverage.branch(10),
+            Some(Branch {
+                taken: 2,
+                not_taken: 1
+            })
+        );
+        assert_eq!(
+            coverage.branch(14),
+            Some(Branch {
+                taken: 1,
+                not_taken: 0
+            })
+        );
+    }
+
+    #[test]
+    fn test_coverage_report() {
+        let (program, debug_info, coverage) = covered();
+        assert_eq!(
+            report(SOURCE, &program, &debug_info, &coverage),
+            "+    1 | load $1 #3
++    2 | load $2 #8
+     3 |
++    4 | increment $0
++    5 | jumpifless $0 $1 $2    [taken 2, not taken 1]
++    6 | jumpifequal $0 $0 $3    [taken 1, not taken 0]
+-    7 | load $5 #1
+
+Instructions: 5/6 (83.3%)
+Branch outcomes: 3/4 (75.0%)
+"
+        );
+    }
+}
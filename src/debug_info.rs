@@ -0,0 +1,196 @@
+use std::fmt;
+
+/// A position in assembly source. Lines and columns count from 1.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Location {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+/// Where the instruction starting at `offset` was written; `file` indexes `DebugInfo::files`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LineEntry {
+    pub offset: usize,
+    pub file: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+/// Marks the start of a serialized debug section.
+const MAGIC: &[u8; 4] = b"EDBG";
+
+/// What the assembler knew about a program that the bytecode alone does not record: the source
+/// position of every instruction and the offset of every label.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DebugInfo {
+    pub files: Vec<String>,
+    /// Sorted by offset
+    pub lines: Vec<LineEntry>,
+    /// Label names and offsets, sorted by offset
+    pub labels: Vec<(String, usize)>,
+}
+
+impl DebugInfo {
+    pub fn new() -> DebugInfo {
+        DebugInfo::default()
+    }
+
+    /// The source of the instruction at or containing `offset`.
+    pub fn location(&self, offset: usize) -> Option<Location> {
+        let index = self.lines.partition_point(|entry| entry.offset <= offset);
+        let entry = self.lines.get(index.checked_sub(1)?)?;
+        Some(Location {
+            file: self.files.get(entry.file)?.clone(),
+            line: entry.line,
+            column: entry.column,
+        })
+    }
+
+    pub fn label_offset(&self, name: &str) -> Option<usize> {
+        self.labels
+            .iter()
+            .find(|(label, _)| label == name)
+            .map(|(_, offset)| *offset)
+    }
+
+    /// The labels declared exactly at `offset`.
+    pub fn labels_at(&self, offset: usize) -> impl Iterator<Item = &str> {
+        self.labels
+            .iter()
+            .filter(move |(_, start)| *start == offset)
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// The debug section: `EDBG`, then the files, line entries and labels, each list prefixed
+    /// by its length. Numbers are big-endian `u32`s and strings are length-prefixed UTF-8.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        push_number(&mut bytes, self.files.len());
+        for file in &self.files {
+            push_string(&mut bytes, file);
+        }
+        push_number(&mut bytes, self.lines.len());
+        for entry in &self.lines {
+            for number in [entry.offset, entry.file, entry.line, entry.column] {
+                push_number(&mut bytes, number);
+            }
+        }
+        push_number(&mut bytes, self.labels.len());
+        for (name, offset) in &self.labels {
+            push_string(&mut bytes, name);
+            push_number(&mut bytes, *offset);
+        }
+        bytes
+    }
+
+    /// Reads a section written by `to_bytes`, or returns `None` if `bytes` is not one.
+    pub fn from_bytes(bytes: &[u8]) -> Option<DebugInfo> {
+        let mut reader = Reader {
+            bytes: bytes.strip_prefix(MAGIC)?,
+        };
+        let mut debug_info = DebugInfo::new();
+        for _ in 0..reader.number()? {
+            debug_info.files.push(reader.string()?);
+        }
+        for _ in 0..reader.number()? {
+            debug_info.lines.push(LineEntry {
+                offset: reader.number()?,
+                file: reader.number()?,
+                line: reader.number()?,
+                column: reader.number()?,
+            });
+        }
+        for _ in 0..reader.number()? {
+            let name = reader.string()?;
+            debug_info.labels.push((name, reader.number()?));
+        }
+        reader.bytes.is_empty().then_some(debug_info)
+    }
+}
+
+pub(crate) fn push_number(bytes: &mut Vec<u8>, number: usize) {
+    bytes.extend((number as u32).to_be_bytes());
+}
+
+pub(crate) fn push_string(bytes: &mut Vec<u8>, string: &str) {
+    push_number(bytes, string.len());
+    bytes.extend(string.as_bytes());
+}
+
+/// Reads what `push_number` and `push_string` wrote, failing on truncated input.
+pub(crate) struct Reader<'a> {
+    pub(crate) bytes: &'a [u8],
+}
+
+impl Reader<'_> {
+    pub(crate) fn take(&mut self, length: usize) -> Option<&[u8]> {
+        if self.bytes.len() < length {
+            return None;
+        }
+        let (taken, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Some(taken)
+    }
+
+    pub(crate) fn number(&mut self) -> Option<usize> {
+        let bytes = self.take(4)?;
+        Some(u32::from_be_bytes(bytes.try_into().ok()?) as usize)
+    }
+
+    pub(crate) fn string(&mut self) -> Option<String> {
+        let length = self.number()?;
+        String::from_utf8(self.take(length)?.to_vec()).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> DebugInfo {
+        DebugInfo {
+            files: vec![String::from("main.iasm")],
+            lines: vec![
+                LineEntry {
+                    offset: 0,
+                    file: 0,
+                    line: 1,
+                    column: 1,
+                },
+                LineEntry {
+                    offset: 4,
+                    file: 0,
+                    line: 3,
+                    column: 7,
+                },
+            ],
+            labels: vec![(String::from("loop"), 4)],
+        }
+    }
+
+    #[test]
+    fn test_location_covers_whole_instruction() {
+        let debug_info = sample();
+        assert_eq!(debug_info.location(2).unwrap().to_string(), "main.iasm:1:1");
+        assert_eq!(debug_info.location(4).unwrap().to_string(), "main.iasm:3:7");
+        assert_eq!(DebugInfo::new().location(0), None);
+        assert_eq!(debug_info.label_offset("loop"), Some(4));
+        assert_eq!(debug_info.labels_at(4).collect::<Vec<_>>(), vec!["loop"]);
+    }
+
+    #[test]
+    fn test_debug_section_round_trip() {
+        let bytes = sample().to_bytes();
+        assert!(bytes.starts_with(b"EDBG"));
+        assert_eq!(DebugInfo::from_bytes(&bytes), Some(sample()));
+        assert_eq!(DebugInfo::from_bytes(&bytes[..bytes.len() - 1]), None);
+        assert_eq!(DebugInfo::from_bytes(b"ELF"), None);
+    }
+}
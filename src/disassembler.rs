@@ -0,0 +1,93 @@
+use std::fmt::Write;
+
+use crate::debug_info::DebugInfo;
+use crate::instruction::{Instruction, Opcode, OperandKind};
+
+/// Where the source comment starts on each disassembled line.
+const COMMENT_COLUMN: usize = 36;
+
+/// `instruction` in assembler syntax.
+pub fn instruction_text(instruction: &Instruction) -> String {
+    let mut text = format!("{:?}", instruction.opcode).to_lowercase();
+    let mut registers = instruction.registers.iter();
+    for kind in instruction.opcode.operands() {
+        match kind {
+            OperandKind::Register => write!(text, " ${}", registers.next().unwrap()),
+            OperandKind::FloatRegister => write!(text, " $f{}", registers.next().unwrap()),
+            OperandKind::Integer => write!(text, " #{}", instruction.immediate as u16),
+            OperandKind::Float => write!(text, " #{:?}", f64::from_bits(instruction.immediate)),
+        }
+        .unwrap();
+    }
+    text
+}
+
+/// One line per instruction: its offset and text, preceded by any labels declared there and
+/// followed by its source location when `debug_info` is given. Bytes that do not decode are
+/// listed one at a time as `.byte`.
+pub fn disassemble(program: &[u8], debug_info: Option<&DebugInfo>) -> String {
+    let mut listing = String::new();
+    let mut offset = 0;
+    while offset < program.len() {
+        let decoded = Instruction::decode(&program[offset..])
+            .filter(|instruction| instruction.opcode != Opcode::ILLEGAL);
+        let (text, width) = match decoded {
+            Some(instruction) => (instruction_text(&instruction), instruction.width()),
+            None => (format!(".byte {}", program[offset]), 1),
+        };
+        let mut line = format!("{:>6}  {}", offset, text);
+        if let Some(debug_info) = debug_info {
+            for label in debug_info.labels_at(offset) {
+                writeln!(listing, "{}:", label).unwrap();
+            }
+            if decoded.is_some() {
+                if let Some(location) = debug_info.location(offset) {
+                    line = format!("{:<width$}; {}", line, location, width = COMMENT_COLUMN);
+                }
+            }
+        }
+        writeln!(listing, "{}", line).unwrap();
+        offset += width;
+    }
+    listing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    #[test]
+    fn test_disassemble_plain_bytes() {
+        let mut program = vec![1, 1, 0, 3, 2, 0, 1, 2, 16, 4];
+        program.extend(2.5f64.to_be_bytes());
+        program.extend([200, 1, 0]);
+        assert_eq!(
+            disassemble(&program, None),
+            "     0  load $1 #3
+     4  add $0 $1 $2
+     8  loadf64 $f4 #2.5
+    18  .byte 200
+    19  .byte 1
+    20  halt
+"
+        );
+    }
+
+    #[test]
+    fn test_disassemble_with_debug_info() {
+        let mut assembler = Assembler::new("main.iasm");
+        assembler.emit_debug_info = true;
+        let assembled = assembler
+            .assemble("load $2 @loop\nloop:\n  increment $0\n  jumpifless $0 $1 $2\n")
+            .unwrap();
+        assert_eq!(
+            disassemble(&assembled.program, assembled.debug_info.as_ref()),
+            "     0  load $2 #4                  ; main.iasm:1:1
+loop:
+     4  increment $0                ; main.iasm:3:3
+     6  jumpifless $0 $1 $2         ; main.iasm:4:3
+"
+        );
+    }
+}
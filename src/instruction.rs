@@ -1,4 +1,8 @@
-#[derive(Clone, Copy, Debug, PartialEq)]
+use std::fmt;
+
+use crate::vm::REGISTER_COUNT;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum Opcode {
     HALT,
     LOAD,
@@ -16,6 +20,48 @@ pub enum Opcode {
     GREATEREQUAL,
     LESSEQUAL,
     JUMPIF,
+    LOADF64,
+    ADDF64,
+    SUBTRACTF64,
+    MULTIPLYF64,
+    DIVIDEF64,
+    EQUALF64,
+    NOTEQUALF64,
+    GREATERF64,
+    LESSF64,
+    GREATEREQUALF64,
+    LESSEQUALF64,
+    INTTOFLOAT,
+    FLOATTOINT,
+    GETREMAINDER,
+    MOVE,
+    INCREMENT,
+    DECREMENT,
+    ADDIMMEDIATE,
+    SUBTRACTIMMEDIATE,
+    JUMPIFNOT,
+    JUMPFORWARDIF,
+    JUMPFORWARDIFNOT,
+    JUMPBACKWARDIF,
+    JUMPBACKWARDIFNOT,
+    JUMPIFEQUAL,
+    JUMPIFNOTEQUAL,
+    JUMPIFGREATER,
+    JUMPIFLESS,
+    JUMPIFGREATEREQUAL,
+    JUMPIFLESSEQUAL,
+    CALLNATIVE,
+    ALLOCATE,
+    LOADBYTE,
+    STOREBYTE,
+    SYSCALL,
+    /// Loads the address a signed distance from the end of the instruction, so that code can
+    /// refer to itself wherever it is placed
+    LOADRELATIVE,
+    /// Loads the 4 bytes at a heap address as a big-endian integer
+    LOADWORD,
+    /// Stores an integer as 4 big-endian bytes at a heap address
+    STOREWORD,
     ILLEGAL,
 }
 
@@ -38,6 +84,44 @@ impl From<u8> for Opcode {
             13 => Opcode::GREATEREQUAL,
             14 => Opcode::LESSEQUAL,
             15 => Opcode::JUMPIF,
+            16 => Opcode::LOADF64,
+            17 => Opcode::ADDF64,
+            18 => Opcode::SUBTRACTF64,
+            19 => Opcode::MULTIPLYF64,
+            20 => Opcode::DIVIDEF64,
+            21 => Opcode::EQUALF64,
+            22 => Opcode::NOTEQUALF64,
+            23 => Opcode::GREATERF64,
+            24 => Opcode::LESSF64,
+            25 => Opcode::GREATEREQUALF64,
+            26 => Opcode::LESSEQUALF64,
+            27 => Opcode::INTTOFLOAT,
+            28 => Opcode::FLOATTOINT,
+            29 => Opcode::GETREMAINDER,
+            30 => Opcode::MOVE,
+            31 => Opcode::INCREMENT,
+            32 => Opcode::DECREMENT,
+            33 => Opcode::ADDIMMEDIATE,
+            34 => Opcode::SUBTRACTIMMEDIATE,
+            35 => Opcode::JUMPIFNOT,
+            36 => Opcode::JUMPFORWARDIF,
+            37 => Opcode::JUMPFORWARDIFNOT,
+            38 => Opcode::JUMPBACKWARDIF,
+            39 => Opcode::JUMPBACKWARDIFNOT,
+            40 => Opcode::JUMPIFEQUAL,
+            41 => Opcode::JUMPIFNOTEQUAL,
+            42 => Opcode::JUMPIFGREATER,
+            43 => Opcode::JUMPIFLESS,
+            44 => Opcode::JUMPIFGREATEREQUAL,
+            45 => Opcode::JUMPIFLESSEQUAL,
+            46 => Opcode::CALLNATIVE,
+            47 => Opcode::ALLOCATE,
+            48 => Opcode::LOADBYTE,
+            49 => Opcode::STOREBYTE,
+            50 => Opcode::SYSCALL,
+            51 => Opcode::LOADRELATIVE,
+            52 => Opcode::LOADWORD,
+            53 => Opcode::STOREWORD,
             _ => Opcode::ILLEGAL,
         }
     }
@@ -62,19 +146,243 @@ impl From<&str> for Opcode {
             "greaterequal" => Opcode::GREATEREQUAL,
             "lessequal" => Opcode::LESSEQUAL,
             "jumpif" => Opcode::JUMPIF,
+            "loadf64" => Opcode::LOADF64,
+            "addf64" => Opcode::ADDF64,
+            "subtractf64" => Opcode::SUBTRACTF64,
+            "multiplyf64" => Opcode::MULTIPLYF64,
+            "dividef64" => Opcode::DIVIDEF64,
+            "equalf64" => Opcode::EQUALF64,
+            "notequalf64" => Opcode::NOTEQUALF64,
+            "greaterf64" => Opcode::GREATERF64,
+            "lessf64" => Opcode::LESSF64,
+            "greaterequalf64" => Opcode::GREATEREQUALF64,
+            "lessequalf64" => Opcode::LESSEQUALF64,
+            "inttofloat" => Opcode::INTTOFLOAT,
+            "floattoint" => Opcode::FLOATTOINT,
+            "getremainder" => Opcode::GETREMAINDER,
+            "move" => Opcode::MOVE,
+            "increment" => Opcode::INCREMENT,
+            "decrement" => Opcode::DECREMENT,
+            "addimmediate" => Opcode::ADDIMMEDIATE,
+            "subtractimmediate" => Opcode::SUBTRACTIMMEDIATE,
+            "jumpifnot" => Opcode::JUMPIFNOT,
+            "jumpforwardif" => Opcode::JUMPFORWARDIF,
+            "jumpforwardifnot" => Opcode::JUMPFORWARDIFNOT,
+            "jumpbackwardif" => Opcode::JUMPBACKWARDIF,
+            "jumpbackwardifnot" => Opcode::JUMPBACKWARDIFNOT,
+            "jumpifequal" => Opcode::JUMPIFEQUAL,
+            "jumpifnotequal" => Opcode::JUMPIFNOTEQUAL,
+            "jumpifgreater" => Opcode::JUMPIFGREATER,
+            "jumpifless" => Opcode::JUMPIFLESS,
+            "jumpifgreaterequal" => Opcode::JUMPIFGREATEREQUAL,
+            "jumpiflessequal" => Opcode::JUMPIFLESSEQUAL,
+            "callnative" => Opcode::CALLNATIVE,
+            "allocate" => Opcode::ALLOCATE,
+            "loadbyte" => Opcode::LOADBYTE,
+            "storebyte" => Opcode::STOREBYTE,
+            "syscall" => Opcode::SYSCALL,
+            "loadrelative" => Opcode::LOADRELATIVE,
+            "loadword" => Opcode::LOADWORD,
+            "storeword" => Opcode::STOREWORD,
             _ => Opcode::ILLEGAL,
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
+/// What follows an opcode byte in the encoded program.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OperandKind {
+    Register,
+    FloatRegister,
+    /// 16 bits, big-endian
+    Integer,
+    /// 64 bits, big-endian
+    Float,
+}
+
+impl fmt::Display for OperandKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OperandKind::Register => write!(f, "an integer register"),
+            OperandKind::FloatRegister => write!(f, "a float register"),
+            OperandKind::Integer => write!(f, "an integer"),
+            OperandKind::Float => write!(f, "a float, such as #2.0"),
+        }
+    }
+}
+
+impl OperandKind {
+    pub fn width(self) -> usize {
+        match self {
+            OperandKind::Register | OperandKind::FloatRegister => 1,
+            OperandKind::Integer => 2,
+            OperandKind::Float => 8,
+        }
+    }
+}
+
+impl Opcode {
+    /// The operands this opcode is encoded with, in order.
+    pub fn operands(self) -> &'static [OperandKind] {
+        use OperandKind::*;
+        match self {
+            Opcode::HALT | Opcode::ILLEGAL => &[],
+            Opcode::LOAD | Opcode::LOADRELATIVE => &[Register, Integer],
+            Opcode::ADD | Opcode::SUBTRACT | Opcode::MULTIPLY | Opcode::DIVIDE => {
+                &[Register, Register, Register]
+            }
+            Opcode::JUMP
+            | Opcode::JUMPFORWARD
+            | Opcode::JUMPBACKWARD
+            | Opcode::JUMPIF
+            | Opcode::JUMPIFNOT
+            | Opcode::JUMPFORWARDIF
+            | Opcode::JUMPFORWARDIFNOT
+            | Opcode::JUMPBACKWARDIF
+            | Opcode::JUMPBACKWARDIFNOT
+            | Opcode::GETREMAINDER
+            | Opcode::INCREMENT
+            | Opcode::DECREMENT
+            | Opcode::ALLOCATE => &[Register],
+            Opcode::EQUAL
+            | Opcode::NOTEQUAL
+            | Opcode::GREATER
+            | Opcode::LESS
+            | Opcode::GREATEREQUAL
+            | Opcode::LESSEQUAL
+            | Opcode::MOVE
+            | Opcode::LOADBYTE
+            | Opcode::STOREBYTE
+            | Opcode::LOADWORD
+            | Opcode::STOREWORD => &[Register, Register],
+            Opcode::LOADF64 => &[FloatRegister, Float],
+            Opcode::ADDF64 | Opcode::SUBTRACTF64 | Opcode::MULTIPLYF64 | Opcode::DIVIDEF64 => {
+                &[FloatRegister, FloatRegister, FloatRegister]
+            }
+            Opcode::EQUALF64
+            | Opcode::NOTEQUALF64
+            | Opcode::GREATERF64
+            | Opcode::LESSF64
+            | Opcode::GREATEREQUALF64
+            | Opcode::LESSEQUALF64 => &[FloatRegister, FloatRegister],
+            Opcode::INTTOFLOAT => &[Register, FloatRegister],
+            Opcode::FLOATTOINT => &[FloatRegister, Register],
+            Opcode::ADDIMMEDIATE | Opcode::SUBTRACTIMMEDIATE => &[Register, Register, Integer],
+            Opcode::JUMPIFEQUAL
+            | Opcode::JUMPIFNOTEQUAL
+            | Opcode::JUMPIFGREATER
+            | Opcode::JUMPIFLESS
+            | Opcode::JUMPIFGREATEREQUAL
+            | Opcode::JUMPIFLESSEQUAL => &[Register, Register, Register],
+            Opcode::CALLNATIVE | Opcode::SYSCALL => &[Integer],
+        }
+    }
+
+    /// Whether this opcode jumps only when some condition holds.
+    pub fn is_conditional_jump(self) -> bool {
+        matches!(
+            self,
+            Opcode::JUMPIF
+                | Opcode::JUMPIFNOT
+                | Opcode::JUMPFORWARDIF
+                | Opcode::JUMPFORWARDIFNOT
+                | Opcode::JUMPBACKWARDIF
+                | Opcode::JUMPBACKWARDIFNOT
+                | Opcode::JUMPIFEQUAL
+                | Opcode::JUMPIFNOTEQUAL
+                | Opcode::JUMPIFGREATER
+                | Opcode::JUMPIFLESS
+                | Opcode::JUMPIFGREATEREQUAL
+                | Opcode::JUMPIFLESSEQUAL
+        )
+    }
+
+    /// Which of its register operands this opcode overwrites, counting from 0, if it overwrites
+    /// an integer register named by one. Syscalls and native functions, which may set any
+    /// register, are not counted.
+    pub fn written_register(self) -> Option<usize> {
+        match self {
+            Opcode::LOAD
+            | Opcode::LOADRELATIVE
+            | Opcode::GETREMAINDER
+            | Opcode::INCREMENT
+            | Opcode::DECREMENT => Some(0),
+            Opcode::MOVE
+            | Opcode::FLOATTOINT
+            | Opcode::ADDIMMEDIATE
+            | Opcode::SUBTRACTIMMEDIATE
+            | Opcode::LOADBYTE
+            | Opcode::LOADWORD => Some(1),
+            Opcode::ADD | Opcode::SUBTRACT | Opcode::MULTIPLY | Opcode::DIVIDE => Some(2),
+            _ => None,
+        }
+    }
+
+    /// Encoded size of an instruction with this opcode, including the opcode byte.
+    pub fn width(self) -> usize {
+        1 + self
+            .operands()
+            .iter()
+            .map(|kind| kind.width())
+            .sum::<usize>()
+    }
+}
+
+/// A decoded instruction: its register operands in encoding order, and its integer or float
+/// operand (if the opcode has one) as raw bits.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Instruction {
-    opcode: Opcode,
+    pub opcode: Opcode,
+    pub registers: [u8; 3],
+    pub immediate: u64,
 }
 
 impl Instruction {
     pub fn new(opcode: Opcode) -> Instruction {
-        Instruction { opcode }
+        Instruction {
+            opcode,
+            registers: [0; 3],
+            immediate: 0,
+        }
+    }
+
+    /// Decodes the instruction at the start of `bytes`, or returns `None` if `bytes` is empty or
+    /// ends before the instruction's last operand.
+    pub fn decode(bytes: &[u8]) -> Option<Instruction> {
+        let mut instruction = Instruction::new(Opcode::from(*bytes.first()?));
+        if bytes.len() < instruction.width() {
+            return None;
+        }
+        let mut cursor = 1;
+        let mut registers = 0;
+        for kind in instruction.opcode.operands() {
+            let operand = &bytes[cursor..cursor + kind.width()];
+            match kind {
+                OperandKind::Register | OperandKind::FloatRegister => {
+                    instruction.registers[registers] = operand[0];
+                    registers += 1;
+                }
+                OperandKind::Integer | OperandKind::Float => {
+                    instruction.immediate = operand
+                        .iter()
+                        .fold(0, |value, byte| (value << 8) | *byte as u64);
+                }
+            }
+            cursor += kind.width();
+        }
+        Some(instruction)
+    }
+
+    pub fn width(&self) -> usize {
+        self.opcode.width()
+    }
+
+    /// The first register operand that names no register, if any does.
+    pub fn register_out_of_range(&self) -> Option<u8> {
+        self.registers
+            .iter()
+            .copied()
+            .find(|index| *index as usize >= REGISTER_COUNT)
     }
 }
 
@@ -100,7 +408,41 @@ mod tests {
         assert_eq!(opcode, Opcode::HALT);
         let opcode = Opcode::from("load");
         assert_eq!(opcode, Opcode::LOAD);
+        let opcode = Opcode::from("addf64");
+        assert_eq!(opcode, Opcode::ADDF64);
         let opcode = Opcode::from("store");
         assert_eq!(opcode, Opcode::ILLEGAL);
     }
+
+    #[test]
+    fn test_opcode_width() {
+        assert_eq!(Opcode::HALT.width(), 1);
+        assert_eq!(Opcode::LOAD.width(), 4);
+        assert_eq!(Opcode::EQUAL.width(), 3);
+        assert_eq!(Opcode::LOADF64.width(), 10);
+        assert_eq!(Opcode::ADDIMMEDIATE.width(), 5);
+    }
+
+    #[test]
+    fn test_written_register() {
+        assert_eq!(Opcode::LOAD.written_register(), Some(0));
+        assert_eq!(Opcode::MOVE.written_register(), Some(1));
+        assert_eq!(Opcode::DIVIDE.written_register(), Some(2));
+        assert_eq!(Opcode::STOREWORD.written_register(), None);
+        assert_eq!(Opcode::INTTOFLOAT.written_register(), None);
+    }
+
+    #[test]
+    fn test_decode_instruction() {
+        let instruction = Instruction::decode(&[33, 1, 2, 1, 44, 0]).unwrap();
+        assert_eq!(instruction.opcode, Opcode::ADDIMMEDIATE);
+        assert_eq!(instruction.registers, [1, 2, 0]);
+        assert_eq!(instruction.immediate, 300);
+
+        let instruction = Instruction::decode(&[200]).unwrap();
+        assert_eq!(instruction.opcode, Opcode::ILLEGAL);
+
+        assert_eq!(Instruction::decode(&[1, 0, 0]), None);
+        assert_eq!(Instruction::decode(&[]), None);
+    }
 }
@@ -0,0 +1,20 @@
+extern crate nom;
+
+pub mod assembler;
+pub mod compiler;
+pub mod coverage;
+pub mod debug_info;
+pub mod disassembler;
+pub mod instruction;
+pub mod linker;
+pub mod loader;
+pub mod native;
+pub mod object;
+pub mod profiler;
+pub mod repl;
+pub mod stdlib;
+pub mod syscall;
+#[cfg(test)]
+mod test_support;
+pub mod verifier;
+pub mod vm;
@@ -0,0 +1,312 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::object::{Library, Object, Target};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LinkError {
+    /// An import that no linked object exports
+    UnresolvedSymbol {
+        name: String,
+        object: String,
+    },
+    DuplicateSymbol {
+        name: String,
+        first: String,
+        second: String,
+    },
+    MissingEntry {
+        name: String,
+    },
+    /// An address past what a 16-bit operand can hold
+    AddressOutOfRange {
+        object: String,
+        address: usize,
+    },
+    /// A `LOADRELATIVE` too far from its target
+    DistanceOutOfRange {
+        object: String,
+        distance: i64,
+    },
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LinkError::UnresolvedSymbol { name, object } => {
+                write!(f, "{}: {} is imported but never exported", object, name)
+            }
+            LinkError::DuplicateSymbol {
+                name,
+                first,
+                second,
+            } => write!(f, "{} is exported by both {} and {}", name, first, second),
+            LinkError::MissingEntry { name } => {
+                write!(f, "no object exports the entry point {}", name)
+            }
+            LinkError::AddressOutOfRange { object, address } => write!(
+                f,
+                "{}: address {} does not fit in a 16-bit operand",
+                object, address
+            ),
+            LinkError::DistanceOutOfRange { object, distance } => write!(
+                f,
+                "{}: distance {} does not fit in a 16-bit operand",
+                object, distance
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LinkError {}
+
+/// A linked program and where to start running it, as `VM::load_program_at` takes them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Executable {
+    pub program: Vec<u8>,
+    pub entry: usize,
+}
+
+/// Appends `object` to `linked` and its exports to `symbols`, which maps each symbol to the
+/// index in `linked` of the object exporting it and its offset there.
+fn add<'a>(
+    object: &'a Object,
+    linked: &mut Vec<&'a Object>,
+    symbols: &mut HashMap<&'a str, (usize, usize)>,
+    errors: &mut Vec<LinkError>,
+) {
+    for (name, offset) in &object.exports {
+        match symbols.get(name.as_str()) {
+            Some((index, _)) => errors.push(LinkError::DuplicateSymbol {
+                name: name.clone(),
+                first: linked[*index].name.clone(),
+                second: object.name.clone(),
+            }),
+            None => {
+                symbols.insert(name, (linked.len(), *offset));
+            }
+        }
+    }
+    linked.push(object);
+}
+
+/// Joins objects into one program, placing them one after another in the order given and
+/// filling in every relocation.
+pub struct Linker {
+    /// The exported symbol the program starts at
+    pub entry: String,
+}
+
+impl Linker {
+    pub fn new() -> Linker {
+        Linker {
+            entry: String::from("main"),
+        }
+    }
+
+    /// Links every object in `objects`, plus whichever objects of `libraries` are needed to
+    /// resolve their imports. Libraries are searched in order, and an object pulled in from
+    /// one may itself pull in more.
+    pub fn link(
+        &self,
+        objects: &[Object],
+        libraries: &[Library],
+    ) -> Result<Executable, Vec<LinkError>> {
+        let mut linked = vec![];
+        let mut symbols = HashMap::new();
+        let mut errors = vec![];
+        for object in objects {
+            add(object, &mut linked, &mut symbols, &mut errors);
+        }
+        // Library objects the linked code asks for, until nothing more can be found
+        let mut taken = vec![];
+        loop {
+            let wanted = linked
+                .iter()
+                .flat_map(|object| &object.imports)
+                .filter(|name| !symbols.contains_key(name.as_str()))
+                .find_map(|name| {
+                    libraries
+                        .iter()
+                        .flat_map(|library| &library.objects)
+                        .enumerate()
+                        .find(|(index, object)| {
+                            !taken.contains(index) && object.export(name).is_some()
+                        })
+                });
+            match wanted {
+                Some((index, object)) => {
+                    taken.push(index);
+                    add(object, &mut linked, &mut symbols, &mut errors);
+                }
+                None => break,
+            }
+        }
+
+        let mut bases = vec![];
+        let mut program = vec![];
+        for object in &linked {
+            bases.push(program.len());
+            program.extend(&object.code);
+        }
+        let address = |name: &str| {
+            symbols
+                .get(name)
+                .map(|(index, offset)| bases[*index] + offset)
+        };
+        for (object, base) in linked.iter().zip(&bases) {
+            for name in &object.imports {
+                if address(name).is_none() {
+                    errors.push(LinkError::UnresolvedSymbol {
+                        name: name.clone(),
+                        object: object.name.clone(),
+                    });
+                }
+            }
+            for relocation in &object.relocations {
+                let target = match &relocation.target {
+                    Target::Local(offset) => base + offset,
+                    Target::Import(name) => match address(name) {
+                        Some(address) => address,
+                        // Already reported with the object's imports
+                        None => continue,
+                    },
+                };
+                let at = base + relocation.offset;
+                let value = if relocation.relative {
+                    let distance = target as i64 - (at + 2) as i64;
+                    i16::try_from(distance)
+                        .map(|distance| distance as u16)
+                        .map_err(|_| LinkError::DistanceOutOfRange {
+                            object: object.name.clone(),
+                            distance,
+                        })
+                } else {
+                    u16::try_from(target).map_err(|_| LinkError::AddressOutOfRange {
+                        object: object.name.clone(),
+                        address: target,
+                    })
+                };
+                match value {
+                    Ok(value) => program[at..at + 2].copy_from_slice(&value.to_be_bytes()),
+                    Err(error) => errors.push(error),
+                }
+            }
+        }
+
+        let entry = address(&self.entry);
+        if entry.is_none() {
+            errors.push(LinkError::MissingEntry {
+                name: self.entry.clone(),
+            });
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(Executable {
+            program,
+            entry: entry.unwrap(),
+        })
+    }
+}
+
+impl Default for Linker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::vm::VM;
+
+    fn object(name: &str, source: &str) -> Object {
+        Assembler::new(name).object(source).unwrap()
+    }
+
+    fn main_object() -> Object {
+        object(
+            "main.iasm",
+            ".import double
+.export main
+main: load $0 #21
+    load $1 @double
+    load $2 @back
+    jump $1
+back: halt
+",
+        )
+    }
+
+    fn library() -> Library {
+        Library {
+            name: String::from("math"),
+            objects: vec![
+                object("half.iasm", ".export half\nhalf: halt\n"),
+                object(
+                    "double.iasm",
+                    ".export double\ndouble: add $0 $0 $0\n    jump $2\n",
+                ),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_link_and_run() {
+        let first = object("first.iasm", ".export first\nfirst: halt\n");
+        let executable = Linker::new()
+            .link(&[first, main_object()], &[library()])
+            .unwrap();
+        // first (1 byte), main (15 bytes), then double (6 bytes) from the library but not half
+        assert_eq!(executable.entry, 1);
+        assert_eq!(executable.program.len(), 1 + 15 + 6);
+        assert_eq!(&executable.program[5..9], &[1, 1, 0, 16]);
+        assert_eq!(&executable.program[9..13], &[1, 2, 0, 15]);
+
+        let mut vm = VM::new();
+        vm.load_program_at(executable.program, executable.entry)
+            .unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.registers[0], 42);
+    }
+
+    #[test]
+    fn test_link_relative_import() {
+        let main = object(
+            "main.iasm",
+            ".import double\n.export main\nmain: loadrelative $1 @double\n",
+        );
+        let relocation = &main.relocations[0];
+        assert!(relocation.relative);
+        let executable = Linker::new().link(&[main], &[library()]).unwrap();
+        // double lands at 4, right after the LOADRELATIVE
+        assert_eq!(executable.program[..4], [51, 1, 0, 0]);
+    }
+
+    #[test]
+    fn test_link_errors() {
+        let errors = Linker::new().link(&[main_object()], &[]).unwrap_err();
+        let messages: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec!["main.iasm: double is imported but never exported"]
+        );
+
+        let extra = object("extra.iasm", ".export main\nmain: halt\n");
+        let errors = Linker {
+            entry: String::from("start"),
+        }
+        .link(&[main_object(), extra], &[library()])
+        .unwrap_err();
+        let messages: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "main is exported by both main.iasm and extra.iasm",
+                "no object exports the entry point start",
+            ]
+        );
+    }
+}
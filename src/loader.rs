@@ -0,0 +1,117 @@
+use std::fmt;
+
+use crate::assembler::Assembled;
+use crate::verifier::Diagnostic;
+use crate::vm::VM;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LoadError {
+    /// An address that no longer fits in its 16-bit operand once moved
+    AddressOutOfRange { offset: usize, address: usize },
+    /// The program the VM would end up with does not verify
+    Unsound(Vec<Diagnostic>),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::AddressOutOfRange { offset, address } => write!(
+                f,
+                "address {} at byte {} does not fit in a 16-bit operand",
+                address, offset
+            ),
+            LoadError::Unsound(diagnostics) => {
+                write!(f, "the program does not verify:")?;
+                for diagnostic in diagnostics {
+                    write!(f, "\n  {}", diagnostic)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+/// A copy of `program` that works when placed `base` bytes into the VM's program. Each offset
+/// in `relocations` is the start of an operand holding an address in `program`, which moves by
+/// `base`; `LOADRELATIVE` distances need no change.
+pub fn relocate(program: &[u8], relocations: &[usize], base: usize) -> Result<Vec<u8>, LoadError> {
+    let mut relocated = program.to_vec();
+    for &offset in relocations {
+        let operand = &mut relocated[offset..offset + 2];
+        let address = u16::from_be_bytes([operand[0], operand[1]]) as usize + base;
+        let address =
+            u16::try_from(address).map_err(|_| LoadError::AddressOutOfRange { offset, address })?;
+        operand.copy_from_slice(&address.to_be_bytes());
+    }
+    Ok(relocated)
+}
+
+/// Appends `assembled` to the program already in `vm`, relocated to where it lands, and points
+/// the VM at its first instruction. Returns the offset it was placed at.
+pub fn load(vm: &mut VM, assembled: &Assembled) -> Result<usize, LoadError> {
+    let base = vm.program.len();
+    let mut program = vm.program.clone();
+    program.extend(relocate(&assembled.program, &assembled.relocations, base)?);
+    vm.load_program_at(program, base)
+        .map_err(LoadError::Unsound)?;
+    Ok(base)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    const COUNTDOWN: &str = "load $0 #3
+    load $2 #0
+    load $1 @loop
+loop: decrement $0
+    jumpifnotequal $0 $2 $1
+    halt
+";
+
+    #[test]
+    fn test_relocate() {
+        let assembled = Assembler::new("main.iasm").assemble(COUNTDOWN).unwrap();
+        assert_eq!(assembled.relocations, vec![10]);
+        let relocated = relocate(&assembled.program, &assembled.relocations, 100).unwrap();
+        assert_eq!(&relocated[8..12], &[1, 1, 0, 112]);
+        assert_eq!(
+            relocate(&assembled.program, &assembled.relocations, 65530),
+            Err(LoadError::AddressOutOfRange {
+                offset: 10,
+                address: 65542
+            })
+        );
+    }
+
+    #[test]
+    fn test_load_after_existing_code() {
+        let mut vm = VM::new();
+        vm.load_program(vec![0; 7]).unwrap();
+        let assembler = Assembler::new("main.iasm");
+        let base = load(&mut vm, &assembler.assemble(COUNTDOWN).unwrap()).unwrap();
+        assert_eq!(base, 7);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[0], 0);
+        assert_eq!(vm.registers[1], 19);
+    }
+
+    #[test]
+    fn test_position_independent_code() {
+        let source = COUNTDOWN.replace("load $1", "loadrelative $1");
+        let assembled = Assembler::new("main.iasm").assemble(&source).unwrap();
+        assert!(assembled.relocations.is_empty());
+        // The distance from the end of the LOADRELATIVE to the loop is 0
+        assert_eq!(&assembled.program[8..12], &[51, 1, 0, 0]);
+
+        let mut vm = VM::new();
+        vm.load_program(vec![0; 300]).unwrap();
+        load(&mut vm, &assembled).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.registers[0], 0);
+        assert_eq!(vm.registers[1], 312);
+    }
+}
@@ -1,11 +1,57 @@
-extern crate nom;
+use std::path::{Path, PathBuf};
+use std::process;
 
-pub mod assembler;
-pub mod instruction;
-pub mod repl;
-pub mod vm;
+use esper::assembler::Assembler;
+use esper::repl;
+
+const USAGE: &str = "usage: esper                                start the REPL
+       esper listing [-I <directory>]... <file>  print the assembler listing of <file>";
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+/// Splits `arguments` into the `-I` search paths and the rest.
+fn search_paths(arguments: &[String]) -> (Vec<PathBuf>, Vec<&str>) {
+    let mut paths = vec![];
+    let mut rest = vec![];
+    let mut arguments = arguments.iter();
+    while let Some(argument) = arguments.next() {
+        match argument.as_str() {
+            "-I" => match arguments.next() {
+                Some(path) => paths.push(PathBuf::from(path)),
+                None => usage(),
+            },
+            argument => rest.push(argument),
+        }
+    }
+    (paths, rest)
+}
+
+fn listing(path: &str, search_paths: Vec<PathBuf>) {
+    let mut assembler = Assembler::new(path);
+    assembler.search_paths = search_paths;
+    match assembler.listing_file(Path::new(path)) {
+        Ok(listing) => print!("{}", listing),
+        Err(errors) => {
+            for error in errors {
+                eprintln!("{}", error);
+            }
+            process::exit(1);
+        }
+    }
+}
 
 fn main() {
-    let mut repl = repl::REPL::new();
-    repl.run();
+    let arguments: Vec<String> = std::env::args().skip(1).collect();
+    let (search_paths, arguments) = search_paths(&arguments);
+    match arguments[..] {
+        [] => {
+            let mut repl = repl::REPL::new();
+            repl.run();
+        }
+        ["listing", path] => listing(path, search_paths),
+        _ => usage(),
+    }
 }
@@ -0,0 +1,24 @@
+use crate::vm::REGISTER_COUNT;
+
+/// The slice of machine state a native function is allowed to touch.
+///
+/// By convention arguments are passed in `$0`, `$1`, ... and the result is left in `$0`.
+pub struct VmContext<'a> {
+    pub registers: &'a mut [i32; REGISTER_COUNT],
+    pub float_registers: &'a mut [f64; REGISTER_COUNT],
+    pub heap: &'a mut Vec<u8>,
+}
+
+impl<'a> VmContext<'a> {
+    /// The `length` heap bytes starting at `address`, or `None` if any of them lie outside it.
+    pub fn memory(&mut self, address: i32, length: i32) -> Option<&mut [u8]> {
+        let start = usize::try_from(address).ok()?;
+        let end = start.checked_add(usize::try_from(length).ok()?)?;
+        self.heap.get_mut(start..end)
+    }
+}
+
+/// A host function callable from esper with `CALLNATIVE #n`.
+///
+/// Returning `Err` aborts the run with `VmError::NativeFailed` carrying the message.
+pub type NativeFunction = Box<dyn FnMut(&mut VmContext) -> Result<(), String>>;
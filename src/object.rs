@@ -0,0 +1,162 @@
+use crate::debug_info::{push_number, push_string, Reader};
+
+/// What the 2-byte operand at a relocation's offset should end up holding.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Target {
+    /// An offset into this object's own code, which moves with the object
+    Local(usize),
+    /// The address of a symbol exported by another object
+    Import(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Relocation {
+    /// Where the operand starts in `Object::code`
+    pub offset: usize,
+    pub target: Target,
+    /// Whether the operand holds the target's distance from the end of the operand, as a
+    /// `LOADRELATIVE` does, rather than its address
+    pub relative: bool,
+}
+
+/// Marks the start of a serialized object.
+const MAGIC: &[u8; 4] = b"EOBJ";
+
+/// Code assembled on its own, to be placed at some offset by the linker. Every operand that
+/// depends on where the code ends up is listed in `relocations` and holds 0 until it is linked.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Object {
+    /// The file the object was assembled from, used in link errors
+    pub name: String,
+    pub code: Vec<u8>,
+    /// The labels other objects may import, and their offsets into `code`
+    pub exports: Vec<(String, usize)>,
+    pub imports: Vec<String>,
+    pub relocations: Vec<Relocation>,
+}
+
+impl Object {
+    pub fn export(&self, name: &str) -> Option<usize> {
+        self.exports
+            .iter()
+            .find(|(export, _)| export == name)
+            .map(|(_, offset)| *offset)
+    }
+
+    /// The object file: `EOBJ`, the name, the code, then the exports, imports and relocations,
+    /// each list prefixed by its length, in the encoding `DebugInfo::to_bytes` uses. A
+    /// relocation is its offset, then 0 and an offset for a `Local` target or 1 and a name for
+    /// an `Import`, then 1 if it is relative and 0 if not.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        push_string(&mut bytes, &self.name);
+        push_number(&mut bytes, self.code.len());
+        bytes.extend(&self.code);
+        push_number(&mut bytes, self.exports.len());
+        for (name, offset) in &self.exports {
+            push_string(&mut bytes, name);
+            push_number(&mut bytes, *offset);
+        }
+        push_number(&mut bytes, self.imports.len());
+        for name in &self.imports {
+            push_string(&mut bytes, name);
+        }
+        push_number(&mut bytes, self.relocations.len());
+        for relocation in &self.relocations {
+            push_number(&mut bytes, relocation.offset);
+            match &relocation.target {
+                Target::Local(offset) => {
+                    push_number(&mut bytes, 0);
+                    push_number(&mut bytes, *offset);
+                }
+                Target::Import(name) => {
+                    push_number(&mut bytes, 1);
+                    push_string(&mut bytes, name);
+                }
+            }
+            push_number(&mut bytes, relocation.relative as usize);
+        }
+        bytes
+    }
+
+    /// Reads an object written by `to_bytes`, or returns `None` if `bytes` is not one.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Object> {
+        let mut reader = Reader {
+            bytes: bytes.strip_prefix(MAGIC)?,
+        };
+        let mut object = Object {
+            name: reader.string()?,
+            ..Object::default()
+        };
+        let length = reader.number()?;
+        object.code = reader.take(length)?.to_vec();
+        for _ in 0..reader.number()? {
+            let name = reader.string()?;
+            object.exports.push((name, reader.number()?));
+        }
+        for _ in 0..reader.number()? {
+            object.imports.push(reader.string()?);
+        }
+        for _ in 0..reader.number()? {
+            let offset = reader.number()?;
+            let target = match reader.number()? {
+                0 => Target::Local(reader.number()?),
+                1 => Target::Import(reader.string()?),
+                _ => return None,
+            };
+            let relative = match reader.number()? {
+                0 => false,
+                1 => true,
+                _ => return None,
+            };
+            object.relocations.push(Relocation {
+                offset,
+                target,
+                relative,
+            });
+        }
+        reader.bytes.is_empty().then_some(object)
+    }
+}
+
+/// Objects the linker takes only as needed: one is linked in when it exports a symbol that
+/// something already linked imports.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Library {
+    pub name: String,
+    pub objects: Vec<Object>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_object_round_trip() {
+        let object = Object {
+            name: String::from("main.iasm"),
+            code: vec![1, 1, 0, 0, 6, 1],
+            exports: vec![(String::from("main"), 0)],
+            imports: vec![String::from("print")],
+            relocations: vec![
+                Relocation {
+                    offset: 2,
+                    target: Target::Import(String::from("print")),
+                    relative: false,
+                },
+                Relocation {
+                    offset: 2,
+                    target: Target::Local(4),
+                    relative: true,
+                },
+            ],
+        };
+        let bytes = object.to_bytes();
+        assert!(bytes.starts_with(b"EOBJ"));
+        assert_eq!(Object::from_bytes(&bytes), Some(object.clone()));
+        assert_eq!(Object::from_bytes(&bytes[..bytes.len() - 1]), None);
+        assert_eq!(Object::from_bytes(b"EDBG"), None);
+        assert_eq!(object.export("main"), Some(0));
+        assert_eq!(object.export("print"), None);
+    }
+}
@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::instruction::Opcode;
+
+/// How many of the hottest instructions `Profiler::report` lists.
+const HOTTEST_INSTRUCTIONS: usize = 20;
+
+/// Counts how often each instruction runs. Attach one to `VM::profiler` before a run; counts
+/// accumulate across runs until the profiler is replaced.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Profiler {
+    /// Executions per byte offset of the instruction
+    offsets: Vec<u64>,
+    opcodes: HashMap<Opcode, u64>,
+    /// The opcode executed at each offset, for labelling the report
+    opcode_at: HashMap<usize, Opcode>,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler::default()
+    }
+
+    pub fn record(&mut self, offset: usize, opcode: Opcode) {
+        if offset >= self.offsets.len() {
+            self.offsets.resize(offset + 1, 0);
+        }
+        if self.offsets[offset] == 0 {
+            self.opcode_at.insert(offset, opcode);
+        }
+        self.offsets[offset] += 1;
+        *self.opcodes.entry(opcode).or_insert(0) += 1;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.offsets.iter().sum()
+    }
+
+    pub fn count_at(&self, offset: usize) -> u64 {
+        self.offsets.get(offset).copied().unwrap_or(0)
+    }
+
+    pub fn count_of(&self, opcode: Opcode) -> u64 {
+        self.opcodes.get(&opcode).copied().unwrap_or(0)
+    }
+
+    fn executed(&self) -> impl Iterator<Item = (usize, u64)> + '_ {
+        self.offsets
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(offset, count)| (offset, *count))
+    }
+
+    /// Executions per label, where an instruction belongs to the closest label at or before it.
+    /// `labels` pairs each label name with its byte offset, in any order.
+    pub fn by_label(&self, labels: &[(String, usize)]) -> Vec<(String, u64)> {
+        let mut sorted: Vec<&(String, usize)> = labels.iter().collect();
+        sorted.sort_by_key(|(_, offset)| *offset);
+        let mut counts: HashMap<String, u64> = HashMap::new();
+        for (offset, count) in self.executed() {
+            *counts
+                .entry(label_name(&sorted, offset).to_string())
+                .or_insert(0) += count;
+        }
+        let mut counts: Vec<(String, u64)> = counts.into_iter().collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        counts
+    }
+
+    /// A human-readable summary, hottest first. Offsets are shown relative to a label when
+    /// `labels` has one at or before them.
+    pub fn report(&self, labels: &[(String, usize)]) -> String {
+        let total = self.total();
+        let share = |count: u64| 100.0 * count as f64 / total.max(1) as f64;
+        let mut sorted: Vec<&(String, usize)> = labels.iter().collect();
+        sorted.sort_by_key(|(_, offset)| *offset);
+        let mut report = String::new();
+        writeln!(report, "{} instructions executed", total).unwrap();
+
+        writeln!(report, "\nBy opcode:").unwrap();
+        let mut opcodes: Vec<(&Opcode, &u64)> = self.opcodes.iter().collect();
+        opcodes.sort_by(|a, b| {
+            b.1.cmp(a.1)
+                .then_with(|| format!("{:?}", a.0).cmp(&format!("{:?}", b.0)))
+        });
+        for (opcode, count) in opcodes {
+            writeln!(
+                report,
+                "{:>12} {:>6.2}%  {:?}",
+                count,
+                share(*count),
+                opcode
+            )
+            .unwrap();
+        }
+
+        if !labels.is_empty() {
+            writeln!(report, "\nBy label:").unwrap();
+            for (label, count) in self.by_label(labels) {
+                writeln!(report, "{:>12} {:>6.2}%  {}", count, share(count), label).unwrap();
+            }
+        }
+
+        writeln!(report, "\nHottest instructions:").unwrap();
+        let mut hottest: Vec<(usize, u64)> = self.executed().collect();
+        hottest.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        for (offset, count) in hottest.into_iter().take(HOTTEST_INSTRUCTIONS) {
+            writeln!(
+                report,
+                "{:>12} {:>6.2}%  {:<16} {:?}",
+                count,
+                share(count),
+                location(&sorted, offset),
+                self.opcode_at[&offset]
+            )
+            .unwrap();
+        }
+        report
+    }
+
+    /// The counts in the collapsed-stack format that flamegraph tools read: one
+    /// `esper;<label>;<opcode> <count>` line per label and opcode.
+    pub fn collapsed_stacks(&self, labels: &[(String, usize)]) -> String {
+        let mut sorted: Vec<&(String, usize)> = labels.iter().collect();
+        sorted.sort_by_key(|(_, offset)| *offset);
+        let mut stacks: HashMap<String, u64> = HashMap::new();
+        for (offset, count) in self.executed() {
+            let stack = format!(
+                "esper;{};{:?}",
+                label_name(&sorted, offset),
+                self.opcode_at[&offset]
+            );
+            *stacks.entry(stack).or_insert(0) += count;
+        }
+        let mut stacks: Vec<(String, u64)> = stacks.into_iter().collect();
+        stacks.sort();
+        stacks
+            .into_iter()
+            .map(|(stack, count)| format!("{} {}\n", stack, count))
+            .collect()
+    }
+}
+
+/// The closest label at or before `offset`, from labels sorted by offset.
+fn enclosing_label<'a>(
+    sorted: &[&'a (String, usize)],
+    offset: usize,
+) -> Option<&'a (String, usize)> {
+    let index = sorted.partition_point(|(_, start)| *start <= offset);
+    index.checked_sub(1).map(|index| sorted[index])
+}
+
+fn label_name<'a>(sorted: &[&'a (String, usize)], offset: usize) -> &'a str {
+    enclosing_label(sorted, offset).map_or("(unlabelled)", |(name, _)| name.as_str())
+}
+
+fn location(sorted: &[&(String, usize)], offset: usize) -> String {
+    match enclosing_label(sorted, offset) {
+        Some((name, start)) if *start == offset => name.clone(),
+        Some((name, start)) => format!("{}+{}", name, offset - start),
+        None => format!("byte {}", offset),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::VM;
+
+    fn profiled_loop() -> Profiler {
+        let mut test_vm = VM::new();
+        test_vm.profiler = Some(Profiler::new());
+        test_vm.program = vec![
+            1, 1, 0, 3, // LOAD $1 #3
+            1, 2, 0, 8, // LOAD $2 #8
+            31, 0, // INCREMENT $0
+            43, 0, 1, 2, // JUMPIFLESS $0 $1 $2
+        ];
+        test_vm.run().unwrap();
+        test_vm.profiler.unwrap()
+    }
+
+    fn labels() -> Vec<(String, usize)> {
+        vec![(String::from("loop"), 8), (String::from("main"), 0)]
+    }
+
+    #[test]
+    fn test_profiler_counts() {
+        let profiler = profiled_loop();
+        assert_eq!(profiler.total(), 8);
+        assert_eq!(profiler.count_at(0), 1);
+        assert_eq!(profiler.count_at(8), 3);
+        assert_eq!(profiler.count_at(10), 3);
+        assert_eq!(profiler.count_of(Opcode::LOAD), 2);
+        assert_eq!(profiler.count_of(Opcode::JUMPIFLESS), 3);
+        assert_eq!(
+            profiler.by_label(&labels()),
+            vec![(String::from("loop"), 6), (String::from("main"), 2)]
+        );
+    }
+
+    #[test]
+    fn test_profiler_report() {
+        let report = profiled_loop().report(&labels());
+        assert!(report.starts_with("8 instructions executed\n"));
+        assert!(report.contains("           6  75.00%  loop\n"));
+        assert!(report.contains("           3  37.50%  loop+2           JUMPIFLESS\n"));
+    }
+
+    #[test]
+    fn test_profiler_collapsed_stacks() {
+        assert_eq!(
+            profiled_loop().collapsed_stacks(&labels()),
+            "esper;loop;INCREMENT 3\nesper;loop;JUMPIFLESS 3\nesper;main;LOAD 2\n"
+        );
+        assert_eq!(
+            profiled_loop().collapsed_stacks(&[]),
+            "esper;(unlabelled);INCREMENT 3\nesper;(unlabelled);JUMPIFLESS 3\nesper;(unlabelled);LOAD 2\n"
+        );
+    }
+}
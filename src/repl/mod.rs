@@ -1,4 +1,10 @@
-use crate::assembler::program_parsers::program;
+use crate::assembler::{Assembler, AssemblerError};
+use crate::coverage::{self, Coverage};
+use crate::debug_info::DebugInfo;
+use crate::disassembler::disassemble;
+use crate::profiler::Profiler;
+use crate::syscall::Capabilities;
+use crate::verifier::verify;
 use crate::vm::VM;
 use std;
 use std::io;
@@ -6,14 +12,27 @@ use std::io::Write;
 
 pub struct REPL {
     command_buffer: Vec<String>,
+    /// Every line that has been assembled into the VM's program, in order
+    source: String,
+    assembler: Assembler,
+    /// Debug info for `source`, kept for error messages, reports and `.disassemble`
+    debug_info: Option<DebugInfo>,
     vm: VM,
 }
 
 impl REPL {
     pub fn new() -> REPL {
+        let mut vm = VM::new();
+        // The REPL user is the one typing the program, so it gets the same access they have
+        vm.syscalls.capabilities = Capabilities::all(std::env::current_dir().unwrap_or_default());
+        let mut assembler = Assembler::new("<repl>");
+        assembler.emit_debug_info = true;
         REPL {
             command_buffer: vec![],
-            vm: VM::new(),
+            source: String::new(),
+            assembler,
+            debug_info: None,
+            vm,
         }
     }
     pub fn run(&mut self) {
@@ -39,8 +58,30 @@ impl REPL {
                 ".registers" => {
                     println!("Here are the registers' contents:");
                     println!("{:#?}", self.vm.registers);
+                    println!("remainder: {}", self.vm.remainder());
                     println!("--- End of listing ---");
                 }
+                ".fregisters" => {
+                    println!("Here are the floating-point registers' contents:");
+                    println!("{:#?}", self.vm.float_registers);
+                    println!("--- End of listing ---");
+                }
+                ".profile" => {
+                    if self.vm.profiler.take().is_some() {
+                        println!("Profiling off.");
+                    } else {
+                        self.vm.profiler = Some(Profiler::new());
+                        println!("Profiling on; a report follows each run.");
+                    }
+                }
+                ".coverage" => {
+                    if self.vm.coverage.take().is_some() {
+                        println!("Coverage off.");
+                    } else {
+                        self.vm.coverage = Some(Coverage::new());
+                        println!("Coverage on; an annotated listing follows each run.");
+                    }
+                }
                 ".history" => {
                     for command in &self.command_buffer {
                         println!("{}", command);
@@ -50,16 +91,73 @@ impl REPL {
                     println!("Deactivating esper powers...");
                     std::process::exit(0);
                 }
+                ".listing" => match self.assembler.listing(&self.source) {
+                    Ok(listing) => print!("{}", listing),
+                    Err(errors) => {
+                        for error in errors {
+                            println!("{}", error);
+                        }
+                    }
+                },
+                ".disassemble" => {
+                    print!(
+                        "{}",
+                        disassemble(&self.vm.program, self.debug_info.as_ref())
+                    );
+                }
                 _ => {
-                    let program = match program(buffer) {
-                        Ok((_, program)) => program,
-                        Err(_) => {
-                            println!("Sorry, what is it you wanted to say?");
+                    // Assembling everything so far lets new lines use labels from earlier ones;
+                    // the earlier lines assemble to the same bytes as before
+                    let mut source = self.source.clone();
+                    source.push_str(buffer);
+                    source.push('\n');
+                    let assembled = match self.assembler.assemble(&source) {
+                        Ok(assembled) => assembled,
+                        // A macro or function is being typed in; wait for its `.endm` or `.endfunc`
+                        Err(errors)
+                            if errors.iter().all(|error| {
+                                matches!(
+                                    error.error,
+                                    AssemblerError::UnterminatedMacro { .. }
+                                        | AssemblerError::UnterminatedFunction { .. }
+                                )
+                            }) =>
+                        {
+                            self.source = source;
+                            continue;
+                        }
+                        Err(errors) => {
+                            for error in errors {
+                                println!("{}", error);
+                            }
                             continue;
                         }
                     };
-                    self.vm.program.append(&mut program.to_bytes());
-                    self.vm.run();
+                    if let Err(diagnostics) = verify(&assembled.program) {
+                        for diagnostic in diagnostics {
+                            println!("Rejected: {}", diagnostic);
+                        }
+                        continue;
+                    }
+                    self.vm.program = assembled.program;
+                    self.debug_info = assembled.debug_info;
+                    self.source = source;
+                    if let Err(error) = self.vm.run() {
+                        println!(
+                            "Runtime error: {}",
+                            error.describe(self.debug_info.as_ref())
+                        );
+                    }
+                    let debug_info = self.debug_info.clone().unwrap_or_default();
+                    if let Some(profiler) = &self.vm.profiler {
+                        print!("{}", profiler.report(&debug_info.labels));
+                    }
+                    if let Some(coverage) = &self.vm.coverage {
+                        print!(
+                            "{}",
+                            coverage::report(&self.source, &self.vm.program, &debug_info, coverage)
+                        );
+                    }
                 }
             }
         }
@@ -0,0 +1,140 @@
+/// The `call routine` macro, which jumps to `routine` with the return address in `$ra`. It
+/// clobbers `$29`.
+pub const CALL: &str = include_str!("call.iasm");
+
+/// Integer arithmetic:
+///
+/// - `std_abs`: `$a0` -> `$a0` its absolute value.
+/// - `std_gcd`: `$a0`, `$a1`, neither negative -> `$a0` their greatest common divisor, which is
+///   0 only when both are. Clobbers `$a1`.
+/// - `std_power`: `$a0` base, `$a1` exponent -> `$a0` the base to that power, or 1 if the
+///   exponent is not positive. Clobbers `$a1`.
+///
+/// A result that does not fit in an `i32` is an overflow, as it would be in the caller's code.
+pub const MATH: &str = include_str!("math.iasm");
+
+/// Text:
+///
+/// - `std_to_digits`: `$a0` number, `$a1` heap address -> `$a0` how many bytes were written
+///   there: the number in decimal, with a leading `-` if it is negative. Writes at most 11
+///   bytes. Clobbers `$a2`.
+pub const DIGITS: &str = include_str!("digits.iasm");
+
+/// The bundled file that `.include <name>` reads.
+///
+/// Its routines all follow one convention. Arguments arrive in `$a0`, `$a1` and `$a2` and the
+/// result is left in `$a0`. The caller puts its return address in `$ra` and the routine ends
+/// with `jump $ra`, which `call` in `call.iasm` takes care of. Routines may overwrite `$24` to
+/// `$29` and the argument registers; every other register is left alone. Labels start with
+/// `std_`, and the code is position-independent, so it can go anywhere after the program's
+/// last instruction.
+pub fn file(name: &str) -> Option<&'static str> {
+    match name {
+        "call.iasm" => Some(CALL),
+        "math.iasm" => Some(MATH),
+        "digits.iasm" => Some(DIGITS),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::vm::VM;
+
+    /// Runs `setup`, calls `routine` from `file`, and halts.
+    fn call(setup: &str, routine: &str, file: &str) -> VM {
+        let source = format!(
+            ".include <call.iasm>\nload $5 #7\n{}\ncall {}\nhalt\n.include <{}>\n",
+            setup, routine, file
+        );
+        let program = Assembler::new("test.iasm")
+            .assemble(&source)
+            .unwrap()
+            .program;
+        let mut vm = VM::new();
+        vm.load_program(program).unwrap();
+        vm.run().unwrap();
+        // Registers outside the convention are left alone
+        assert_eq!(vm.registers[5], 7);
+        vm
+    }
+
+    /// Loads `-value` into `register`, since `LOAD` only takes numbers that fit in a `u16`.
+    fn negative(register: &str, value: u16) -> String {
+        format!(
+            "load $2 #{}\nload {} #0\nsubtract {} $2 {}",
+            value, register, register, register
+        )
+    }
+
+    #[test]
+    fn test_abs() {
+        assert_eq!(
+            call("load $a0 #12", "std_abs", "math.iasm").registers[0],
+            12
+        );
+        let setup = negative("$a0", 12);
+        assert_eq!(call(&setup, "std_abs", "math.iasm").registers[0], 12);
+    }
+
+    #[test]
+    fn test_gcd() {
+        for (a, b, gcd) in [
+            (12, 18, 6),
+            (18, 12, 6),
+            (17, 5, 1),
+            (0, 9, 9),
+            (9, 0, 9),
+            (0, 0, 0),
+        ] {
+            let setup = format!("load $a0 #{}\nload $a1 #{}", a, b);
+            assert_eq!(call(&setup, "std_gcd", "math.iasm").registers[0], gcd);
+        }
+    }
+
+    #[test]
+    fn test_power() {
+        for (base, exponent, power) in [(2, 10, 1024), (3, 0, 1), (7, 1, 7), (0, 3, 0)] {
+            let setup = format!("load $a0 #{}\nload $a1 #{}", base, exponent);
+            assert_eq!(call(&setup, "std_power", "math.iasm").registers[0], power);
+        }
+        let setup = format!("load $a0 #2\n{}", negative("$a1", 3));
+        assert_eq!(call(&setup, "std_power", "math.iasm").registers[0], 1);
+    }
+
+    #[test]
+    fn test_to_digits() {
+        for (setup, digits) in [
+            (String::from("load $a0 #0"), "0"),
+            (String::from("load $a0 #1234"), "1234"),
+            (String::from("load $a0 #65535"), "65535"),
+            (negative("$a0", 907), "-907"),
+            (
+                format!(
+                    "{}\nload $2 #256\nmultiply $a0 $2 $a0\nmultiply $a0 $2 $a0",
+                    negative("$a0", 32768)
+                ),
+                "-2147483648",
+            ),
+        ] {
+            let setup = format!("load $3 #16\nallocate $3\n{}\nload $a1 #4", setup);
+            let vm = call(&setup, "std_to_digits", "digits.iasm");
+            assert_eq!(vm.registers[0] as usize, digits.len());
+            assert_eq!(&vm.heap[4..4 + digits.len()], digits.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_unknown_file() {
+        assert_eq!(file("strings.iasm"), None);
+        let errors = Assembler::new("test.iasm")
+            .assemble(".include <strings.iasm>\n")
+            .unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "test.iasm:1:1: cannot find <strings.iasm> to include"
+        );
+    }
+}
@@ -0,0 +1,307 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::native::VmContext;
+
+/// The stable numbering behind `SYSCALL #n`.
+///
+/// Arguments are passed in `$0`, `$1`, `$2` and the result comes back in `$0`, where -1 means
+/// the host operation failed (end of input, missing file, bad descriptor, ...). Descriptors 0, 1
+/// and 2 are the console's input, output and error streams; opened files start at 3.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Syscall {
+    /// `$0` address, `$1` capacity -> length of the line read, without its line ending, and
+    /// `$1` 1 if the line did not fit, in which case the next `ReadLine` goes on with the rest of
+    /// it, or 0 if it ended
+    ReadLine,
+    /// `$0` descriptor, `$1` address, `$2` length -> bytes written
+    Write,
+    /// `$0` path address, `$1` path length, `$2` mode (0 read, 1 truncate, 2 append) -> descriptor
+    Open,
+    /// `$0` descriptor, `$1` address, `$2` capacity -> bytes read
+    Read,
+    /// `$0` descriptor -> 0
+    Close,
+    /// -> `$0` seconds since the Unix epoch (wrapping past 2038), `$1` milliseconds
+    Time,
+    Unknown,
+}
+
+impl From<u16> for Syscall {
+    fn from(v: u16) -> Self {
+        match v {
+            0 => Syscall::ReadLine,
+            1 => Syscall::Write,
+            2 => Syscall::Open,
+            3 => Syscall::Read,
+            4 => Syscall::Close,
+            5 => Syscall::Time,
+            _ => Syscall::Unknown,
+        }
+    }
+}
+
+/// What a program running on the VM is allowed to do through `SYSCALL`. Denies everything by
+/// default, so untrusted bytecode gets no I/O unless the embedder opts in.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    pub console: bool,
+    pub clock: bool,
+    /// Files may only be opened beneath this directory; `None` denies file access entirely.
+    pub sandbox_root: Option<PathBuf>,
+}
+
+impl Capabilities {
+    pub fn none() -> Capabilities {
+        Capabilities::default()
+    }
+
+    pub fn all(sandbox_root: PathBuf) -> Capabilities {
+        Capabilities {
+            console: true,
+            clock: true,
+            sandbox_root: Some(sandbox_root),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SyscallError {
+    Unknown,
+    Denied,
+    MemoryOutOfBounds { address: i32, length: i32 },
+}
+
+pub struct Syscalls {
+    pub capabilities: Capabilities,
+    /// `None` reads the process's stdin, shared with whoever else (such as the REPL) uses it.
+    input: Option<Box<dyn BufRead>>,
+    /// The rest of a line that did not fit in the last `ReadLine`
+    pending: Option<Vec<u8>>,
+    output: Box<dyn Write>,
+    error: Box<dyn Write>,
+    files: HashMap<i32, File>,
+    next_descriptor: i32,
+}
+
+impl Syscalls {
+    pub fn new() -> Syscalls {
+        Syscalls {
+            capabilities: Capabilities::none(),
+            input: None,
+            pending: None,
+            output: Box::new(io::stdout()),
+            error: Box::new(io::stderr()),
+            files: HashMap::new(),
+            next_descriptor: 3,
+        }
+    }
+
+    /// Redirects descriptors 0, 1 and 2 away from the process's own standard streams.
+    pub fn set_console(
+        &mut self,
+        input: Box<dyn BufRead>,
+        output: Box<dyn Write>,
+        error: Box<dyn Write>,
+    ) {
+        self.input = Some(input);
+        self.output = output;
+        self.error = error;
+    }
+
+    pub fn dispatch(&mut self, number: u16, context: &mut VmContext) -> Result<(), SyscallError> {
+        let syscall = Syscall::from(number);
+        let allowed = match syscall {
+            Syscall::ReadLine => self.capabilities.console,
+            Syscall::Write | Syscall::Read => match context.registers[0] {
+                0..=2 => self.capabilities.console,
+                _ => self.capabilities.sandbox_root.is_some(),
+            },
+            Syscall::Open | Syscall::Close => self.capabilities.sandbox_root.is_some(),
+            Syscall::Time => self.capabilities.clock,
+            Syscall::Unknown => return Err(SyscallError::Unknown),
+        };
+        if !allowed {
+            return Err(SyscallError::Denied);
+        }
+        let result = match syscall {
+            Syscall::ReadLine => self.read_line(context)?,
+            Syscall::Write => self.write(context)?,
+            Syscall::Open => self.open(context)?,
+            Syscall::Read => self.read(context)?,
+            Syscall::Close => self.files.remove(&context.registers[0]).map(|_| 0),
+            Syscall::Time => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                context.registers[1] = now.subsec_millis() as i32;
+                Some(now.as_secs() as i32)
+            }
+            Syscall::Unknown => unreachable!(),
+        };
+        context.registers[0] = result.unwrap_or(-1);
+        Ok(())
+    }
+
+    fn read_line(&mut self, context: &mut VmContext) -> Result<Option<i32>, SyscallError> {
+        let (address, capacity) = (context.registers[0], context.registers[1]);
+        let buffer = memory(context, address, capacity)?;
+        let line = match self.pending.take() {
+            Some(rest) => rest,
+            None => {
+                let mut line = String::new();
+                let read = match &mut self.input {
+                    Some(input) => input.read_line(&mut line),
+                    None => io::stdin().read_line(&mut line),
+                };
+                match read {
+                    Ok(0) | Err(_) => return Ok(None),
+                    Ok(_) => {}
+                }
+                line.trim_end_matches(['\n', '\r']).as_bytes().to_vec()
+            }
+        };
+        let length = line.len().min(buffer.len());
+        buffer[..length].copy_from_slice(&line[..length]);
+        let truncated = length < line.len();
+        if truncated {
+            self.pending = Some(line[length..].to_vec());
+        }
+        context.registers[1] = truncated as i32;
+        Ok(Some(length as i32))
+    }
+
+    fn write(&mut self, context: &mut VmContext) -> Result<Option<i32>, SyscallError> {
+        let (descriptor, address, length) = (
+            context.registers[0],
+            context.registers[1],
+            context.registers[2],
+        );
+        let bytes = memory(context, address, length)?;
+        let stream: &mut dyn Write = match descriptor {
+            1 => &mut self.output,
+            2 => &mut self.error,
+            _ => match self.files.get_mut(&descriptor) {
+                Some(file) => file,
+                None => return Ok(None),
+            },
+        };
+        Ok(stream
+            .write_all(bytes)
+            .and_then(|_| stream.flush())
+            .ok()
+            .map(|_| length))
+    }
+
+    fn read(&mut self, context: &mut VmContext) -> Result<Option<i32>, SyscallError> {
+        let (descriptor, address, capacity) = (
+            context.registers[0],
+            context.registers[1],
+            context.registers[2],
+        );
+        let buffer = memory(context, address, capacity)?;
+        let read = match (descriptor, &mut self.input) {
+            (0, Some(input)) => input.read(buffer),
+            (0, None) => io::stdin().read(buffer),
+            _ => match self.files.get_mut(&descriptor) {
+                Some(file) => file.read(buffer),
+                None => return Ok(None),
+            },
+        };
+        Ok(read.ok().map(|count| count as i32))
+    }
+
+    fn open(&mut self, context: &mut VmContext) -> Result<Option<i32>, SyscallError> {
+        let (address, length, mode) = (
+            context.registers[0],
+            context.registers[1],
+            context.registers[2],
+        );
+        let path = memory(context, address, length)?;
+        let root = self.capabilities.sandbox_root.as_ref().unwrap();
+        let path = match std::str::from_utf8(path)
+            .ok()
+            .and_then(|path| resolve(root, path))
+        {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let mut options = OpenOptions::new();
+        match mode {
+            0 => options.read(true),
+            1 => options.write(true).create(true).truncate(true),
+            2 => options.append(true).create(true),
+            _ => return Ok(None),
+        };
+        Ok(options.open(path).ok().map(|file| {
+            let descriptor = self.next_descriptor;
+            self.next_descriptor += 1;
+            self.files.insert(descriptor, file);
+            descriptor
+        }))
+    }
+}
+
+impl Default for Syscalls {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn memory<'a>(
+    context: &'a mut VmContext,
+    address: i32,
+    length: i32,
+) -> Result<&'a mut [u8], SyscallError> {
+    context
+        .memory(address, length)
+        .ok_or(SyscallError::MemoryOutOfBounds { address, length })
+}
+
+/// Maps a program-supplied relative path to a location inside `root`, refusing anything that
+/// would land outside it (absolute paths, `..`, or symlinks pointing elsewhere).
+fn resolve(root: &Path, path: &str) -> Option<PathBuf> {
+    let relative = Path::new(path);
+    let plain = relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    if !plain || relative.file_name().is_none() {
+        return None;
+    }
+    let root = root.canonicalize().ok()?;
+    let candidate = root.join(relative);
+    let resolved = match candidate.canonicalize() {
+        Ok(existing) => existing,
+        Err(_) => candidate
+            .parent()?
+            .canonicalize()
+            .ok()?
+            .join(candidate.file_name()?),
+    };
+    resolved.starts_with(&root).then_some(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_syscall_numbering() {
+        assert_eq!(Syscall::from(0), Syscall::ReadLine);
+        assert_eq!(Syscall::from(5), Syscall::Time);
+        assert_eq!(Syscall::from(6), Syscall::Unknown);
+    }
+
+    #[test]
+    fn test_resolve_stays_inside_root() {
+        let root = std::env::temp_dir();
+        assert!(resolve(&root, "notes.txt").is_some());
+        assert!(resolve(&root, "./notes.txt").is_some());
+        assert!(resolve(&root, "../notes.txt").is_none());
+        assert!(resolve(&root, "/etc/passwd").is_none());
+        assert!(resolve(&root, "").is_none());
+    }
+}
@@ -0,0 +1,40 @@
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use crate::vm::VM;
+
+/// An output stream that keeps what is written, for reading back after the VM that owns a clone
+/// of it has run.
+#[derive(Clone, Default)]
+pub struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuffer {
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.borrow().clone()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A VM allowed to use the console, which reads `input` and writes to the returned buffer.
+pub fn console_vm(input: &'static [u8]) -> (VM, SharedBuffer) {
+    let output = SharedBuffer::default();
+    let mut vm = VM::new();
+    vm.syscalls.set_console(
+        Box::new(input),
+        Box::new(output.clone()),
+        Box::new(io::sink()),
+    );
+    vm.syscalls.capabilities.console = true;
+    (vm, output)
+}
@@ -0,0 +1,294 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::instruction::{Instruction, Opcode, OperandKind};
+use crate::vm::REGISTER_COUNT;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Problem {
+    IllegalOpcode { byte: u8 },
+    Truncated { opcode: Opcode, missing: usize },
+    RegisterOutOfRange { index: u8 },
+    JumpOutOfBounds { target: i64 },
+    JumpIntoInstruction { target: usize },
+}
+
+/// Something wrong with the instruction starting at `offset`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub offset: usize,
+    pub problem: Problem,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "byte {}: ", self.offset)?;
+        match &self.problem {
+            Problem::IllegalOpcode { byte } => write!(f, "illegal opcode {}", byte),
+            Problem::Truncated { opcode, missing } => {
+                write!(f, "{:?} is missing {} operand byte(s)", opcode, missing)
+            }
+            Problem::RegisterOutOfRange { index } => {
+                write!(f, "register {} does not exist", index)
+            }
+            Problem::JumpOutOfBounds { target } => {
+                write!(f, "jump target {} is outside the program", target)
+            }
+            Problem::JumpIntoInstruction { target } => {
+                write!(
+                    f,
+                    "jump target {} is in the middle of an instruction",
+                    target
+                )
+            }
+        }
+    }
+}
+
+struct Decoded {
+    offset: usize,
+    instruction: Instruction,
+}
+
+/// How a jump picks its destination: which register operand holds it, and what the register's
+/// value is relative to.
+enum JumpKind {
+    Absolute(usize),
+    Forward(usize),
+    Backward(usize),
+}
+
+fn jump_kind(opcode: Opcode) -> Option<JumpKind> {
+    match opcode {
+        Opcode::JUMP | Opcode::JUMPIF | Opcode::JUMPIFNOT => Some(JumpKind::Absolute(0)),
+        Opcode::JUMPIFEQUAL
+        | Opcode::JUMPIFNOTEQUAL
+        | Opcode::JUMPIFGREATER
+        | Opcode::JUMPIFLESS
+        | Opcode::JUMPIFGREATEREQUAL
+        | Opcode::JUMPIFLESSEQUAL => Some(JumpKind::Absolute(2)),
+        Opcode::JUMPFORWARD | Opcode::JUMPFORWARDIF | Opcode::JUMPFORWARDIFNOT => {
+            Some(JumpKind::Forward(0))
+        }
+        Opcode::JUMPBACKWARD | Opcode::JUMPBACKWARDIF | Opcode::JUMPBACKWARDIFNOT => {
+            Some(JumpKind::Backward(0))
+        }
+        _ => None,
+    }
+}
+
+/// Decodes the whole program, checking that every opcode exists, every instruction is complete
+/// and every register index is below `REGISTER_COUNT`.
+fn decode(program: &[u8], diagnostics: &mut Vec<Diagnostic>) -> Vec<Decoded> {
+    let mut decoded = vec![];
+    let mut offset = 0;
+    while offset < program.len() {
+        let problem = match Instruction::decode(&program[offset..]) {
+            Some(instruction) if instruction.opcode != Opcode::ILLEGAL => {
+                let register_count = instruction
+                    .opcode
+                    .operands()
+                    .iter()
+                    .filter(|kind| {
+                        matches!(kind, OperandKind::Register | OperandKind::FloatRegister)
+                    })
+                    .count();
+                for index in &instruction.registers[..register_count] {
+                    if *index as usize >= REGISTER_COUNT {
+                        diagnostics.push(Diagnostic {
+                            offset,
+                            problem: Problem::RegisterOutOfRange { index: *index },
+                        });
+                    }
+                }
+                decoded.push(Decoded {
+                    offset,
+                    instruction,
+                });
+                offset += instruction.width();
+                continue;
+            }
+            Some(_) => Problem::IllegalOpcode {
+                byte: program[offset],
+            },
+            None => {
+                let opcode = Opcode::from(program[offset]);
+                Problem::Truncated {
+                    opcode,
+                    missing: offset + opcode.width() - program.len(),
+                }
+            }
+        };
+        diagnostics.push(Diagnostic { offset, problem });
+        break;
+    }
+    decoded
+}
+
+/// Resolves every jump whose register was set by a `LOAD` earlier in the same straight-line run
+/// of code. Knowledge of a register is dropped whenever anything else may write it, after every
+/// jump, and at every offset in `entry_points`, so targets are only reported when they are the
+/// same no matter how control got there.
+fn known_jumps(instructions: &[Decoded], entry_points: &HashSet<usize>) -> Vec<(usize, i64)> {
+    let mut jumps = vec![];
+    let mut known: [Option<i64>; 32] = [None; 32];
+    for Decoded {
+        offset,
+        instruction,
+    } in instructions
+    {
+        if entry_points.contains(offset) {
+            known = [None; 32];
+        }
+        let next = (offset + instruction.width()) as i64;
+        let register_value = |operand: usize| {
+            known
+                .get(instruction.registers[operand] as usize)
+                .copied()
+                .flatten()
+        };
+        if let Some(kind) = jump_kind(instruction.opcode) {
+            let target = match kind {
+                JumpKind::Absolute(operand) => register_value(operand),
+                JumpKind::Forward(operand) => register_value(operand).map(|jump| next + jump),
+                JumpKind::Backward(operand) => register_value(operand).map(|jump| next - jump),
+            };
+            if let Some(target) = target {
+                jumps.push((*offset, target));
+            }
+            known = [None; 32];
+            continue;
+        }
+        match instruction.opcode {
+            Opcode::LOAD => {
+                if let Some(slot) = known.get_mut(instruction.registers[0] as usize) {
+                    *slot = Some(instruction.immediate as i64);
+                }
+            }
+            Opcode::LOADRELATIVE => {
+                if let Some(slot) = known.get_mut(instruction.registers[0] as usize) {
+                    *slot = Some(next + instruction.immediate as u16 as i16 as i64);
+                }
+            }
+            Opcode::CALLNATIVE | Opcode::SYSCALL => known = [None; 32],
+            _ => {
+                let register_kinds = instruction.opcode.operands().iter().filter(|kind| {
+                    matches!(kind, OperandKind::Register | OperandKind::FloatRegister)
+                });
+                for (kind, index) in register_kinds.zip(instruction.registers) {
+                    if *kind == OperandKind::Register {
+                        if let Some(slot) = known.get_mut(index as usize) {
+                            *slot = None;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    jumps
+}
+
+/// Checks `program` without running it. An empty list of diagnostics means every instruction
+/// decodes, names real registers, and every statically known jump lands on an instruction (or
+/// exactly at the end of the program, which halts).
+pub fn verify(program: &[u8]) -> Result<(), Vec<Diagnostic>> {
+    let mut diagnostics = vec![];
+    let instructions = decode(program, &mut diagnostics);
+    let boundaries: HashSet<usize> = instructions
+        .iter()
+        .map(|decoded| decoded.offset)
+        .chain([program.len()])
+        .collect();
+
+    let first_pass = known_jumps(&instructions, &HashSet::new());
+    let entry_points = first_pass
+        .iter()
+        .filter_map(|(_, target)| usize::try_from(*target).ok())
+        .collect();
+    for (offset, target) in known_jumps(&instructions, &entry_points) {
+        let problem = match usize::try_from(target) {
+            Ok(target) if target > program.len() => Problem::JumpOutOfBounds {
+                target: target as i64,
+            },
+            Ok(target) if !boundaries.contains(&target) => Problem::JumpIntoInstruction { target },
+            Ok(_) => continue,
+            Err(_) => Problem::JumpOutOfBounds { target },
+        };
+        diagnostics.push(Diagnostic { offset, problem });
+    }
+
+    diagnostics.sort_by_key(|diagnostic| diagnostic.offset);
+    if diagnostics.is_empty() {
+        Ok(())
+    } else {
+        Err(diagnostics)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_accepts_valid_program() {
+        // LOAD $1 #8, LOAD $2 #3, DECREMENT $2, JUMPIFNOTEQUAL $2 $0 $1, HALT
+        let program = vec![1, 1, 0, 8, 1, 2, 0, 3, 32, 2, 41, 2, 0, 1, 0];
+        assert_eq!(verify(&program), Ok(()));
+    }
+
+    #[test]
+    fn test_verify_reports_decoding_problems() {
+        assert_eq!(
+            verify(&[2, 0, 32, 1, 1, 0]),
+            Err(vec![
+                Diagnostic {
+                    offset: 0,
+                    problem: Problem::RegisterOutOfRange { index: 32 }
+                },
+                Diagnostic {
+                    offset: 4,
+                    problem: Problem::Truncated {
+                        opcode: Opcode::LOAD,
+                        missing: 2
+                    }
+                },
+            ])
+        );
+        assert_eq!(
+            verify(&[0, 200]),
+            Err(vec![Diagnostic {
+                offset: 1,
+                problem: Problem::IllegalOpcode { byte: 200 }
+            }])
+        );
+    }
+
+    #[test]
+    fn test_verify_reports_bad_jumps() {
+        // LOAD $0 #2, JUMP $0 (into the LOAD's operands)
+        assert_eq!(
+            verify(&[1, 0, 0, 2, 6, 0]),
+            Err(vec![Diagnostic {
+                offset: 4,
+                problem: Problem::JumpIntoInstruction { target: 2 }
+            }])
+        );
+        // LOAD $0 #9, JUMPBACKWARD $0
+        assert_eq!(
+            verify(&[1, 0, 0, 9, 8, 0]),
+            Err(vec![Diagnostic {
+                offset: 4,
+                problem: Problem::JumpOutOfBounds { target: -3 }
+            }])
+        );
+    }
+
+    #[test]
+    fn test_verify_ignores_targets_not_statically_known() {
+        // LOAD $0 #2, INCREMENT $0, JUMP $0
+        assert_eq!(verify(&[1, 0, 0, 2, 31, 0, 6, 0]), Ok(()));
+        // LOAD $0 #3, INCREMENT $5, JUMP $0, LOAD $1 #4, JUMP $1: the second jump lands on
+        // the INCREMENT, so $0 is not known by the time the first jump runs
+        assert_eq!(verify(&[1, 0, 0, 3, 31, 5, 6, 0, 1, 1, 0, 4, 6, 1]), Ok(()));
+    }
+}
@@ -1,149 +1,862 @@
-use crate::instruction::Opcode;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::coverage::Coverage;
+use crate::debug_info::DebugInfo;
+use crate::instruction::{Instruction, Opcode};
+use crate::native::{NativeFunction, VmContext};
+use crate::profiler::Profiler;
+use crate::syscall::{SyscallError, Syscalls};
+use crate::verifier::{verify, Diagnostic};
+
+/// How `ADD`, `SUBTRACT`, `MULTIPLY` and `DIVIDE` react when a result does not fit in an `i32`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowMode {
+    /// Two's complement wrapping; the overflow flag records that it happened.
+    Wrapping,
+    /// Faults with `VmError::ArithmeticOverflow`.
+    Trapping,
+}
+
+/// Ceilings for running untrusted bytecode; `None` leaves that resource unbounded. The heap is
+/// the only memory a program can grow, so it is the only one capped.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// Instructions executed per call to `VM::run`.
+    pub max_instructions: Option<u64>,
+    pub max_heap_bytes: Option<usize>,
+    /// Wall-clock time per call to `VM::run`, checked every `TIMEOUT_CHECK_INTERVAL` instructions.
+    pub timeout: Option<Duration>,
+}
+
+/// How many integer registers the VM has, and how many float registers.
+pub const REGISTER_COUNT: usize = 32;
+
+const TIMEOUT_CHECK_INTERVAL: u64 = 1024;
+
+/// Marks byte offsets in `VM::decoded_at` where no predecoded instruction starts.
+const NOT_DECODED: u32 = u32::MAX;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VmError {
+    ArithmeticOverflow {
+        offset: usize,
+        opcode: Opcode,
+    },
+    DivisionByZero {
+        offset: usize,
+    },
+    /// A relative jump whose register holds a negative distance, or that would land before the
+    /// start of the program. `distance` is negative for a backward jump.
+    JumpOutOfRange {
+        offset: usize,
+        distance: i64,
+    },
+    IllegalOpcode {
+        offset: usize,
+        byte: u8,
+    },
+    UnknownNative {
+        offset: usize,
+        number: u16,
+    },
+    NativeFailed {
+        offset: usize,
+        number: u16,
+        message: String,
+    },
+    UnknownSyscall {
+        offset: usize,
+        number: u16,
+    },
+    SyscallDenied {
+        offset: usize,
+        number: u16,
+    },
+    MemoryOutOfBounds {
+        offset: usize,
+        address: i32,
+        length: i32,
+    },
+    InvalidAllocation {
+        offset: usize,
+        amount: i32,
+    },
+    InstructionLimitExceeded {
+        offset: usize,
+        limit: u64,
+    },
+    MemoryLimitExceeded {
+        offset: usize,
+        requested: usize,
+        limit: usize,
+    },
+    Timeout {
+        offset: usize,
+        limit: Duration,
+    },
+    TruncatedInstruction {
+        offset: usize,
+    },
+    /// A register operand with no register behind it, in a program set without `load_program`
+    RegisterOutOfRange {
+        offset: usize,
+        index: u8,
+    },
+}
+
+impl VmError {
+    /// The offset of the instruction that failed.
+    pub fn offset(&self) -> usize {
+        match self {
+            VmError::ArithmeticOverflow { offset, .. }
+            | VmError::DivisionByZero { offset }
+            | VmError::JumpOutOfRange { offset, .. }
+            | VmError::IllegalOpcode { offset, .. }
+            | VmError::UnknownNative { offset, .. }
+            | VmError::NativeFailed { offset, .. }
+            | VmError::UnknownSyscall { offset, .. }
+            | VmError::SyscallDenied { offset, .. }
+            | VmError::MemoryOutOfBounds { offset, .. }
+            | VmError::InvalidAllocation { offset, .. }
+            | VmError::InstructionLimitExceeded { offset, .. }
+            | VmError::MemoryLimitExceeded { offset, .. }
+            | VmError::Timeout { offset, .. }
+            | VmError::TruncatedInstruction { offset }
+            | VmError::RegisterOutOfRange { offset, .. } => *offset,
+        }
+    }
+
+    /// The error message, naming the failing instruction by its source location when
+    /// `debug_info` knows it and by its byte offset otherwise.
+    pub fn describe(&self, debug_info: Option<&DebugInfo>) -> String {
+        let place = debug_info
+            .and_then(|debug_info| debug_info.location(self.offset()))
+            .map_or_else(
+                || format!("byte {}", self.offset()),
+                |location| location.to_string(),
+            );
+        match self {
+            VmError::ArithmeticOverflow { opcode, .. } => {
+                format!("arithmetic overflow in {:?} at {}", opcode, place)
+            }
+            VmError::DivisionByZero { .. } => format!("division by zero at {}", place),
+            VmError::IllegalOpcode { byte, .. } => {
+                format!("illegal opcode {} at {}", byte, place)
+            }
+            VmError::JumpOutOfRange { distance, .. } => {
+                format!("relative jump by {} at {} is out of range", distance, place)
+            }
+            VmError::UnknownNative { number, .. } => {
+                format!("no native function #{} (called at {})", number, place)
+            }
+            VmError::NativeFailed {
+                number, message, ..
+            } => format!(
+                "native function #{} failed at {}: {}",
+                number, place, message
+            ),
+            VmError::UnknownSyscall { number, .. } => {
+                format!("no syscall #{} (called at {})", number, place)
+            }
+            VmError::SyscallDenied { number, .. } => {
+                format!("syscall #{} at {} is not permitted", number, place)
+            }
+            VmError::MemoryOutOfBounds {
+                address, length, ..
+            } => format!(
+                "access to {} heap bytes at address {} is out of bounds ({})",
+                length, address, place
+            ),
+            VmError::InvalidAllocation { amount, .. } => {
+                format!("cannot allocate {} bytes at {}", amount, place)
+            }
+            VmError::InstructionLimitExceeded { limit, .. } => {
+                format!("instruction limit of {} reached at {}", limit, place)
+            }
+            VmError::MemoryLimitExceeded {
+                requested, limit, ..
+            } => format!(
+                "heap of {} bytes exceeds the limit of {} at {}",
+                requested, limit, place
+            ),
+            VmError::Timeout { limit, .. } => format!("timed out after {:?} at {}", limit, place),
+            VmError::TruncatedInstruction { .. } => {
+                format!("instruction at {} runs past the end of the program", place)
+            }
+            VmError::RegisterOutOfRange { index, .. } => {
+                format!("register {} at {} does not exist", index, place)
+            }
+        }
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.describe(None))
+    }
+}
+
+impl std::error::Error for VmError {}
 
 pub struct VM {
-    pub registers: [i32; 32],
+    pub registers: [i32; REGISTER_COUNT],
+    pub float_registers: [f64; REGISTER_COUNT],
     program_counter: usize,
     pub program: Vec<u8>,
-    remainder: u32,
+    pub heap: Vec<u8>,
+    pub syscalls: Syscalls,
+    pub overflow_mode: OverflowMode,
+    pub limits: Limits,
+    /// When set, every executed instruction is counted in it.
+    pub profiler: Option<Profiler>,
+    /// When set, records which instructions ran and which way conditional jumps went.
+    pub coverage: Option<Coverage>,
+    instructions_executed: u64,
+    deadline: Option<Instant>,
+    run_time: Duration,
+    remainder: i32,
     comparison_flag: bool,
+    overflow_flag: bool,
+    natives: HashMap<u16, NativeFunction>,
+    /// Dispatch from instructions decoded once per program rather than re-reading bytes
+    /// every step. Only worth turning off to measure what it buys.
+    pub predecode: bool,
+    decoded: Vec<Instruction>,
+    /// For each byte offset of `decoded_bytes`, the index into `decoded` of the instruction
+    /// starting there, or `NOT_DECODED`.
+    decoded_at: Vec<u32>,
+    /// The prefix of `program` that `decoded` was built from, so edits can be detected.
+    decoded_bytes: Vec<u8>,
 }
 
 impl VM {
     pub fn new() -> VM {
         VM {
-            registers: [0; 32],
+            registers: [0; REGISTER_COUNT],
+            float_registers: [0.0; REGISTER_COUNT],
             program_counter: 0,
             program: vec![],
+            heap: vec![],
+            syscalls: Syscalls::new(),
+            overflow_mode: OverflowMode::Wrapping,
+            limits: Limits::default(),
+            profiler: None,
+            coverage: None,
+            instructions_executed: 0,
+            deadline: None,
+            run_time: Duration::ZERO,
             remainder: 0,
             comparison_flag: false,
+            overflow_flag: false,
+            natives: HashMap::new(),
+            predecode: true,
+            decoded: vec![],
+            decoded_at: vec![],
+            decoded_bytes: vec![],
+        }
+    }
+
+    /// Brings the predecoded instructions up to date with `program`. Appended bytes (as the REPL
+    /// produces) are decoded incrementally; any other change starts over.
+    fn refresh_decoded(&mut self) {
+        if !self.program.starts_with(&self.decoded_bytes) {
+            self.decoded.clear();
+            self.decoded_at.clear();
+            self.decoded_bytes.clear();
+        }
+        let mut offset = self.decoded_bytes.len();
+        // One naming a register that does not exist is left for `fetch` to report
+        while let Some(instruction) = Instruction::decode(&self.program[offset..])
+            .filter(|instruction| instruction.register_out_of_range().is_none())
+        {
+            self.decoded_at.resize(offset, NOT_DECODED);
+            self.decoded_at.push(self.decoded.len() as u32);
+            self.decoded.push(instruction);
+            offset += instruction.width();
+        }
+        self.decoded_at.resize(offset, NOT_DECODED);
+        let decoded_length = self.decoded_bytes.len();
+        self.decoded_bytes
+            .extend_from_slice(&self.program[decoded_length..offset]);
+    }
+
+    /// The instruction at `offset`, from the predecoded instructions when `offset` is one of
+    /// their boundaries and straight from `program` otherwise (e.g. a jump into the middle of an
+    /// instruction, or a tail that did not decode).
+    fn fetch(&self, offset: usize) -> Result<Instruction, VmError> {
+        if self.predecode {
+            if let Some(&index) = self.decoded_at.get(offset) {
+                if index != NOT_DECODED {
+                    return Ok(self.decoded[index as usize]);
+                }
+            }
+        }
+        let instruction = Instruction::decode(&self.program[offset..])
+            .ok_or(VmError::TruncatedInstruction { offset })?;
+        match instruction.register_out_of_range() {
+            Some(index) => Err(VmError::RegisterOutOfRange { offset, index }),
+            None => Ok(instruction),
         }
     }
 
-    fn decode_opcode(&mut self) -> Opcode {
-        let opcode = Opcode::from(self.program[self.program_counter]);
-        self.program_counter += 1;
-        opcode
+    fn float_arithmetic(&mut self, instruction: Instruction, operation: fn(f64, f64) -> f64) {
+        let [a, b, c] = instruction.registers.map(usize::from);
+        self.float_registers[c] = operation(self.float_registers[a], self.float_registers[b]);
     }
 
-    fn next_8_bits(&mut self) -> u8 {
-        let result = self.program[self.program_counter];
-        self.program_counter += 1;
-        result
+    fn float_comparison(&mut self, instruction: Instruction, comparison: fn(&f64, &f64) -> bool) {
+        let [a, b, _] = instruction.registers.map(usize::from);
+        self.comparison_flag = comparison(&self.float_registers[a], &self.float_registers[b]);
     }
 
-    fn next_16_bits(&mut self) -> u16 {
-        let result = ((self.program[self.program_counter] as u16) << 8)
-            | self.program[self.program_counter + 1] as u16;
-        self.program_counter += 2;
-        result
+    fn check_overflow(
+        &mut self,
+        offset: usize,
+        opcode: Opcode,
+        (result, overflowed): (i32, bool),
+    ) -> Result<i32, VmError> {
+        self.overflow_flag = overflowed;
+        if overflowed && self.overflow_mode == OverflowMode::Trapping {
+            return Err(VmError::ArithmeticOverflow { offset, opcode });
+        }
+        Ok(result)
+    }
+
+    fn arithmetic(
+        &mut self,
+        offset: usize,
+        instruction: Instruction,
+        operation: fn(i32, i32) -> (i32, bool),
+    ) -> Result<(), VmError> {
+        let [a, b, c] = instruction.registers.map(usize::from);
+        let result = self.check_overflow(
+            offset,
+            instruction.opcode,
+            operation(self.registers[a], self.registers[b]),
+        )?;
+        self.registers[c] = result;
+        Ok(())
     }
 
-    fn execute_instruction(&mut self) -> bool {
+    fn immediate_arithmetic(
+        &mut self,
+        offset: usize,
+        instruction: Instruction,
+        operation: fn(i32, i32) -> (i32, bool),
+    ) -> Result<(), VmError> {
+        let [a, b, _] = instruction.registers.map(usize::from);
+        let number = instruction.immediate as i32;
+        let result = self.check_overflow(
+            offset,
+            instruction.opcode,
+            operation(self.registers[a], number),
+        )?;
+        self.registers[b] = result;
+        Ok(())
+    }
+
+    fn step_register(
+        &mut self,
+        offset: usize,
+        instruction: Instruction,
+        step: i32,
+    ) -> Result<(), VmError> {
+        let register = instruction.registers[0] as usize;
+        let result = self.check_overflow(
+            offset,
+            instruction.opcode,
+            self.registers[register].overflowing_add(step),
+        )?;
+        self.registers[register] = result;
+        Ok(())
+    }
+
+    /// Moves the program counter by the distance in the instruction's register, forward or
+    /// backward. A negative distance is an error, as is landing before the start.
+    fn jump_relative(
+        &mut self,
+        offset: usize,
+        instruction: Instruction,
+        backward: bool,
+    ) -> Result<(), VmError> {
+        let distance = self.registers[instruction.registers[0] as usize];
+        let target = usize::try_from(distance).ok().and_then(|distance| {
+            if backward {
+                self.program_counter.checked_sub(distance)
+            } else {
+                self.program_counter.checked_add(distance)
+            }
+        });
+        self.program_counter = target.ok_or(VmError::JumpOutOfRange {
+            offset,
+            distance: if backward {
+                -(distance as i64)
+            } else {
+                distance as i64
+            },
+        })?;
+        Ok(())
+    }
+
+    fn compare_and_jump(&mut self, instruction: Instruction, comparison: fn(&i32, &i32) -> bool) {
+        let [a, b, c] = instruction.registers.map(usize::from);
+        let target = self.registers[c];
+        self.comparison_flag = comparison(&self.registers[a], &self.registers[b]);
+        if self.comparison_flag {
+            self.program_counter = target as usize;
+        }
+    }
+
+    fn heap_byte(&mut self, offset: usize, address: i32) -> Result<&mut u8, VmError> {
+        usize::try_from(address)
+            .ok()
+            .and_then(|index| self.heap.get_mut(index))
+            .ok_or(VmError::MemoryOutOfBounds {
+                offset,
+                address,
+                length: 1,
+            })
+    }
+
+    fn heap_word(&mut self, offset: usize, address: i32) -> Result<&mut [u8], VmError> {
+        usize::try_from(address)
+            .ok()
+            .and_then(|index| self.heap.get_mut(index..index.checked_add(4)?))
+            .ok_or(VmError::MemoryOutOfBounds {
+                offset,
+                address,
+                length: 4,
+            })
+    }
+
+    fn check_heap(&self, offset: usize, requested: usize) -> Result<(), VmError> {
+        match self.limits.max_heap_bytes {
+            Some(limit) if requested > limit => Err(VmError::MemoryLimitExceeded {
+                offset,
+                requested,
+                limit,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_limits(&self, offset: usize) -> Result<(), VmError> {
+        if let Some(limit) = self.limits.max_instructions {
+            if self.instructions_executed >= limit {
+                return Err(VmError::InstructionLimitExceeded { offset, limit });
+            }
+        }
+        if let (Some(limit), Some(deadline)) = (self.limits.timeout, self.deadline) {
+            if self
+                .instructions_executed
+                .is_multiple_of(TIMEOUT_CHECK_INTERVAL)
+                && Instant::now() >= deadline
+            {
+                return Err(VmError::Timeout { offset, limit });
+            }
+        }
+        Ok(())
+    }
+
+    fn execute_instruction(&mut self) -> Result<bool, VmError> {
         if self.program_counter >= self.program.len() {
-            return true;
+            return Ok(true);
         }
-        match self.decode_opcode() {
+        let offset = self.program_counter;
+        self.check_limits(offset)?;
+        let instruction = self.fetch(offset)?;
+        self.program_counter += instruction.width();
+        self.instructions_executed += 1;
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(offset, instruction.opcode);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record(offset);
+        }
+        let [a, b, c] = instruction.registers.map(usize::from);
+        let done = match instruction.opcode {
             Opcode::HALT => {
                 println!("HALT encountered!");
                 true
             }
             Opcode::LOAD => {
-                let register = self.next_8_bits() as usize;
-                let number = self.next_16_bits() as i32;
+                let register = a;
+                let number = instruction.immediate as u16 as i32;
                 self.registers[register] = number;
                 false
             }
+            Opcode::LOADRELATIVE => {
+                let distance = instruction.immediate as u16 as i16 as i32;
+                self.registers[a] = self.program_counter as i32 + distance;
+                false
+            }
             Opcode::ADD => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 + register2;
+                self.arithmetic(offset, instruction, i32::overflowing_add)?;
                 false
             }
             Opcode::SUBTRACT => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 - register2;
+                self.arithmetic(offset, instruction, i32::overflowing_sub)?;
                 false
             }
             Opcode::MULTIPLY => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 * register2;
+                self.arithmetic(offset, instruction, i32::overflowing_mul)?;
                 false
             }
             Opcode::DIVIDE => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 / register2;
-                self.remainder = (register1 % register2) as u32;
+                let register1 = self.registers[a];
+                let register2 = self.registers[b];
+                if register2 == 0 {
+                    return Err(VmError::DivisionByZero { offset });
+                }
+                let quotient = self.check_overflow(
+                    offset,
+                    Opcode::DIVIDE,
+                    register1.overflowing_div(register2),
+                )?;
+                self.registers[c] = quotient;
+                // Truncated division, so the remainder takes the sign of the dividend
+                self.remainder = register1.wrapping_rem(register2);
                 false
             }
             Opcode::JUMP => {
-                let target = self.registers[self.next_8_bits() as usize];
+                let target = self.registers[a];
                 self.program_counter = target as usize;
                 false
             }
             Opcode::JUMPFORWARD => {
-                let jump = self.registers[self.next_8_bits() as usize];
-                self.program_counter += jump as usize;
+                self.jump_relative(offset, instruction, false)?;
                 false
             }
             Opcode::JUMPBACKWARD => {
-                let jump = self.registers[self.next_8_bits() as usize];
-                self.program_counter -= jump as usize;
+                self.jump_relative(offset, instruction, true)?;
                 false
             }
             Opcode::EQUAL => {
-                self.comparison_flag = self.registers[self.next_8_bits() as usize]
-                    == self.registers[self.next_8_bits() as usize];
+                self.comparison_flag = self.registers[a] == self.registers[b];
                 false
             }
             Opcode::NOTEQUAL => {
-                self.comparison_flag = self.registers[self.next_8_bits() as usize]
-                    != self.registers[self.next_8_bits() as usize];
+                self.comparison_flag = self.registers[a] != self.registers[b];
                 false
             }
             Opcode::GREATER => {
-                self.comparison_flag = self.registers[self.next_8_bits() as usize]
-                    > self.registers[self.next_8_bits() as usize];
+                self.comparison_flag = self.registers[a] > self.registers[b];
                 false
             }
             Opcode::LESS => {
-                self.comparison_flag = self.registers[self.next_8_bits() as usize]
-                    < self.registers[self.next_8_bits() as usize];
+                self.comparison_flag = self.registers[a] < self.registers[b];
                 false
             }
             Opcode::GREATEREQUAL => {
-                self.comparison_flag = self.registers[self.next_8_bits() as usize]
-                    >= self.registers[self.next_8_bits() as usize];
+                self.comparison_flag = self.registers[a] >= self.registers[b];
                 false
             }
             Opcode::LESSEQUAL => {
-                self.comparison_flag = self.registers[self.next_8_bits() as usize]
-                    <= self.registers[self.next_8_bits() as usize];
+                self.comparison_flag = self.registers[a] <= self.registers[b];
                 false
             }
             Opcode::JUMPIF => {
-                let register = self.next_8_bits() as usize;
+                let register = a;
                 let target = self.registers[register];
                 if self.comparison_flag {
                     self.program_counter = target as usize;
                 }
                 false
             }
-            Opcode::ILLEGAL => true,
+            Opcode::LOADF64 => {
+                let register = a;
+                let number = f64::from_bits(instruction.immediate);
+                self.float_registers[register] = number;
+                false
+            }
+            Opcode::ADDF64 => {
+                self.float_arithmetic(instruction, |a, b| a + b);
+                false
+            }
+            Opcode::SUBTRACTF64 => {
+                self.float_arithmetic(instruction, |a, b| a - b);
+                false
+            }
+            Opcode::MULTIPLYF64 => {
+                self.float_arithmetic(instruction, |a, b| a * b);
+                false
+            }
+            Opcode::DIVIDEF64 => {
+                self.float_arithmetic(instruction, |a, b| a / b);
+                false
+            }
+            Opcode::EQUALF64 => {
+                self.float_comparison(instruction, f64::eq);
+                false
+            }
+            Opcode::NOTEQUALF64 => {
+                self.float_comparison(instruction, f64::ne);
+                false
+            }
+            Opcode::GREATERF64 => {
+                self.float_comparison(instruction, f64::gt);
+                false
+            }
+            Opcode::LESSF64 => {
+                self.float_comparison(instruction, f64::lt);
+                false
+            }
+            Opcode::GREATEREQUALF64 => {
+                self.float_comparison(instruction, f64::ge);
+                false
+            }
+            Opcode::LESSEQUALF64 => {
+                self.float_comparison(instruction, f64::le);
+                false
+            }
+            Opcode::INTTOFLOAT => {
+                let number = self.registers[a];
+                self.float_registers[b] = number as f64;
+                false
+            }
+            Opcode::FLOATTOINT => {
+                // Truncates toward zero, saturating at the i32 bounds (NaN becomes 0)
+                let number = self.float_registers[a];
+                self.registers[b] = number as i32;
+                false
+            }
+            Opcode::GETREMAINDER => {
+                self.registers[a] = self.remainder;
+                false
+            }
+            Opcode::MOVE => {
+                let number = self.registers[a];
+                self.registers[b] = number;
+                false
+            }
+            Opcode::INCREMENT => {
+                self.step_register(offset, instruction, 1)?;
+                false
+            }
+            Opcode::DECREMENT => {
+                self.step_register(offset, instruction, -1)?;
+                false
+            }
+            Opcode::ADDIMMEDIATE => {
+                self.immediate_arithmetic(offset, instruction, i32::overflowing_add)?;
+                false
+            }
+            Opcode::SUBTRACTIMMEDIATE => {
+                self.immediate_arithmetic(offset, instruction, i32::overflowing_sub)?;
+                false
+            }
+            Opcode::JUMPIFNOT => {
+                let register = a;
+                let target = self.registers[register];
+                if !self.comparison_flag {
+                    self.program_counter = target as usize;
+                }
+                false
+            }
+            Opcode::JUMPFORWARDIF => {
+                if self.comparison_flag {
+                    self.jump_relative(offset, instruction, false)?;
+                }
+                false
+            }
+            Opcode::JUMPFORWARDIFNOT => {
+                if !self.comparison_flag {
+                    self.jump_relative(offset, instruction, false)?;
+                }
+                false
+            }
+            Opcode::JUMPBACKWARDIF => {
+                if self.comparison_flag {
+                    self.jump_relative(offset, instruction, true)?;
+                }
+                false
+            }
+            Opcode::JUMPBACKWARDIFNOT => {
+                if !self.comparison_flag {
+                    self.jump_relative(offset, instruction, true)?;
+                }
+                false
+            }
+            Opcode::JUMPIFEQUAL => {
+                self.compare_and_jump(instruction, i32::eq);
+                false
+            }
+            Opcode::JUMPIFNOTEQUAL => {
+                self.compare_and_jump(instruction, i32::ne);
+                false
+            }
+            Opcode::JUMPIFGREATER => {
+                self.compare_and_jump(instruction, i32::gt);
+                false
+            }
+            Opcode::JUMPIFLESS => {
+                self.compare_and_jump(instruction, i32::lt);
+                false
+            }
+            Opcode::JUMPIFGREATEREQUAL => {
+                self.compare_and_jump(instruction, i32::ge);
+                false
+            }
+            Opcode::JUMPIFLESSEQUAL => {
+                self.compare_and_jump(instruction, i32::le);
+                false
+            }
+            Opcode::CALLNATIVE => {
+                let number = instruction.immediate as u16;
+                let function = self
+                    .natives
+                    .get_mut(&number)
+                    .ok_or(VmError::UnknownNative { offset, number })?;
+                let mut context = VmContext {
+                    registers: &mut self.registers,
+                    float_registers: &mut self.float_registers,
+                    heap: &mut self.heap,
+                };
+                function(&mut context).map_err(|message| VmError::NativeFailed {
+                    offset,
+                    number,
+                    message,
+                })?;
+                self.check_heap(offset, self.heap.len())?;
+                false
+            }
+            Opcode::ALLOCATE => {
+                let amount = self.registers[a];
+                if amount < 0 {
+                    return Err(VmError::InvalidAllocation { offset, amount });
+                }
+                let requested = self.heap.len() + amount as usize;
+                self.check_heap(offset, requested)?;
+                self.heap.resize(requested, 0);
+                false
+            }
+            Opcode::LOADBYTE => {
+                let address = self.registers[a];
+                let byte = self.heap_byte(offset, address)?;
+                self.registers[b] = *byte as i32;
+                false
+            }
+            Opcode::STOREBYTE => {
+                let number = self.registers[a];
+                let address = self.registers[b];
+                *self.heap_byte(offset, address)? = number as u8;
+                false
+            }
+            Opcode::SYSCALL => {
+                let number = instruction.immediate as u16;
+                let mut context = VmContext {
+                    registers: &mut self.registers,
+                    float_registers: &mut self.float_registers,
+                    heap: &mut self.heap,
+                };
+                self.syscalls
+                    .dispatch(number, &mut context)
+                    .map_err(|error| match error {
+                        SyscallError::Unknown => VmError::UnknownSyscall { offset, number },
+                        SyscallError::Denied => VmError::SyscallDenied { offset, number },
+                        SyscallError::MemoryOutOfBounds { address, length } => {
+                            VmError::MemoryOutOfBounds {
+                                offset,
+                                address,
+                                length,
+                            }
+                        }
+                    })?;
+                false
+            }
+            Opcode::LOADWORD => {
+                let address = self.registers[a];
+                let word = self.heap_word(offset, address)?;
+                self.registers[b] = i32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+                false
+            }
+            Opcode::STOREWORD => {
+                let number = self.registers[a];
+                let address = self.registers[b];
+                self.heap_word(offset, address)?
+                    .copy_from_slice(&number.to_be_bytes());
+                false
+            }
+            Opcode::ILLEGAL => {
+                return Err(VmError::IllegalOpcode {
+                    offset,
+                    byte: self.program[offset],
+                })
+            }
+        };
+        if let Some(coverage) = &mut self.coverage {
+            if instruction.opcode.is_conditional_jump() {
+                // A jump to the very next instruction is indistinguishable from falling through
+                let taken = self.program_counter != offset + instruction.width();
+                coverage.record_branch(offset, taken);
+            }
         }
+        Ok(done)
+    }
+
+    /// Makes `function` callable from programs as `CALLNATIVE #number`, replacing any
+    /// function previously registered under that number.
+    pub fn register_native<F>(&mut self, number: u16, function: F)
+    where
+        F: FnMut(&mut VmContext) -> Result<(), String> + 'static,
+    {
+        self.natives.insert(number, Box::new(function));
+    }
+
+    /// The remainder left behind by the most recent `DIVIDE`.
+    pub fn remainder(&self) -> i32 {
+        self.remainder
+    }
+
+    /// Verifies `program` and, if it is sound, replaces the current program with it and
+    /// rewinds to its start.
+    pub fn load_program(&mut self, program: Vec<u8>) -> Result<(), Vec<Diagnostic>> {
+        verify(&program)?;
+        self.program = program;
+        self.program_counter = 0;
+        Ok(())
+    }
+
+    /// Like `load_program`, but starts at `entry`, such as a linked `Executable`'s entry point.
+    pub fn load_program_at(
+        &mut self,
+        program: Vec<u8>,
+        entry: usize,
+    ) -> Result<(), Vec<Diagnostic>> {
+        self.load_program(program)?;
+        self.program_counter = entry;
+        Ok(())
     }
 
     pub fn add_byte(&mut self, byte: u8) {
         self.program.push(byte);
     }
 
-    pub fn run(&mut self) {
-        let mut done = false;
-        while !done {
-            done = self.execute_instruction();
+    /// How many instructions the most recent `run` executed.
+    pub fn instructions_executed(&self) -> u64 {
+        self.instructions_executed
+    }
+
+    /// Wall-clock time spent in the most recent `run`.
+    pub fn run_time(&self) -> Duration {
+        self.run_time
+    }
+
+    pub fn run(&mut self) -> Result<(), VmError> {
+        let started = Instant::now();
+        self.instructions_executed = 0;
+        self.deadline = self.limits.timeout.map(|timeout| started + timeout);
+        if self.predecode {
+            self.refresh_decoded();
         }
+        let mut result = Ok(false);
+        while let Ok(false) = result {
+            result = self.execute_instruction();
+        }
+        self.run_time = started.elapsed();
+        result.map(|_| ())
     }
 }
 
@@ -156,6 +869,9 @@ impl Default for VM {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::assembler::Assembler;
+    use crate::syscall::Capabilities;
+    use crate::test_support::console_vm;
 
     #[test]
     fn test_vm_creation() {
@@ -169,7 +885,7 @@ mod tests {
     fn test_opcode_halt() {
         let mut test_vm = VM::new();
         test_vm.program = vec![0, 0, 0, 0];
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.program_counter, 1);
     }
 
@@ -177,10 +893,19 @@ mod tests {
     fn test_opcode_load() {
         let mut test_vm = VM::new();
         test_vm.program = vec![1, 0, 1, 58, 0];
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[0], 314);
     }
 
+    #[test]
+    fn test_opcode_loadrelative() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![51, 0, 255, 252, 51, 1, 0, 2];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[0], 0);
+        assert_eq!(test_vm.registers[1], 10);
+    }
+
     #[test]
     fn test_opcode_add() {
         let mut test_vm = VM::new();
@@ -190,7 +915,7 @@ mod tests {
         //          1: 3_106
         //          2: 5_142 + 3_106 = 8_248
 
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[2], 8_248);
     }
 
@@ -203,7 +928,7 @@ mod tests {
         //          1: 3_106
         //          2: 5_142 - 3_106 = 2_036
 
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[2], 2_036);
     }
 
@@ -216,7 +941,7 @@ mod tests {
         //          1: 3_106
         //          2: 5_142 * 3_106 = 15_971_052
 
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[2], 15_971_052);
     }
 
@@ -229,7 +954,7 @@ mod tests {
         //          1: 3_106
         //          2: 5_142 / 3_106 = 1 remainder 2_036
 
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[2], 1);
         assert_eq!(test_vm.remainder, 2_036);
     }
@@ -239,7 +964,7 @@ mod tests {
         let mut test_vm = VM::new();
         test_vm.registers[0] = 1;
         test_vm.program = vec![6, 0, 0, 0];
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.program_counter, 2);
     }
 
@@ -251,7 +976,7 @@ mod tests {
 
         //                     ----  o-->-->  .
 
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.program_counter, 5);
     }
 
@@ -264,7 +989,7 @@ mod tests {
         //                                    <--o
         //                                    ----------     .
 
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.program_counter, 10);
         assert_eq!(test_vm.registers[5], -1);
     }
@@ -273,78 +998,78 @@ mod tests {
     fn test_opcode_equal() {
         let mut test_vm = VM::new();
         test_vm.program = vec![1, 0, 1, 0, 1, 3, 1, 0, 9, 0, 3];
-        test_vm.run();
-        assert_eq!(test_vm.comparison_flag, true);
+        test_vm.run().unwrap();
+        assert!(test_vm.comparison_flag);
         test_vm.program.extend([1, 3, 0, 1, 9, 0, 3]);
-        test_vm.run();
-        assert_eq!(test_vm.comparison_flag, false);
+        test_vm.run().unwrap();
+        assert!(!test_vm.comparison_flag);
     }
 
     #[test]
     fn test_opcode_not_equal() {
         let mut test_vm = VM::new();
         test_vm.program = vec![1, 0, 1, 0, 1, 3, 1, 0, 10, 0, 3];
-        test_vm.run();
-        assert_eq!(test_vm.comparison_flag, false);
+        test_vm.run().unwrap();
+        assert!(!test_vm.comparison_flag);
         test_vm.program.extend([1, 3, 0, 1, 10, 0, 3]);
-        test_vm.run();
-        assert_eq!(test_vm.comparison_flag, true);
+        test_vm.run().unwrap();
+        assert!(test_vm.comparison_flag);
     }
 
     #[test]
     fn test_opcode_greater() {
         let mut test_vm = VM::new();
         test_vm.program = vec![1, 20, 12, 34, 1, 22, 43, 21, 11, 20, 22];
-        test_vm.run();
-        assert_eq!(test_vm.comparison_flag, false);
+        test_vm.run().unwrap();
+        assert!(!test_vm.comparison_flag);
         test_vm.program.extend([1, 22, 12, 34, 11, 20, 22]);
-        test_vm.run();
-        assert_eq!(test_vm.comparison_flag, false);
+        test_vm.run().unwrap();
+        assert!(!test_vm.comparison_flag);
         test_vm.program.extend([1, 22, 0, 12, 11, 20, 22]);
-        test_vm.run();
-        assert_eq!(test_vm.comparison_flag, true);
+        test_vm.run().unwrap();
+        assert!(test_vm.comparison_flag);
     }
 
     #[test]
     fn test_opcode_less() {
         let mut test_vm = VM::new();
         test_vm.program = vec![1, 20, 12, 34, 1, 22, 43, 21, 12, 20, 22];
-        test_vm.run();
-        assert_eq!(test_vm.comparison_flag, true);
+        test_vm.run().unwrap();
+        assert!(test_vm.comparison_flag);
         test_vm.program.extend([1, 22, 12, 34, 12, 20, 22]);
-        test_vm.run();
-        assert_eq!(test_vm.comparison_flag, false);
+        test_vm.run().unwrap();
+        assert!(!test_vm.comparison_flag);
         test_vm.program.extend([1, 22, 0, 12, 12, 20, 22]);
-        test_vm.run();
-        assert_eq!(test_vm.comparison_flag, false);
+        test_vm.run().unwrap();
+        assert!(!test_vm.comparison_flag);
     }
 
     #[test]
     fn test_opcode_greater_equal() {
         let mut test_vm = VM::new();
         test_vm.program = vec![1, 20, 12, 34, 1, 22, 43, 21, 13, 20, 22];
-        test_vm.run();
-        assert_eq!(test_vm.comparison_flag, false);
+        test_vm.run().unwrap();
+        assert!(!test_vm.comparison_flag);
         test_vm.program.extend([1, 22, 12, 34, 13, 20, 22]);
-        test_vm.run();
-        assert_eq!(test_vm.comparison_flag, true);
+        test_vm.run().unwrap();
+        assert!(test_vm.comparison_flag);
         test_vm.program.extend([1, 22, 0, 12, 13, 20, 22]);
-        test_vm.run();
-        assert_eq!(test_vm.comparison_flag, true);
+        test_vm.run().unwrap();
+        assert!(test_vm.comparison_flag);
     }
 
     #[test]
     fn test_opcode_less_equal() {
         let mut test_vm = VM::new();
         test_vm.program = vec![1, 20, 12, 34, 1, 22, 43, 21, 14, 20, 22];
-        test_vm.run();
-        assert_eq!(test_vm.comparison_flag, true);
+        test_vm.run().unwrap();
+        assert!(test_vm.comparison_flag);
         test_vm.program.extend([1, 22, 12, 34, 14, 20, 22]);
-        test_vm.run();
-        assert_eq!(test_vm.comparison_flag, true);
+        test_vm.run().unwrap();
+        assert!(test_vm.comparison_flag);
         test_vm.program.extend([1, 22, 0, 12, 14, 20, 22]);
-        test_vm.run();
-        assert_eq!(test_vm.comparison_flag, false);
+        test_vm.run().unwrap();
+        assert!(!test_vm.comparison_flag);
     }
 
     #[test]
@@ -353,7 +1078,7 @@ mod tests {
         test_vm.registers[5] = 15;
         test_vm.program = vec![1, 31, 0, 41, 1, 15, 0, 26, 10, 31, 15, 15, 5, 0, 0, 0];
         //                     *--==--=====  *--==--=====  **--==--==  **--=        o->
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.program_counter, 16);
     }
 
@@ -361,7 +1086,599 @@ mod tests {
     fn test_opcode_illegal() {
         let mut test_vm = VM::new();
         test_vm.program = vec![123, 0, 0, 0];
-        test_vm.run();
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::IllegalOpcode {
+                offset: 0,
+                byte: 123
+            })
+        );
         assert_eq!(test_vm.program_counter, 1);
     }
+
+    #[test]
+    fn test_error_describes_source_location() {
+        let mut assembler = Assembler::new("main.iasm");
+        assembler.emit_debug_info = true;
+        let assembled = assembler
+            .assemble("load $1 #0\nload $2 #1\n    divide $2 $1 $3\n")
+            .unwrap();
+        let mut test_vm = VM::new();
+        test_vm.program = assembled.program;
+        let error = test_vm.run().unwrap_err();
+        assert_eq!(error.to_string(), "division by zero at byte 8");
+        assert_eq!(
+            error.describe(assembled.debug_info.as_ref()),
+            "division by zero at main.iasm:3:5"
+        );
+    }
+
+    #[test]
+    fn test_add_wraps_and_sets_overflow_flag() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = i32::MAX;
+        test_vm.registers[1] = 1;
+        test_vm.program = vec![2, 0, 1, 2];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2], i32::MIN);
+        assert!(test_vm.overflow_flag);
+        test_vm.program.extend([2, 1, 1, 3]);
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[3], 2);
+        assert!(!test_vm.overflow_flag);
+    }
+
+    #[test]
+    fn test_subtract_and_multiply_wrap() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = i32::MIN;
+        test_vm.registers[1] = 1;
+        test_vm.registers[2] = 65_536;
+        test_vm.program = vec![3, 0, 1, 3, 4, 2, 2, 4];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[3], i32::MAX);
+        assert_eq!(test_vm.registers[4], 0);
+        assert!(test_vm.overflow_flag);
+    }
+
+    #[test]
+    fn test_trapping_mode_faults_on_overflow() {
+        let mut test_vm = VM::new();
+        test_vm.overflow_mode = OverflowMode::Trapping;
+        test_vm.registers[0] = i32::MAX;
+        test_vm.registers[1] = 1;
+        test_vm.program = vec![2, 1, 1, 2, 2, 0, 1, 3];
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::ArithmeticOverflow {
+                offset: 4,
+                opcode: Opcode::ADD
+            })
+        );
+        assert_eq!(test_vm.registers[2], 2);
+        assert_eq!(test_vm.registers[3], 0);
+        assert!(test_vm.overflow_flag);
+    }
+
+    #[test]
+    fn test_divide_overflow_and_division_by_zero() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = i32::MIN;
+        test_vm.registers[1] = -1;
+        test_vm.program = vec![5, 0, 1, 2];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2], i32::MIN);
+        assert!(test_vm.overflow_flag);
+
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 7;
+        test_vm.program = vec![5, 0, 1, 2];
+        assert_eq!(test_vm.run(), Err(VmError::DivisionByZero { offset: 0 }));
+    }
+
+    #[test]
+    fn test_opcode_load_f64() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![16, 3];
+        test_vm.program.extend(2.5f64.to_be_bytes());
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.float_registers[3], 2.5);
+    }
+
+    #[test]
+    fn test_opcode_float_arithmetic() {
+        let mut test_vm = VM::new();
+        test_vm.float_registers[0] = 7.5;
+        test_vm.float_registers[1] = 2.5;
+        test_vm.program = vec![17, 0, 1, 2, 18, 0, 1, 3, 19, 0, 1, 4, 20, 0, 1, 5];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.float_registers[2], 10.0);
+        assert_eq!(test_vm.float_registers[3], 5.0);
+        assert_eq!(test_vm.float_registers[4], 18.75);
+        assert_eq!(test_vm.float_registers[5], 3.0);
+    }
+
+    #[test]
+    fn test_opcode_float_comparison() {
+        let mut test_vm = VM::new();
+        test_vm.float_registers[0] = 1.5;
+        test_vm.float_registers[1] = 2.5;
+        test_vm.program = vec![24, 0, 1];
+        test_vm.run().unwrap();
+        assert!(test_vm.comparison_flag);
+        test_vm.program.extend([23, 0, 1]);
+        test_vm.run().unwrap();
+        assert!(!test_vm.comparison_flag);
+        test_vm.float_registers[2] = f64::NAN;
+        test_vm.program.extend([21, 2, 2]);
+        test_vm.run().unwrap();
+        assert!(!test_vm.comparison_flag);
+    }
+
+    #[test]
+    fn test_opcode_int_float_conversion() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = -7;
+        test_vm.float_registers[1] = -3.75;
+        test_vm.float_registers[2] = 1e20;
+        test_vm.program = vec![27, 0, 0, 28, 1, 1, 28, 2, 2];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.float_registers[0], -7.0);
+        assert_eq!(test_vm.registers[1], -3);
+        assert_eq!(test_vm.registers[2], i32::MAX);
+    }
+
+    #[test]
+    fn test_opcode_get_remainder() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = -7;
+        test_vm.registers[1] = 2;
+        test_vm.program = vec![5, 0, 1, 2, 29, 3];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2], -3);
+        assert_eq!(test_vm.registers[3], -1);
+        assert_eq!(test_vm.remainder(), -1);
+
+        test_vm.registers[1] = -2;
+        test_vm.registers[0] = 7;
+        test_vm.program.extend([5, 0, 1, 2, 29, 3]);
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2], -3);
+        assert_eq!(test_vm.registers[3], 1);
+    }
+
+    #[test]
+    fn test_opcode_move() {
+        let mut test_vm = VM::new();
+        test_vm.registers[4] = -12;
+        test_vm.program = vec![30, 4, 9];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[9], -12);
+        assert_eq!(test_vm.registers[4], -12);
+    }
+
+    #[test]
+    fn test_opcode_increment_decrement() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 41;
+        test_vm.registers[1] = i32::MIN;
+        test_vm.program = vec![31, 0, 32, 1];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[0], 42);
+        assert_eq!(test_vm.registers[1], i32::MAX);
+        assert!(test_vm.overflow_flag);
+    }
+
+    #[test]
+    fn test_opcode_immediate_arithmetic() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 10;
+        test_vm.program = vec![33, 0, 1, 1, 0, 34, 0, 2, 0, 15];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[1], 266);
+        assert_eq!(test_vm.registers[2], -5);
+        assert!(!test_vm.overflow_flag);
+    }
+
+    #[test]
+    fn test_increment_traps_on_overflow() {
+        let mut test_vm = VM::new();
+        test_vm.overflow_mode = OverflowMode::Trapping;
+        test_vm.registers[0] = i32::MAX;
+        test_vm.program = vec![31, 0];
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::ArithmeticOverflow {
+                offset: 0,
+                opcode: Opcode::INCREMENT
+            })
+        );
+        assert_eq!(test_vm.registers[0], i32::MAX);
+    }
+
+    #[test]
+    fn test_opcode_jump_if_not() {
+        let mut test_vm = VM::new();
+        test_vm.registers[5] = 6;
+        test_vm.program = vec![35, 5, 0, 0, 0, 0, 0, 0];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.program_counter, 7);
+
+        let mut test_vm = VM::new();
+        test_vm.registers[5] = 6;
+        test_vm.comparison_flag = true;
+        test_vm.program = vec![35, 5, 0, 0, 0, 0, 0, 0];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.program_counter, 3);
+    }
+
+    #[test]
+    fn test_opcode_relative_conditional_jumps() {
+        let mut test_vm = VM::new();
+        test_vm.registers[3] = 2;
+        test_vm.program = vec![
+            1, 1, 0, 3, // LOAD $1 #3
+            1, 2, 0, 7, // LOAD $2 #7
+            32, 1, // DECREMENT $1
+            10, 1, 0, // NOTEQUAL $1 $0
+            38, 2, // JUMPBACKWARDIF $2 (back to the DECREMENT)
+            37, 3, // JUMPFORWARDIFNOT $3 (over the INCREMENT)
+            31, 4, // INCREMENT $4
+            0,
+        ];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[1], 0);
+        assert_eq!(test_vm.registers[4], 0);
+        assert_eq!(test_vm.program_counter, 20);
+    }
+
+    #[test]
+    fn test_relative_jump_out_of_range() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![
+            1, 1, 0, 100, // LOAD $1 #100
+            9, 2, 2, // EQUAL $2 $2
+            38, 1, // JUMPBACKWARDIF $1, to before the start
+        ];
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::JumpOutOfRange {
+                offset: 7,
+                distance: -100
+            })
+        );
+
+        let mut test_vm = VM::new();
+        test_vm.registers[3] = -4;
+        test_vm.program = vec![7, 3]; // JUMPFORWARD $3
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::JumpOutOfRange {
+                offset: 0,
+                distance: -4
+            })
+        );
+    }
+
+    #[test]
+    fn test_opcode_compare_and_jump() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![
+            1, 1, 0, 5, // LOAD $1 #5
+            1, 2, 0, 8, // LOAD $2 #8
+            31, 0, // INCREMENT $0
+            43, 0, 1, 2, // JUMPIFLESS $0 $1 $2
+            0,
+        ];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[0], 5);
+        assert!(!test_vm.comparison_flag);
+        assert_eq!(test_vm.program_counter, 15);
+    }
+
+    #[test]
+    fn test_opcode_call_native() {
+        let mut test_vm = VM::new();
+        test_vm.register_native(300, |context| {
+            context.registers[0] = context.registers[0] * 10 + context.registers[1];
+            context.float_registers[0] = 0.5;
+            Ok(())
+        });
+        test_vm.registers[0] = 4;
+        test_vm.registers[1] = 2;
+        test_vm.program = vec![46, 1, 44, 46, 1, 44];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[0], 422);
+        assert_eq!(test_vm.float_registers[0], 0.5);
+    }
+
+    #[test]
+    fn test_call_native_errors() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![1, 0, 0, 1, 46, 0, 7];
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::UnknownNative {
+                offset: 4,
+                number: 7
+            })
+        );
+
+        let mut test_vm = VM::new();
+        test_vm.register_native(7, |_| Err(String::from("out of widgets")));
+        test_vm.program = vec![46, 0, 7];
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::NativeFailed {
+                offset: 0,
+                number: 7,
+                message: String::from("out of widgets")
+            })
+        );
+    }
+
+    #[test]
+    fn test_opcode_allocate_and_bytes() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 4;
+        test_vm.registers[1] = 3;
+        test_vm.registers[2] = 300;
+        test_vm.program = vec![47, 0, 49, 2, 1, 48, 1, 3];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.heap, vec![0, 0, 0, 44]);
+        assert_eq!(test_vm.registers[3], 44);
+
+        test_vm.registers[1] = 4;
+        test_vm.program.extend([48, 1, 3]);
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::MemoryOutOfBounds {
+                offset: 8,
+                address: 4,
+                length: 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_syscall_console() {
+        let (mut test_vm, output) = console_vm(b"hello\nworld\n");
+        test_vm.heap = vec![0; 8];
+        test_vm.registers[1] = 8;
+        test_vm.program = vec![
+            50, 0, 0, // SYSCALL #0 (READLINE into address 0)
+            30, 0, 2, // MOVE $0 $2
+            1, 1, 0, 0, // LOAD $1 #0
+            1, 0, 0, 1, // LOAD $0 #1
+            50, 0, 1, // SYSCALL #1 (WRITE to the console output)
+        ];
+        test_vm.run().unwrap();
+        assert_eq!(output.contents(), b"hello");
+        assert_eq!(test_vm.registers[0], 5);
+    }
+
+    #[test]
+    fn test_syscall_read_line_in_parts() {
+        let (mut test_vm, _) = console_vm(b"too long\nend\n");
+        test_vm.heap = vec![0; 8];
+        // LOAD $0 #0, LOAD $1 #5, SYSCALL #0 (READLINE into 5 bytes at address 0)
+        let read = [1, 0, 0, 0, 1, 1, 0, 5, 50, 0, 0];
+        test_vm.program = read.to_vec();
+        test_vm.run().unwrap();
+        assert_eq!(&test_vm.heap[..5], b"too l");
+        assert_eq!(&test_vm.registers[..2], &[5, 1]);
+
+        // The rest of the line comes first, then the next one
+        test_vm.program.extend(read);
+        test_vm.run().unwrap();
+        assert_eq!(&test_vm.heap[..3], b"ong");
+        assert_eq!(&test_vm.registers[..2], &[3, 0]);
+        test_vm.program.extend(read);
+        test_vm.run().unwrap();
+        assert_eq!(&test_vm.heap[..3], b"end");
+        assert_eq!(&test_vm.registers[..2], &[3, 0]);
+    }
+
+    #[test]
+    fn test_syscall_denied_without_capability() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![50, 0, 5];
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::SyscallDenied {
+                offset: 0,
+                number: 5
+            })
+        );
+        let mut test_vm = VM::new();
+        test_vm.syscalls.capabilities.clock = true;
+        test_vm.program = vec![50, 0, 5, 50, 0, 99];
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::UnknownSyscall {
+                offset: 3,
+                number: 99
+            })
+        );
+        assert!(test_vm.registers[0] > 0);
+    }
+
+    #[test]
+    fn test_syscall_files_in_sandbox() {
+        let root = std::env::temp_dir().join(format!("esper-sandbox-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let mut test_vm = VM::new();
+        test_vm.syscalls.capabilities = Capabilities::all(root.clone());
+        test_vm.heap = b"out.txthi!".to_vec();
+        test_vm.program = vec![
+            1, 1, 0, 7, // LOAD $1 #7 (path length)
+            1, 2, 0, 1, // LOAD $2 #1 (truncate)
+            50, 0, 2, // OPEN
+            1, 1, 0, 7, // LOAD $1 #7 (address of "hi!")
+            1, 2, 0, 3, // LOAD $2 #3
+            50, 0, 1, // WRITE
+            1, 0, 0, 3, // LOAD $0 #3 (the first file descriptor)
+            50, 0, 4, // CLOSE
+        ];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[0], 0);
+        assert_eq!(std::fs::read(root.join("out.txt")).unwrap(), b"hi!");
+
+        test_vm.heap = b"../escape".to_vec();
+        test_vm.registers = [0; 32];
+        test_vm.registers[1] = 9;
+        test_vm.program.extend([50, 0, 2]);
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[0], -1);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_instruction_limit_stops_infinite_loop() {
+        let mut test_vm = VM::new();
+        test_vm.limits.max_instructions = Some(1_000);
+        test_vm.program = vec![6, 0];
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::InstructionLimitExceeded {
+                offset: 0,
+                limit: 1_000
+            })
+        );
+        assert_eq!(test_vm.instructions_executed(), 1_000);
+
+        let mut test_vm = VM::new();
+        test_vm.limits.max_instructions = Some(2);
+        test_vm.program = vec![31, 0, 31, 0];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.instructions_executed(), 2);
+    }
+
+    #[test]
+    fn test_timeout_stops_infinite_loop() {
+        let mut test_vm = VM::new();
+        test_vm.limits.timeout = Some(Duration::from_millis(20));
+        test_vm.program = vec![6, 0];
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::Timeout {
+                offset: 0,
+                limit: Duration::from_millis(20)
+            })
+        );
+        assert!(test_vm.run_time() >= Duration::from_millis(20));
+        assert!(test_vm.instructions_executed() > 0);
+    }
+
+    #[test]
+    fn test_heap_limit() {
+        let mut test_vm = VM::new();
+        test_vm.limits.max_heap_bytes = Some(16);
+        test_vm.registers[0] = 10;
+        test_vm.program = vec![47, 0, 47, 0];
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::MemoryLimitExceeded {
+                offset: 2,
+                requested: 20,
+                limit: 16
+            })
+        );
+        assert_eq!(test_vm.heap.len(), 10);
+    }
+
+    #[test]
+    fn test_load_program_verifies() {
+        let mut test_vm = VM::new();
+        assert!(test_vm.load_program(vec![1, 40, 0, 1]).is_err());
+        assert!(test_vm.program.is_empty());
+        test_vm.load_program(vec![1, 4, 0, 1]).unwrap();
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[4], 1);
+    }
+
+    #[test]
+    fn test_predecoded_program_follows_edits() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![1, 0, 0, 7];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[0], 7);
+
+        // Appended bytes, including an instruction split across two appends
+        test_vm.program.extend([1, 1]);
+        test_vm.run().unwrap_err();
+        test_vm.program.extend([0, 8, 2, 0, 1, 2]);
+        test_vm.program_counter = 4;
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2], 15);
+
+        // Replacing the program outright, with the same length as before
+        test_vm.program = vec![1, 3, 0, 9, 1, 4, 0, 1, 3, 3, 4, 5];
+        test_vm.program_counter = 0;
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[5], 8);
+    }
+
+    #[test]
+    fn test_truncated_instruction() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![31, 0, 1, 0];
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::TruncatedInstruction { offset: 2 })
+        );
+        assert_eq!(test_vm.registers[0], 1);
+    }
+
+    #[test]
+    fn test_register_out_of_range() {
+        for predecode in [true, false] {
+            let mut test_vm = VM::new();
+            test_vm.predecode = predecode;
+            // INCREMENT $0, then LOAD $40 #1
+            test_vm.program = vec![31, 0, 1, 40, 0, 1];
+            assert_eq!(
+                test_vm.run(),
+                Err(VmError::RegisterOutOfRange {
+                    offset: 2,
+                    index: 40
+                })
+            );
+            assert_eq!(test_vm.registers[0], 1);
+        }
+    }
+
+    #[test]
+    fn test_opcode_words() {
+        let mut test_vm = VM::new();
+        test_vm.heap = vec![0; 6];
+        test_vm.registers[0] = -300;
+        test_vm.registers[1] = 2;
+        test_vm.program = vec![53, 0, 1, 52, 1, 2];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.heap, vec![0, 0, 255, 255, 254, 212]);
+        assert_eq!(test_vm.registers[2], -300);
+
+        test_vm.registers[1] = 3;
+        test_vm.program.extend([52, 1, 2]);
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::MemoryOutOfBounds {
+                offset: 6,
+                address: 3,
+                length: 4
+            })
+        );
+    }
+
+    #[test]
+    fn test_jump_into_middle_of_instruction_decodes_bytes() {
+        let mut test_vm = VM::new();
+        // LOAD $2 #7938, whose immediate reads as INCREMENT $2 when jumped into, then JUMP $1
+        test_vm.registers[1] = 2;
+        test_vm.program = vec![1, 2, 31, 2, 6, 1];
+        test_vm.limits.max_instructions = Some(4);
+        test_vm.run().unwrap_err();
+        assert_eq!(test_vm.registers[2], 7939);
+    }
 }